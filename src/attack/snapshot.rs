@@ -0,0 +1,348 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::profiler::allocator::{buddyinfo, pagetypeinfo, FreeAreas};
+
+/// Zone statistics, including the free pages and watermarks of each zone
+const ZONEINFO: &str = "/proc/zoneinfo";
+
+/// The counters of one zone in `/proc/zoneinfo`, like `nr_free_pages` and the `min`, `low` and
+/// `high` watermarks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ZoneCounters {
+    pub(crate) node: u32,
+    pub(crate) zone: String,
+    pub(crate) counters: BTreeMap<String, u64>,
+}
+
+/// The state of the page allocator at one point of an attack.
+#[derive(Clone, Debug)]
+pub(crate) struct AllocatorSnapshot {
+    pub(crate) label: String,
+    pub(crate) buddyinfo: Vec<FreeAreas>,
+    /// Empty if `/proc/pagetypeinfo` couldn't be read, which needs root
+    pub(crate) pagetypeinfo: Vec<FreeAreas>,
+    pub(crate) zones: Vec<ZoneCounters>,
+}
+
+/// What changed in the page allocator between two snapshots. Only the counts which changed are
+/// listed.
+pub(crate) struct SnapshotDiff<'a> {
+    before: &'a AllocatorSnapshot,
+    after: &'a AllocatorSnapshot,
+}
+
+/// Snapshots taken during one attack, saved to a directory together with the differences between
+/// each snapshot and the one before it.
+pub(crate) struct SnapshotLog {
+    dir: PathBuf,
+    snapshots: Vec<AllocatorSnapshot>,
+}
+
+fn area_name(area: &FreeAreas) -> String {
+    match &area.migrate_type {
+        Some(migrate_type) => format!(
+            "Node {}, zone {}, type {}",
+            area.node, area.zone, migrate_type
+        ),
+        None => format!("Node {}, zone {}", area.node, area.zone),
+    }
+}
+
+/// Parses the counters of every zone in the contents of `/proc/zoneinfo`. The per-node statistics
+/// listed under the first zone of each node and the per-CPU page lists are left out.
+pub(crate) fn parse_zone_counters(zoneinfo: &str) -> Vec<ZoneCounters> {
+    let mut zones: Vec<ZoneCounters> = Vec::new();
+    let mut in_zone = false;
+    for line in zoneinfo.lines() {
+        if let Some((node, zone)) = line
+            .strip_prefix("Node")
+            .and_then(|rest| rest.split_once(", zone"))
+        {
+            if let Ok(node) = node.trim().parse() {
+                zones.push(ZoneCounters {
+                    node,
+                    zone: zone.trim().to_string(),
+                    counters: BTreeMap::new(),
+                });
+            }
+            in_zone = false;
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (key, value) = match fields[..] {
+            // The zone's own counters start with its free pages
+            ["pages", "free", value] => {
+                in_zone = true;
+                ("free", value)
+            }
+            ["pagesets"] => {
+                in_zone = false;
+                continue;
+            }
+            [key, value] if in_zone => (key, value),
+            _ => continue,
+        };
+        if let (Some(zone), Ok(value)) = (zones.last_mut(), value.parse()) {
+            zone.counters.insert(key.to_string(), value);
+        }
+    }
+    zones
+}
+
+/// Reads the counters of every zone from `/proc/zoneinfo`.
+pub(crate) fn zone_counters() -> Result<Vec<ZoneCounters>, String> {
+    fs::read_to_string(ZONEINFO)
+        .map(|zoneinfo| parse_zone_counters(&zoneinfo))
+        .map_err(|e| format!("Couldn't read {}: {}", ZONEINFO, e))
+}
+
+impl AllocatorSnapshot {
+    /// Reads the current state of the page allocator. Files which can't be read are left empty
+    /// with a warning, so a snapshot is always taken.
+    pub(crate) fn take(label: &str) -> Self {
+        fn or_warn<T>(result: Result<Vec<T>, String>) -> Vec<T> {
+            result.unwrap_or_else(|e| {
+                eprintln!("[!] {}", e);
+                Vec::new()
+            })
+        }
+        Self {
+            label: label.to_string(),
+            buddyinfo: or_warn(buddyinfo()),
+            pagetypeinfo: or_warn(pagetypeinfo()),
+            zones: or_warn(zone_counters()),
+        }
+    }
+
+    pub(crate) fn diff<'a>(&'a self, later: &'a AllocatorSnapshot) -> SnapshotDiff<'a> {
+        SnapshotDiff {
+            before: self,
+            after: later,
+        }
+    }
+}
+
+impl fmt::Display for AllocatorSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# {}", self.label)?;
+        for (title, areas) in [
+            ("buddyinfo", &self.buddyinfo),
+            ("pagetypeinfo", &self.pagetypeinfo),
+        ] {
+            writeln!(f, "\n## {} (free blocks by order)", title)?;
+            for area in areas {
+                let free = area.free.iter().map(|count| count.to_string());
+                writeln!(
+                    f,
+                    "{}: {}",
+                    area_name(area),
+                    free.collect::<Vec<_>>().join(" ")
+                )?;
+            }
+        }
+        writeln!(f, "\n## zoneinfo")?;
+        for zone in &self.zones {
+            writeln!(f, "Node {}, zone {}:", zone.node, zone.zone)?;
+            for (key, value) in &zone.counters {
+                writeln!(f, "    {} {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SnapshotDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# {} -> {}", self.before.label, self.after.label)?;
+        for (title, before, after) in [
+            ("buddyinfo", &self.before.buddyinfo, &self.after.buddyinfo),
+            (
+                "pagetypeinfo",
+                &self.before.pagetypeinfo,
+                &self.after.pagetypeinfo,
+            ),
+        ] {
+            writeln!(f, "\n## {} (change in free blocks by order)", title)?;
+            let before = before
+                .iter()
+                .map(|area| (area_name(area), area))
+                .collect::<BTreeMap<_, _>>();
+            for area in after {
+                let name = area_name(area);
+                let Some(old) = before.get(&name) else {
+                    continue;
+                };
+                let changes = area
+                    .free
+                    .iter()
+                    .zip(&old.free)
+                    .enumerate()
+                    .filter(|(_, (new, old))| new != old)
+                    .map(|(order, (new, old))| {
+                        format!("order {} {:+}", order, *new as i64 - *old as i64)
+                    })
+                    .collect::<Vec<_>>();
+                if !changes.is_empty() {
+                    writeln!(f, "{}: {}", name, changes.join(", "))?;
+                }
+            }
+        }
+        writeln!(f, "\n## zoneinfo")?;
+        for zone in &self.after.zones {
+            let Some(old) = self
+                .before
+                .zones
+                .iter()
+                .find(|old| old.node == zone.node && old.zone == zone.zone)
+            else {
+                continue;
+            };
+            let changes = zone
+                .counters
+                .iter()
+                .filter_map(|(key, new)| {
+                    let old = old.counters.get(key)?;
+                    (new != old).then(|| format!("{} {:+}", key, *new as i64 - *old as i64))
+                })
+                .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                writeln!(
+                    f,
+                    "Node {}, zone {}: {}",
+                    zone.node,
+                    zone.zone,
+                    changes.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SnapshotLog {
+    /// Starts a log saving its snapshots to `dir`, which is created if needed.
+    pub(crate) fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            snapshots: Vec::new(),
+        })
+    }
+
+    /// Takes a snapshot labelled `label` and saves it, together with the difference to the
+    /// previous snapshot, which is also printed.
+    pub(crate) fn record(&mut self, label: &str) -> io::Result<()> {
+        let snapshot = AllocatorSnapshot::take(label);
+        let name = format!("{:02}_{}", self.snapshots.len(), label.replace(' ', "_"));
+        let mut file = fs::File::create(self.dir.join(format!("{}.txt", name)))?;
+        write!(file, "{}", snapshot)?;
+        if let Some(previous) = self.snapshots.last() {
+            let diff = previous.diff(&snapshot);
+            println!("{}", diff);
+            let mut file = fs::File::create(self.dir.join(format!("{}.diff", name)))?;
+            write!(file, "{}", diff)?;
+        }
+        self.snapshots.push(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(machine: &str, file: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/proc")
+            .join(machine)
+            .join(file);
+        fs::read_to_string(path).unwrap()
+    }
+
+    /// Two nodes of a NUMA server, cut down to a few counters of each zone
+    const TWO_NODES: &str = "\
+Node 0, zone   Normal
+  per-node stats
+      nr_inactive_anon 1024
+  pages free     51200
+        min      2048
+        low      2560
+        high     3072
+        protection: (0, 0, 0, 0)
+      nr_free_pages 51200
+  pagesets
+    cpu: 0
+              count: 12
+Node 1, zone   Normal
+  per-node stats
+      nr_inactive_anon 2048
+  pages free     40960
+        min      2048
+        low      2560
+        high     3072
+        protection: (0, 0, 0, 0)
+      nr_free_pages 40960
+  pagesets
+    cpu: 0
+              count: 34
+  vm stats threshold: 12
+";
+
+    #[test]
+    fn parses_zone_counters() {
+        let vm = fixture("vm-6.18", "zoneinfo");
+        let cases = [
+            (
+                vm.as_str(),
+                vec![
+                    (0, "DMA", 3840, 42, 4095),
+                    (0, "DMA32", 763815, 8512, 1044480),
+                    (0, "Normal", 227818, 8340, 786432),
+                    (0, "Movable", 0, 32, 0),
+                    (0, "Device", 0, 0, 0),
+                ],
+            ),
+            (
+                TWO_NODES,
+                vec![(0, "Normal", 51200, 2048, 0), (1, "Normal", 40960, 2048, 0)],
+            ),
+        ];
+        for (zoneinfo, expected) in cases {
+            let zones = parse_zone_counters(zoneinfo);
+            assert_eq!(zones.len(), expected.len());
+            for (zone, (node, name, free, min, spanned)) in zones.iter().zip(expected) {
+                assert_eq!((zone.node, zone.zone.as_str()), (node, name));
+                assert_eq!(zone.counters["free"], free, "{:?}", zone);
+                assert_eq!(zone.counters["min"], min, "{:?}", zone);
+                assert_eq!(zone.counters.get("spanned").copied().unwrap_or(0), spanned);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_out_node_stats_and_pagesets() {
+        let vm = fixture("vm-6.18", "zoneinfo");
+        for zone in parse_zone_counters(&vm)
+            .iter()
+            .chain(&parse_zone_counters(TWO_NODES))
+        {
+            for key in [
+                "nr_inactive_anon",
+                "count:",
+                "protection:",
+                "cpu:",
+                "start_pfn:",
+            ] {
+                assert!(!zone.counters.contains_key(key), "{} in {:?}", key, zone);
+            }
+        }
+        let dma32 = &parse_zone_counters(&vm)[1];
+        assert_eq!(dma32.counters["nr_free_pages"], 763815);
+        assert_eq!(dma32.counters["numa_hit"], 4719616);
+    }
+}
@@ -3,7 +3,21 @@ mod attack_tester;
 mod profiler;
 mod tui;
 
+use std::process;
+
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use profiler::{
+    budget::parse_size,
+    calibrate::load_hammer_counts,
+    detect::{machine, Machine},
+    mapping::{get_mapping, preset_dimms, XorMapping},
+    numa::bind_to_node,
+    pattern::{parse_pattern, HammerPattern, Hammering},
+    utils::get_phys_memory_size,
+};
+
+/// The number of DIMMs assumed when they can't be detected.
+const DEFAULT_DIMMS: u8 = 2;
 
 #[derive(Parser)]
 struct Cli {
@@ -17,6 +31,12 @@ enum Command {
     Profile(ProfilerArgs),
     Evaluate(ProfilerArgs),
     Attack(AttackArgs),
+    /// Reverse engineers the DRAM address mapping using row buffer conflict timing
+    ReverseMap(ReverseMapArgs),
+    /// Checks the configured DRAM mapping against row buffer conflict timing
+    VerifyMapping(VerifyMappingArgs),
+    /// Measures the page policy and refresh interval and saves them as the machine profile
+    Calibrate(CalibrateArgs),
 }
 
 #[derive(Args, Debug)]
@@ -24,61 +44,317 @@ struct ProfilerArgs {
     /// How much of the physical memory that should be allocated during profiling
     #[arg(long, short = 'p', default_value_t = 0.5)]
     fraction_of_phys_memory: f64,
-    /// How many cores are on the target machine
-    #[arg(long, short, default_value_t = 4)]
-    cores: u8,
-    /// How many ram sticks on the target machine
-    #[arg(long, short, default_value_t = 2)]
-    dimms: u8,
-    /// Which northbridge your CPU has (affects the DRAM mapping)
-    #[arg(long, short, value_enum, default_value_t)]
-    bridge: Bridge,
+    /// How much memory to allocate instead of a fraction, e.g. 12G or 512M
+    #[arg(long, value_parser = parse_size, conflicts_with = "fraction_of_phys_memory")]
+    size: Option<usize>,
+    /// How many cores are on the target machine (detected if not given)
+    #[arg(long, short)]
+    cores: Option<u8>,
+    #[command(flatten)]
+    dram: DramArgs,
     /// File used to save the output
     #[arg(long, short, default_value = "flips.out")]
     output: String,
     #[arg(long, short, value_enum, default_value_t)]
     attack_method: AttackMethod,
+    /// Hammer pattern: double-sided, half-double, <N>-sided or aggressor distances with weights
+    /// like -2*8,-1,+1,+2*8, optionally followed by :interleaved, :sequential or :random
+    #[arg(long, value_parser = parse_pattern, default_value = "double-sided")]
+    pattern: HammerPattern,
+    /// Pages backing the profiled memory, huge pages give whole rows and their neighbours
+    #[arg(long, value_enum, default_value_t)]
+    backing: Backing,
+    /// NUMA node to allocate the memory from and hammer on (any if not given)
+    #[arg(long)]
+    numa_node: Option<usize>,
 }
 
 impl Default for ProfilerArgs {
     fn default() -> Self {
         Self {
             fraction_of_phys_memory: 0.5,
-            cores: 4,
-            dimms: 2,
-            bridge: Bridge::Haswell,
+            size: None,
+            cores: Some(default_cores()),
+            dram: DramArgs::default(),
             output: "flips.out".to_string(),
             attack_method: AttackMethod::RowHammer,
+            pattern: HammerPattern::default(),
+            backing: Backing::default(),
+            numa_node: None,
         }
     }
 }
 
+/// Options describing the DRAM of the target machine
+#[derive(Args, Debug)]
+struct DramArgs {
+    /// How many ram sticks on the target machine (detected if not given)
+    #[arg(long, short)]
+    dimms: Option<u8>,
+    /// Which CPU generation the target machine has, affects the DRAM mapping (detected if not
+    /// given)
+    #[arg(long, short, value_enum)]
+    bridge: Option<Bridge>,
+    /// DRAM generation of the target machine, defaults to the detected one or the one the CPU
+    /// generation uses
+    #[arg(long, value_enum)]
+    dram_type: Option<DramGeneration>,
+    /// DRAM mapping description file (e.g. from `reverse-map`), overrides `--bridge` and `--dimms`
+    #[arg(long, short)]
+    mapping: Option<String>,
+    /// Odd ranks have their address bits mirrored, as on most dual rank unbuffered DIMMs
+    #[arg(long, action)]
+    rank_mirroring: bool,
+}
+
+impl ProfilerArgs {
+    /// The number of cores given, or the detected one.
+    fn cores(&self) -> u8 {
+        self.cores.unwrap_or_else(default_cores)
+    }
+}
+
+/// Detects the machine to fill in every option, as there is no command line to give them on.
+impl Default for DramArgs {
+    fn default() -> Self {
+        let detected = Self {
+            dimms: None,
+            bridge: None,
+            dram_type: None,
+            mapping: None,
+            rank_mirroring: false,
+        };
+        let (bridge, dram_type, dimms) = detected.preset();
+        Self {
+            dimms: Some(dimms),
+            bridge: Some(bridge),
+            dram_type,
+            ..detected
+        }
+    }
+}
+
+impl DramArgs {
+    /// Loads the mapping description given with `--mapping`, or picks the mapping for the bridge
+    /// and number of DIMMs.
+    fn dram_mapping(&self) -> XorMapping {
+        let mut mapping = match &self.mapping {
+            Some(path) => {
+                let mapping = XorMapping::from_file(path).unwrap_or_else(|e| {
+                    eprintln!("[!] Invalid mapping description {}: {}", path, e);
+                    process::exit(1);
+                });
+                if let Some(Err(e)) = self.dram_type.map(|generation| generation.check(&mapping)) {
+                    eprintln!("[!] Invalid mapping description {}: {}", path, e);
+                    process::exit(1);
+                }
+                mapping
+            }
+            None => {
+                let (bridge, generation, dimms) = self.preset();
+                preset_mapping(bridge, generation, dimms)
+            }
+        };
+        mapping.rank_mirroring |= self.rank_mirroring;
+        mapping
+    }
+
+    /// The number of DIMMs given, or the detected one.
+    fn dimms(&self) -> u8 {
+        self.preset().2
+    }
+
+    /// The CPU generation given, or the detected one.
+    fn bridge(&self) -> Bridge {
+        self.preset().0
+    }
+
+    /// The CPU generation, DRAM generation and number of DIMMs to pick the preset mapping with.
+    ///
+    /// The options which aren't given are detected. If there's no preset for what was detected,
+    /// the defaults from before detection (two DIMMs in a Haswell machine) are used instead.
+    fn preset(&self) -> (Bridge, Option<DramGeneration>, u8) {
+        self.preset_for(machine())
+    }
+
+    /// Picks the preset as `preset` does, for the detected `machine`.
+    fn preset_for(&self, machine: &Machine) -> (Bridge, Option<DramGeneration>, u8) {
+        let layout = machine.dimms_per_channel();
+        let detected_dimms = layout.as_deref().map(preset_dimms);
+        let detected = (
+            self.bridge.or(machine.bridge()).unwrap_or_default(),
+            self.dram_type.or(machine.generation()),
+            self.dimms
+                .or(detected_dimms.flatten())
+                .unwrap_or(DEFAULT_DIMMS),
+        );
+        let unknown_layout = self.dimms.is_none() && detected_dimms == Some(None);
+        if !unknown_layout && get_mapping(detected.0, detected.1, detected.2).is_ok() {
+            return detected;
+        }
+
+        let fallback = (
+            self.bridge.unwrap_or_default(),
+            self.dram_type,
+            self.dimms.unwrap_or(DEFAULT_DIMMS),
+        );
+        if get_mapping(fallback.0, fallback.1, fallback.2).is_ok() {
+            let generation = detected
+                .1
+                .map_or("unknown".to_string(), |g| format!("{:?}", g));
+            let layout = layout.map_or("unknown".to_string(), |counts| format!("{:?}", counts));
+            eprintln!(
+                "[!] No mapping preset for the detected {:?} CPU with {} DIMMs ({} per channel), \
+                 using {:?} with {} DIMMs instead. Give --bridge, --dimms and --dram-type or a \
+                 mapping description with --mapping if that's wrong",
+                detected.0, generation, layout, fallback.0, fallback.2
+            );
+        }
+        fallback
+    }
+}
+
+/// The number of physical cores, or 4 if they couldn't be detected.
+fn default_cores() -> u8 {
+    machine().cores.unwrap_or(4)
+}
+
+/// The fraction of the physical memory to allocate, `size` bytes if it's given.
+fn memory_fraction(fraction_of_phys_memory: f64, size: Option<usize>) -> f64 {
+    size.map_or(fraction_of_phys_memory, |size| {
+        size as f64 / get_phys_memory_size() as f64
+    })
+}
+
+/// Binds the memory and the CPUs to NUMA `node` if it's given, exiting if that fails.
+fn bind_numa_node(node: Option<usize>) {
+    let Some(node) = node else {
+        return;
+    };
+    match bind_to_node(node) {
+        Ok(cpus) => println!("Bound to NUMA node {} (CPUs {:?})", node, cpus),
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns the preset mapping for `bridge` and `dimms`, exiting if there is none.
+fn preset_mapping(bridge: Bridge, generation: Option<DramGeneration>, dimms: u8) -> XorMapping {
+    get_mapping(bridge, generation, dimms).unwrap_or_else(|e| {
+        eprintln!(
+            "[!] {}, give a mapping description with --mapping instead",
+            e
+        );
+        process::exit(1);
+    })
+}
+
 #[derive(Args, Debug)]
 struct AttackArgs {
     #[arg(long, short = 'p', default_value_t = 0.5)]
     fraction_of_phys_memory: f64,
-    #[arg(long, short, default_value_t = 2)]
-    dimms: u8,
+    /// How much memory to allocate instead of a fraction, e.g. 12G or 512M
+    #[arg(long, value_parser = parse_size, conflicts_with = "fraction_of_phys_memory")]
+    size: Option<usize>,
+    #[command(flatten)]
+    dram: DramArgs,
     #[arg(long, short, action)]
     testing: bool,
     #[arg(long, short, default_value_t = 0)]
     number_of_dummy_pages: usize,
+    /// Hammer pattern: double-sided, half-double, <N>-sided or aggressor distances with weights
+    /// like -2*8,-1,+1,+2*8, optionally followed by :interleaved, :sequential or :random
+    #[arg(long, value_parser = parse_pattern, default_value = "double-sided")]
+    pattern: HammerPattern,
+    /// Pages backing the memory searched for the profiled pages
+    #[arg(long, value_enum, default_value_t)]
+    backing: Backing,
+    /// NUMA node to allocate the memory from and hammer on (any if not given)
+    #[arg(long)]
+    numa_node: Option<usize>,
 }
 
 impl Default for AttackArgs {
     fn default() -> Self {
         Self {
             fraction_of_phys_memory: 0.5,
-            dimms: 2,
+            size: None,
+            dram: DramArgs::default(),
             testing: false,
             number_of_dummy_pages: 0,
+            pattern: HammerPattern::default(),
+            backing: Backing::default(),
+            numa_node: None,
         }
     }
 }
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Args, Debug)]
+struct ReverseMapArgs {
+    /// How much of the physical memory that should be allocated for the measurements
+    #[arg(long, short = 'p', default_value_t = 0.5)]
+    fraction_of_phys_memory: f64,
+    /// How much memory to allocate instead of a fraction, e.g. 12G or 512M
+    #[arg(long, value_parser = parse_size, conflicts_with = "fraction_of_phys_memory")]
+    size: Option<usize>,
+    /// How many random base addresses to measure row conflicts against
+    #[arg(long, short, default_value_t = 64)]
+    samples: usize,
+    /// The maximum number of bits in a recovered XOR function
+    #[arg(long, short = 'w', default_value_t = 7)]
+    max_weight: u32,
+    /// Recover the mapping from a recorded latency dataset instead of measuring
+    #[arg(long)]
+    dataset: Option<String>,
+    /// Save the measured latencies as a dataset
+    #[arg(long)]
+    record: Option<String>,
+    /// File used to save the mapping description
+    #[arg(long, short, default_value = "mapping.txt")]
+    output: String,
+}
+
+#[derive(Args, Debug)]
+struct VerifyMappingArgs {
+    /// How much of the physical memory that should be allocated for the measurements
+    #[arg(long, short = 'p', default_value_t = 0.1)]
+    fraction_of_phys_memory: f64,
+    /// How much memory to allocate instead of a fraction, e.g. 12G or 512M
+    #[arg(long, value_parser = parse_size, conflicts_with = "fraction_of_phys_memory")]
+    size: Option<usize>,
+    #[command(flatten)]
+    dram: DramArgs,
+    /// How many page pairs to measure for each check
+    #[arg(long, short, default_value_t = 200)]
+    samples: usize,
+}
+
+#[derive(Args, Debug)]
+struct CalibrateArgs {
+    /// How much of the physical memory that should be allocated for the measurements
+    #[arg(long, short = 'p', default_value_t = 0.1)]
+    fraction_of_phys_memory: f64,
+    /// How much memory to allocate instead of a fraction, e.g. 12G or 512M
+    #[arg(long, value_parser = parse_size, conflicts_with = "fraction_of_phys_memory")]
+    size: Option<usize>,
+    #[command(flatten)]
+    dram: DramArgs,
+    /// How many page pairs to measure the page policy with
+    #[arg(long, short, default_value_t = 200)]
+    samples: usize,
+}
+
+/// The CPU generation of the target machine, which decides the memory controller's mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 enum Bridge {
-    Haswell,
     Sandy,
+    Ivy,
+    Haswell,
+    Skylake,
+    CoffeeLake,
+    /// AMD Zen 2
+    Zen,
 }
 
 impl Default for Bridge {
@@ -87,6 +363,31 @@ impl Default for Bridge {
     }
 }
 
+/// The DRAM standard of the target machine, which decides the number of banks and bank groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DramGeneration {
+    Ddr3,
+    Ddr4,
+}
+
+/// The kind of pages backing the memory allocated for profiling and attacking
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Backing {
+    /// Anonymous 4 KiB pages, wherever the kernel puts them
+    #[default]
+    #[value(name = "4k")]
+    Small,
+    /// Transparent huge pages, 2 MiB contiguous when the kernel manages to merge them
+    Thp,
+    /// 2 MiB pages from the hugetlb pool, see /proc/sys/vm/nr_hugepages
+    #[value(name = "hugetlb-2m")]
+    Hugetlb2m,
+    /// 1 GiB pages from the hugetlb pool, see
+    /// /sys/kernel/mm/hugepages/hugepages-1048576kB/nr_hugepages
+    #[value(name = "hugetlb-1g")]
+    Hugetlb1g,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum AttackMethod {
     RowHammer,
@@ -103,29 +404,149 @@ fn main() {
     let cli = Cli::parse();
     match cli.command {
         None => tui::select_command(),
-        Some(command) => match command {
-            Command::Profile(args) => {
-                profiler::rowhammer::main(
-                    args.fraction_of_phys_memory,
-                    args.cores,
-                    args.dimms,
-                    args.bridge,
-                    args.output,
-                    args.attack_method,
-                );
-            }
-            Command::Evaluate(args) => {
-                profiler::pagefinder::main(args.dimms);
+        Some(command) => {
+            if !matches!(command, Command::ReverseMap(_)) {
+                println!("Detected hardware:\n{}", machine());
             }
-            Command::Attack(args) => {
-                attack::attack::main(
-                    args.fraction_of_phys_memory,
-                    args.dimms,
-                    args.testing,
-                    args.number_of_dummy_pages,
-                );
-                //attack::degrade::main();
-            }
-        },
+            run_command(command);
+        }
+    }
+}
+
+fn run_command(command: Command) {
+    match command {
+        Command::Profile(args) => {
+            bind_numa_node(args.numa_node);
+            profiler::rowhammer::main(
+                memory_fraction(args.fraction_of_phys_memory, args.size),
+                args.cores(),
+                &args.dram.dram_mapping(),
+                args.output,
+                &Hammering {
+                    pattern: args.pattern.clone(),
+                    method: args.attack_method,
+                    counts: load_hammer_counts(),
+                },
+                args.backing,
+            );
+        }
+        Command::Evaluate(args) => {
+            bind_numa_node(args.numa_node);
+            profiler::pagefinder::main(
+                &args.dram.dram_mapping(),
+                args.backing,
+                &args.pattern,
+                load_hammer_counts().rowhammer,
+            );
+        }
+        Command::Attack(args) => {
+            bind_numa_node(args.numa_node);
+            attack::attack::main(
+                memory_fraction(args.fraction_of_phys_memory, args.size),
+                &args.dram.dram_mapping(),
+                args.testing,
+                args.number_of_dummy_pages,
+                args.backing,
+                &args.pattern,
+                load_hammer_counts().rowhammer,
+            );
+            //attack::degrade::main();
+        }
+        Command::ReverseMap(args) => {
+            profiler::reverse::main(
+                memory_fraction(args.fraction_of_phys_memory, args.size),
+                args.samples,
+                args.max_weight,
+                args.dataset,
+                args.record,
+                args.output,
+            );
+        }
+        Command::VerifyMapping(args) => {
+            profiler::verify::main(
+                memory_fraction(args.fraction_of_phys_memory, args.size),
+                &args.dram.dram_mapping(),
+                args.samples,
+            );
+        }
+        Command::Calibrate(args) => {
+            profiler::calibrate::main(
+                memory_fraction(args.fraction_of_phys_memory, args.size),
+                &args.dram.dram_mapping(),
+                args.samples,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn dram_args(bridge: Option<Bridge>, dimms: Option<u8>) -> DramArgs {
+        DramArgs {
+            dimms,
+            bridge,
+            dram_type: None,
+            mapping: None,
+            rank_mirroring: false,
+        }
+    }
+
+    #[test]
+    fn presets_of_detected_machines() {
+        let cases = [
+            (
+                "haswell-desktop",
+                (Bridge::Haswell, Some(DramGeneration::Ddr3), 2),
+            ),
+            ("zen2-desktop", (Bridge::Zen, Some(DramGeneration::Ddr4), 4)),
+            // No CPU we have a mapping for and no DIMMs, the defaults are used
+            ("sapphire-rapids-vm", (Bridge::Haswell, None, 2)),
+            ("missing", (Bridge::Haswell, None, 2)),
+            // There are no presets for DDR3 on Skylake or for four channels, so these fall back
+            // to the defaults instead of failing
+            ("skylake-laptop", (Bridge::Haswell, None, 2)),
+            ("haswell-ep-server", (Bridge::Haswell, None, 2)),
+        ];
+        for (name, preset) in cases {
+            let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/detect")
+                .join(name);
+            let machine = Machine::detect(&root);
+            let args = dram_args(None, None);
+            assert_eq!(args.preset_for(&machine), preset, "{}", name);
+            get_mapping(preset.0, preset.1, preset.2).unwrap();
+        }
+    }
+
+    #[test]
+    fn given_options_win() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/detect");
+        let haswell = Machine::detect(&root.join("haswell-desktop"));
+        assert_eq!(
+            dram_args(None, Some(1)).preset_for(&haswell),
+            (Bridge::Haswell, Some(DramGeneration::Ddr3), 1)
+        );
+        // The detected DDR3 doesn't fit Skylake, so only the given options are kept
+        assert_eq!(
+            dram_args(Some(Bridge::Skylake), None).preset_for(&haswell),
+            (Bridge::Skylake, None, 2)
+        );
+        // Nothing to fall back to, the mapping then fails with the given options
+        assert_eq!(
+            dram_args(Some(Bridge::Sandy), Some(3)).preset_for(&haswell),
+            (Bridge::Sandy, None, 3)
+        );
+
+        let laptop = Machine::detect(&root.join("skylake-laptop"));
+        let mut args = dram_args(None, None);
+        args.dram_type = Some(DramGeneration::Ddr4);
+        assert_eq!(
+            args.preset_for(&laptop),
+            (Bridge::Skylake, Some(DramGeneration::Ddr4), 1)
+        );
     }
 }
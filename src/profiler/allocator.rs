@@ -0,0 +1,555 @@
+use std::{collections::BTreeMap, fmt, fs, fs::OpenOptions, iter, process};
+
+use memmap2::{Advice, MmapMut, MmapOptions};
+use procfs::process::Process;
+
+use crate::profiler::utils::{get_page_frame_number, HUGE_PAGE_SIZE, PAGE_SIZE};
+
+/// Free blocks of each order in each zone
+const BUDDYINFO: &str = "/proc/buddyinfo";
+/// Free blocks of each order in each zone, split by migrate type
+const PAGETYPEINFO: &str = "/proc/pagetypeinfo";
+/// Zone statistics, including the first page frame number and size of each zone
+const ZONEINFO: &str = "/proc/zoneinfo";
+/// How many times new memory is mapped while looking for contiguous blocks
+const ALLOCATION_ROUNDS: usize = 16;
+/// How many times more memory than the requested blocks is mapped in each round, as only part of
+/// it ends up physically contiguous
+const OVERALLOCATION: usize = 4;
+/// Order of a transparent huge page
+const HUGE_PAGE_ORDER: u32 = 9;
+/// Splits the transparent huge pages in a range of a process, needs root and debugfs
+const SPLIT_HUGE_PAGES: &str = "/sys/kernel/debug/split_huge_pages";
+
+/// The number of free blocks of each order in one zone (and migrate type), as listed by the buddy
+/// allocator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FreeAreas {
+    pub(crate) node: u32,
+    pub(crate) zone: String,
+    /// `None` for the totals in `/proc/buddyinfo`
+    pub(crate) migrate_type: Option<String>,
+    /// The number of free blocks of `2^order` pages, indexed by order
+    pub(crate) free: Vec<u64>,
+}
+
+impl FreeAreas {
+    /// The number of blocks of `order` the free blocks of at least that order could be split into.
+    pub(crate) fn blocks_of_order(&self, order: u32) -> u64 {
+        self.free
+            .iter()
+            .enumerate()
+            .skip(order as usize)
+            .map(|(o, count)| count << (o - order as usize))
+            .sum()
+    }
+}
+
+/// The page frame numbers spanned by one zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ZoneSpan {
+    pub(crate) node: u32,
+    pub(crate) zone: String,
+    pub(crate) start_pfn: u64,
+    pub(crate) spanned: u64,
+}
+
+impl ZoneSpan {
+    pub(crate) fn contains(&self, pfn: u64) -> bool {
+        (self.start_pfn..self.start_pfn + self.spanned).contains(&pfn)
+    }
+}
+
+/// A physically contiguous block of `2^order` pages.
+#[derive(Clone, Debug)]
+pub(crate) struct Block {
+    pub(crate) virt_addr: *mut u8,
+    pub(crate) pfn: u64,
+    pub(crate) order: u32,
+    /// `None` if the page frame number isn't in any zone of `/proc/zoneinfo`
+    pub(crate) zone: Option<String>,
+}
+
+impl Block {
+    /// The (virtual address, page frame number) pairs of the pages in the block.
+    pub(crate) fn pages(&self) -> impl Iterator<Item = (*mut u8, u64)> + '_ {
+        (0..1 << self.order)
+            .map(|i| unsafe { (self.virt_addr.add(i * PAGE_SIZE), self.pfn + i as u64) })
+    }
+}
+
+/// Physically contiguous blocks, together with the mappings they are in. Pages outside the
+/// blocks have been given back to the kernel.
+pub(crate) struct ContiguousBlocks {
+    _mmaps: Vec<MmapMut>,
+    pub(crate) blocks: Vec<Block>,
+}
+
+/// Lists the blocks by zone, e.g. `12 blocks of order 4: 10 in DMA32, 2 in Normal`.
+impl fmt::Display for ContiguousBlocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut by_order: BTreeMap<u32, BTreeMap<&str, usize>> = BTreeMap::new();
+        for block in &self.blocks {
+            *by_order
+                .entry(block.order)
+                .or_default()
+                .entry(block.zone.as_deref().unwrap_or("unknown zone"))
+                .or_default() += 1;
+        }
+        if by_order.is_empty() {
+            return write!(f, "no blocks");
+        }
+        for (i, (order, zones)) in by_order.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            let count = zones.values().sum::<usize>();
+            let zones = zones
+                .iter()
+                .map(|(zone, count)| format!("{} in {}", count, zone))
+                .collect::<Vec<_>>();
+            write!(
+                f,
+                "{} blocks of order {}: {}",
+                count,
+                order,
+                zones.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the contents of `/proc/buddyinfo`, with lines like
+/// `Node 0, zone   Normal  11924   7214   2273 ...`.
+pub(crate) fn parse_buddyinfo(buddyinfo: &str) -> Vec<FreeAreas> {
+    buddyinfo
+        .lines()
+        .filter_map(|line| {
+            let (node, rest) = line.strip_prefix("Node")?.split_once(',')?;
+            let mut fields = rest.trim().strip_prefix("zone")?.split_whitespace();
+            Some(FreeAreas {
+                node: node.trim().parse().ok()?,
+                zone: fields.next()?.to_string(),
+                migrate_type: None,
+                free: fields
+                    .map(|count| count.parse().ok())
+                    .collect::<Option<_>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses the free blocks by migrate type in the contents of `/proc/pagetypeinfo`, with lines
+/// like `Node    0, zone   Normal, type    Movable  11923   7214 ...`.
+pub(crate) fn parse_pagetypeinfo(pagetypeinfo: &str) -> Vec<FreeAreas> {
+    pagetypeinfo
+        .lines()
+        .filter_map(|line| {
+            let (node, rest) = line.strip_prefix("Node")?.split_once(',')?;
+            let (zone, rest) = rest.trim().strip_prefix("zone")?.split_once(',')?;
+            let mut fields = rest.trim().strip_prefix("type")?.split_whitespace();
+            Some(FreeAreas {
+                node: node.trim().parse().ok()?,
+                zone: zone.trim().to_string(),
+                migrate_type: Some(fields.next()?.to_string()),
+                free: fields
+                    .map(|count| count.parse().ok())
+                    .collect::<Option<_>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Parses the zone spans in the contents of `/proc/zoneinfo`. Zones without pages are left out.
+pub(crate) fn parse_zone_spans(zoneinfo: &str) -> Vec<ZoneSpan> {
+    let mut spans = Vec::new();
+    let mut current: Option<(u32, String)> = None;
+    let mut spanned = None;
+    for line in zoneinfo.lines() {
+        if let Some((node, zone)) = line
+            .strip_prefix("Node")
+            .and_then(|rest| rest.split_once(", zone"))
+        {
+            current = node
+                .trim()
+                .parse()
+                .ok()
+                .map(|node| (node, zone.trim().to_string()));
+            spanned = None;
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (
+            fields.next(),
+            fields.next().and_then(|v| v.parse::<u64>().ok()),
+        ) {
+            (Some("spanned"), Some(pages)) => spanned = Some(pages),
+            (Some("start_pfn:"), Some(start_pfn)) => {
+                if let (Some((node, zone)), Some(spanned)) = (&current, spanned) {
+                    if spanned > 0 {
+                        spans.push(ZoneSpan {
+                            node: *node,
+                            zone: zone.clone(),
+                            start_pfn,
+                            spanned,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    spans
+}
+
+/// The zone `pfn` is in.
+pub(crate) fn zone_of(spans: &[ZoneSpan], pfn: u64) -> Option<&ZoneSpan> {
+    spans.iter().find(|span| span.contains(pfn))
+}
+
+/// Reads the free blocks of each zone from `/proc/buddyinfo`.
+pub(crate) fn buddyinfo() -> Result<Vec<FreeAreas>, String> {
+    fs::read_to_string(BUDDYINFO)
+        .map(|buddyinfo| parse_buddyinfo(&buddyinfo))
+        .map_err(|e| format!("Couldn't read {}: {}", BUDDYINFO, e))
+}
+
+/// Reads the free blocks of each zone and migrate type from `/proc/pagetypeinfo`, which needs
+/// root.
+pub(crate) fn pagetypeinfo() -> Result<Vec<FreeAreas>, String> {
+    fs::read_to_string(PAGETYPEINFO)
+        .map(|pagetypeinfo| parse_pagetypeinfo(&pagetypeinfo))
+        .map_err(|e| format!("Couldn't read {}: {}", PAGETYPEINFO, e))
+}
+
+/// Reads the zone spans from `/proc/zoneinfo`.
+pub(crate) fn zone_spans() -> Result<Vec<ZoneSpan>, String> {
+    fs::read_to_string(ZONEINFO)
+        .map(|zoneinfo| parse_zone_spans(&zoneinfo))
+        .map_err(|e| format!("Couldn't read {}: {}", ZONEINFO, e))
+}
+
+/// Finds the blocks of `2^order` pages in `mmap` which are physically contiguous and aligned like
+/// the buddy allocator aligns them.
+fn find_blocks(mmap: &mut MmapMut, order: u32, spans: &[ZoneSpan]) -> Result<Vec<Block>, String> {
+    let pagemap = &mut Process::myself()
+        .and_then(|process| process.pagemap())
+        .map_err(|e| format!("Couldn't open the pagemap: {}", e))?;
+    let base_ptr = mmap.as_mut_ptr();
+    let pfns = (0..mmap.len() / PAGE_SIZE)
+        .map(|i| get_page_frame_number(pagemap, unsafe { base_ptr.add(i * PAGE_SIZE) }).ok())
+        .collect::<Vec<_>>();
+
+    let block_pages = 1 << order;
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i + block_pages <= pfns.len() {
+        let contiguous = match pfns[i] {
+            Some(first) if first % block_pages as u64 == 0 => {
+                (1..block_pages).all(|j| pfns[i + j] == Some(first + j as u64))
+            }
+            _ => false,
+        };
+        if !contiguous {
+            i += 1;
+            continue;
+        }
+        let pfn = pfns[i].expect("Contiguous blocks have page frame numbers");
+        blocks.push(Block {
+            virt_addr: unsafe { base_ptr.add(i * PAGE_SIZE) },
+            pfn,
+            order,
+            zone: zone_of(spans, pfn).map(|span| span.zone.clone()),
+        });
+        i += block_pages;
+    }
+    Ok(blocks)
+}
+
+/// Gives the pages of `mmap` outside `blocks` back to the kernel.
+fn release_unused(mmap: &MmapMut, blocks: &[Block]) -> Result<(), String> {
+    let base = mmap.as_ptr() as usize;
+    let mut kept = blocks
+        .iter()
+        .map(|block| {
+            let start = block.virt_addr as usize - base;
+            start..start + (PAGE_SIZE << block.order)
+        })
+        .collect::<Vec<_>>();
+    kept.sort_by_key(|range| range.start);
+
+    let mut offset = 0;
+    for range in kept.into_iter().chain(iter::once(mmap.len()..mmap.len())) {
+        if range.start > offset {
+            mmap.advise_range(Advice::DontNeed, offset, range.start - offset)
+                .map_err(|e| format!("Couldn't release unused pages: {}", e))?;
+        }
+        offset = range.end;
+    }
+    Ok(())
+}
+
+/// Splits the transparent huge pages backing `mmap` into single pages. Pages freed from inside a
+/// huge page otherwise stay part of it until the kernel gets around to splitting it, and don't go
+/// back to the buddy allocator until then.
+fn split_huge_pages(mmap: &MmapMut) -> Result<(), String> {
+    let start = mmap.as_ptr() as usize;
+    let range = format!("{},{:#x},{:#x}", process::id(), start, start + mmap.len());
+    fs::write(SPLIT_HUGE_PAGES, range).map_err(|e| {
+        format!(
+            "Couldn't split huge pages through {}: {}",
+            SPLIT_HUGE_PAGES, e
+        )
+    })
+}
+
+/// Allocates memory until it holds `count` physically contiguous blocks of `2^order` pages, only
+/// keeping blocks in `zone` if it's given (e.g. `DMA32`). Pages which aren't part of a kept block
+/// are released right away.
+///
+/// Needs root to read page frame numbers.
+///
+/// # Returns
+/// The blocks, which may be fewer than `count` if the buddy allocator didn't have enough free
+/// blocks of the order.
+pub(crate) fn acquire_contiguous_blocks(
+    order: u32,
+    count: usize,
+    zone: Option<&str>,
+) -> Result<ContiguousBlocks, String> {
+    let spans = zone_spans()?;
+    let in_zone = |name: &str| zone.is_none_or(|zone| zone == name);
+    if let Some(zone) = zone {
+        if !spans.iter().any(|span| span.zone == zone) {
+            return Err(format!("There is no {} zone on this machine", zone));
+        }
+    }
+
+    // Anonymous memory comes from movable blocks, the other migrate types are only used if those
+    // run out
+    let free_blocks = |areas: &[FreeAreas]| {
+        areas
+            .iter()
+            .filter(|areas| in_zone(&areas.zone))
+            .filter(|areas| areas.migrate_type.as_deref().unwrap_or("Movable") == "Movable")
+            .map(|areas| areas.blocks_of_order(order))
+            .sum::<u64>()
+    };
+    let (available, kind) = match pagetypeinfo() {
+        Ok(areas) => (free_blocks(&areas), "free movable"),
+        Err(_) => (free_blocks(&buddyinfo()?), "free"),
+    };
+    let zone_suffix = zone.map_or(String::new(), |zone| format!(" in {}", zone));
+    println!(
+        "The buddy allocator has {} {} blocks of order {}{}",
+        available, kind, order, zone_suffix
+    );
+    if (available as usize) < count {
+        println!(
+            "[!] That's less than {}, the kernel has to reclaim or compact memory to give us them",
+            count
+        );
+    }
+
+    let can_split = OpenOptions::new()
+        .write(true)
+        .open(SPLIT_HUGE_PAGES)
+        .is_ok();
+    if !can_split {
+        println!(
+            "[!] Can't write {}, so no huge pages are used and fewer blocks may be found",
+            SPLIT_HUGE_PAGES
+        );
+    }
+
+    let mut mmaps = Vec::new();
+    let mut blocks = Vec::new();
+    for _ in 0..ALLOCATION_ROUNDS {
+        if blocks.len() >= count {
+            break;
+        }
+        let len = ((count - blocks.len()) * (PAGE_SIZE << order) * OVERALLOCATION)
+            .max(2 * HUGE_PAGE_SIZE);
+        let mut mmap = MmapOptions::new()
+            .len(len)
+            .map_anon()
+            .map_err(|e| format!("Couldn't map memory for blocks: {}", e))?;
+        // Single pages are handed out in no particular order if the kernel shuffles its free
+        // lists, but a transparent huge page is always an aligned block of order 9. The blocks
+        // are freed page by page later, so huge pages are only used if they can be split first.
+        if order <= HUGE_PAGE_ORDER && can_split {
+            let _ = mmap.advise(Advice::HugePage);
+        }
+        let ptr = mmap.as_mut_ptr();
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            unsafe {
+                *ptr.add(offset) = 1 + offset as u8;
+            }
+        }
+
+        let mut found = find_blocks(&mut mmap, order, &spans)?;
+        found.retain(|block| block.zone.as_deref().is_some_and(in_zone));
+        found.truncate(count - blocks.len());
+        if can_split {
+            split_huge_pages(&mmap)?;
+        }
+        release_unused(&mmap, &found)?;
+
+        blocks.extend(found);
+        mmaps.push(mmap);
+    }
+
+    if blocks.len() < count {
+        println!(
+            "[!] Only found {} of {} contiguous blocks of order {}{}",
+            blocks.len(),
+            count,
+            order,
+            zone_suffix
+        );
+    }
+
+    Ok(ContiguousBlocks {
+        _mmaps: mmaps,
+        blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fixture(machine: &str, file: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/proc")
+            .join(machine)
+            .join(file);
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn area(node: u32, zone: &str, migrate_type: Option<&str>, free: &[u64]) -> FreeAreas {
+        FreeAreas {
+            node,
+            zone: zone.to_string(),
+            migrate_type: migrate_type.map(str::to_string),
+            free: free.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_buddyinfo() {
+        let cases = [
+            (
+                fixture("vm-6.18", "buddyinfo"),
+                vec![
+                    area(0, "DMA", None, &[0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 3]),
+                    area(
+                        0,
+                        "DMA32",
+                        None,
+                        &[231, 48, 52, 40, 41, 30, 32, 30, 31, 28, 716],
+                    ),
+                    area(
+                        0,
+                        "Normal",
+                        None,
+                        &[6273, 5654, 2296, 981, 383, 148, 63, 24, 37, 47, 127],
+                    ),
+                ],
+            ),
+            (
+                // Two nodes, with a line cut short in the middle
+                "Node 0, zone   Normal      1      2      3\n\
+                 Node 1, zone   Normal      4\n\
+                 Node 1, zone   Normal      5      x\n"
+                    .to_string(),
+                vec![
+                    area(0, "Normal", None, &[1, 2, 3]),
+                    area(1, "Normal", None, &[4]),
+                ],
+            ),
+            (String::new(), vec![]),
+        ];
+        for (buddyinfo, expected) in cases {
+            assert_eq!(parse_buddyinfo(&buddyinfo), expected);
+        }
+    }
+
+    #[test]
+    fn parses_pagetypeinfo() {
+        let areas = parse_pagetypeinfo(&fixture("vm-6.18", "pagetypeinfo"));
+        // The header and the number of page blocks of each type are left out
+        assert_eq!(areas.len(), 3 * 5);
+        let cases = [
+            (
+                0,
+                area(
+                    0,
+                    "DMA",
+                    Some("Unmovable"),
+                    &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0],
+                ),
+            ),
+            (
+                6,
+                area(
+                    0,
+                    "DMA32",
+                    Some("Movable"),
+                    &[215, 32, 35, 27, 28, 26, 27, 26, 28, 27, 716],
+                ),
+            ),
+            (14, area(0, "Normal", Some("Isolate"), &[0; 11])),
+        ];
+        for (i, expected) in cases {
+            assert_eq!(areas[i], expected);
+        }
+    }
+
+    #[test]
+    fn parses_zone_spans() {
+        let spans = parse_zone_spans(&fixture("vm-6.18", "zoneinfo"));
+        // The empty Movable and Device zones are left out
+        let expected = [
+            (0, "DMA", 1, 4095),
+            (0, "DMA32", 4096, 1044480),
+            (0, "Normal", 1048576, 786432),
+        ];
+        assert_eq!(spans.len(), expected.len());
+        for (span, (node, zone, start_pfn, spanned)) in spans.iter().zip(expected) {
+            assert_eq!(
+                (span.node, span.zone.as_str(), span.start_pfn, span.spanned),
+                (node, zone, start_pfn, spanned)
+            );
+        }
+        let cases = [
+            (0, None),
+            (1, Some("DMA")),
+            (4095, Some("DMA")),
+            (4096, Some("DMA32")),
+            (1048575, Some("DMA32")),
+            (1048576, Some("Normal")),
+            (1048576 + 786432, None),
+        ];
+        for (pfn, zone) in cases {
+            let found = zone_of(&spans, pfn).map(|span| span.zone.as_str());
+            assert_eq!(found, zone, "pfn {:#x}", pfn);
+        }
+    }
+
+    #[test]
+    fn counts_blocks_of_order() {
+        let areas = parse_buddyinfo(&fixture("vm-6.18", "buddyinfo"));
+        let cases = [
+            (0, 8, 1 + 2 + 3 * 4),
+            (0, 10, 3),
+            (1, 9, 28 + 716 * 2),
+            (2, 11, 0),
+        ];
+        for (zone, order, blocks) in cases {
+            assert_eq!(areas[zone].blocks_of_order(order), blocks);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::{fs, path::Path};
+
+use procfs::process::{LimitValue, Process};
+
+use crate::Backing;
+
+/// Memory left for the rest of the system when scaling down a mapping
+const RESERVED_MEMORY: u64 = 512 << 20;
+/// Mappings smaller than this are refused instead of scaled down
+const MIN_MAPPING_SIZE: u64 = 64 << 20;
+/// Bit of `CAP_IPC_LOCK` in the capability sets, which lifts `RLIMIT_MEMLOCK`
+const CAP_IPC_LOCK: u64 = 1 << 14;
+/// Where cgroup v2 is mounted, on its own or next to cgroup v1
+const CGROUP2_ROOTS: [&str; 2] = ["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
+/// The limits on how much memory a mapping can take without getting the process (or machine)
+/// killed, where `None` means there is no limit or it couldn't be read.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MemoryBudget {
+    /// `MemAvailable` from `/proc/meminfo`, or the free huge pages for hugetlb backing
+    pub(crate) available: Option<u64>,
+    /// What's left below `memory.max` of our cgroup
+    pub(crate) cgroup_headroom: Option<u64>,
+    /// `RLIMIT_MEMLOCK`, unless we have `CAP_IPC_LOCK`
+    pub(crate) memlock: Option<u64>,
+    /// Memory to leave for the rest of the system, none for the hugetlb pool
+    pub(crate) reserve: u64,
+}
+
+impl MemoryBudget {
+    /// Reads the current limits for memory backed by `backing`.
+    pub(crate) fn read(backing: Backing) -> Self {
+        let available = if backing.is_hugetlb() {
+            free_huge_pages(backing.page_size()).map(|pages| pages * backing.page_size() as u64)
+        } else {
+            fs::read_to_string("/proc/meminfo")
+                .ok()
+                .and_then(|meminfo| parse_meminfo_field(&meminfo, "MemAvailable"))
+        };
+        // Huge pages from the hugetlb pool are charged to the hugetlb controller instead
+        let cgroup_headroom = (!backing.is_hugetlb()).then(cgroup_headroom).flatten();
+        let memlock = Process::myself()
+            .ok()
+            .filter(|process| {
+                process
+                    .status()
+                    .map_or(true, |status| status.capeff & CAP_IPC_LOCK == 0)
+            })
+            .and_then(|process| process.limits().ok())
+            .and_then(|limits| match limits.max_locked_memory.soft_limit {
+                LimitValue::Unlimited => None,
+                LimitValue::Value(bytes) => Some(bytes),
+            });
+        Self {
+            available,
+            cgroup_headroom,
+            memlock,
+            reserve: if backing.is_hugetlb() {
+                0
+            } else {
+                RESERVED_MEMORY
+            },
+        }
+    }
+
+    /// Checks a mapping of `len` bytes against the budget.
+    ///
+    /// # Returns
+    /// `len`, or a smaller size leaving some memory for the rest of the system if `len` is more
+    /// than what's available, or an error if not even a small mapping fits.
+    pub(crate) fn fit(&self, len: u64) -> Result<u64, String> {
+        let limit = match (self.available, self.cgroup_headroom) {
+            (Some(available), Some(headroom)) => available.min(headroom),
+            (limit, None) | (None, limit) => match limit {
+                Some(limit) => limit,
+                None => return Ok(len),
+            },
+        };
+        let scaled = limit.saturating_sub(self.reserve);
+        if len <= scaled {
+            return Ok(len);
+        }
+        if scaled < MIN_MAPPING_SIZE.min(len) {
+            return Err(format!(
+                "{} MiB is requested but only {} MiB is available",
+                len >> 20,
+                limit >> 20
+            ));
+        }
+        Ok(scaled)
+    }
+}
+
+/// Parses a size like `12G`, `512M`, `4096K` or `65536` (bytes), with an optional `B` or `iB`
+/// suffix, where the units are powers of 1024.
+pub(crate) fn parse_size(size: &str) -> Result<usize, String> {
+    let trimmed = size.trim();
+    let trimmed = trimmed
+        .strip_suffix("iB")
+        .or_else(|| trimmed.strip_suffix('B'))
+        .unwrap_or(trimmed);
+    let (number, shift) = match trimmed.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown unit `{}` in `{}`", unit, size)),
+            };
+            (&trimmed[..i], shift)
+        }
+        _ => (trimmed, 0),
+    };
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("invalid size `{}`, expected e.g. 12G or 512M", size))?;
+    if number <= 0.0 {
+        return Err(format!("size `{}` has to be positive", size));
+    }
+    Ok((number * (1u64 << shift) as f64) as usize)
+}
+
+/// Finds a field in the contents of `/proc/meminfo`.
+///
+/// # Returns
+/// The value in bytes, or `None` if the field is missing.
+pub(crate) fn parse_meminfo_field(meminfo: &str, field: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key != field {
+            return None;
+        }
+        let mut parts = value.split_whitespace();
+        let value = parts.next()?.parse::<u64>().ok()?;
+        match parts.next() {
+            Some("kB") => Some(value << 10),
+            _ => Some(value),
+        }
+    })
+}
+
+/// Finds the cgroup v2 path in the contents of `/proc/self/cgroup`, which is on the line
+/// starting with `0::`.
+pub(crate) fn parse_cgroup2_path(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+/// Parses the contents of a cgroup v2 `memory.max` or `memory.current` file.
+///
+/// # Returns
+/// The number of bytes, or `None` for `max` (no limit).
+pub(crate) fn parse_cgroup_memory(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+/// The memory left below the `memory.max` of the cgroup we're in, or of any of its parents.
+fn cgroup_headroom() -> Option<u64> {
+    let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = parse_cgroup2_path(&cgroup)?.trim_start_matches('/');
+    CGROUP2_ROOTS.iter().find_map(|root| {
+        let mut dir = Path::new(root).join(path);
+        let mut headroom: Option<u64> = None;
+        // The closest limit isn't necessarily the smallest one
+        while dir.starts_with(root) {
+            let read = |file: &str| fs::read_to_string(dir.join(file)).ok();
+            if let (Some(max), Some(current)) = (read("memory.max"), read("memory.current")) {
+                if let (Some(max), Some(current)) =
+                    (parse_cgroup_memory(&max), parse_cgroup_memory(&current))
+                {
+                    let left = max.saturating_sub(current);
+                    headroom = Some(headroom.map_or(left, |headroom| headroom.min(left)));
+                }
+            }
+            if !dir.pop() {
+                break;
+            }
+        }
+        headroom
+    })
+}
+
+/// The number of free pages of `page_size` in the hugetlb pool.
+fn free_huge_pages(page_size: usize) -> Option<u64> {
+    fs::read_to_string(format!(
+        "/sys/kernel/mm/hugepages/hugepages-{}kB/free_hugepages",
+        page_size >> 10
+    ))
+    .ok()?
+    .trim()
+    .parse()
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+    const GIB: u64 = 1 << 30;
+
+    fn budget(available: Option<u64>, cgroup_headroom: Option<u64>, reserve: u64) -> MemoryBudget {
+        MemoryBudget {
+            available,
+            cgroup_headroom,
+            memlock: None,
+            reserve,
+        }
+    }
+
+    #[test]
+    fn fits_mappings() {
+        let cases = [
+            // No limits
+            (budget(None, None, RESERVED_MEMORY), 64 * GIB, Ok(64 * GIB)),
+            (
+                budget(Some(8 * GIB), None, RESERVED_MEMORY),
+                4 * GIB,
+                Ok(4 * GIB),
+            ),
+            // The cgroup allows less than MemAvailable
+            (
+                budget(Some(8 * GIB), Some(2 * GIB), RESERVED_MEMORY),
+                4 * GIB,
+                Ok(2 * GIB - RESERVED_MEMORY),
+            ),
+            (budget(None, Some(2 * GIB), RESERVED_MEMORY), GIB, Ok(GIB)),
+            // Scaled down to leave the reserve
+            (
+                budget(Some(4 * GIB), None, RESERVED_MEMORY),
+                4 * GIB,
+                Ok(4 * GIB - RESERVED_MEMORY),
+            ),
+            (
+                budget(
+                    Some(RESERVED_MEMORY + MIN_MAPPING_SIZE),
+                    None,
+                    RESERVED_MEMORY,
+                ),
+                GIB,
+                Ok(MIN_MAPPING_SIZE),
+            ),
+            // Refused below the smallest mapping
+            (
+                budget(
+                    Some(RESERVED_MEMORY + MIN_MAPPING_SIZE - MIB),
+                    None,
+                    RESERVED_MEMORY,
+                ),
+                GIB,
+                Err(()),
+            ),
+            (
+                budget(Some(256 * MIB), Some(GIB), RESERVED_MEMORY),
+                GIB,
+                Err(()),
+            ),
+            // Small mappings fit as long as there's room for them
+            (
+                budget(Some(RESERVED_MEMORY + 16 * MIB), None, RESERVED_MEMORY),
+                16 * MIB,
+                Ok(16 * MIB),
+            ),
+            (
+                budget(Some(RESERVED_MEMORY + 8 * MIB), None, RESERVED_MEMORY),
+                16 * MIB,
+                Err(()),
+            ),
+            // Free huge pages of the hugetlb pool, nothing is reserved for the system
+            (budget(Some(2 * GIB), None, 0), 2 * GIB, Ok(2 * GIB)),
+            (budget(Some(GIB), None, 0), 2 * GIB, Ok(GIB)),
+            (budget(Some(0), None, 0), 2 * GIB, Err(())),
+        ];
+        for (budget, len, expected) in cases {
+            assert_eq!(
+                budget.fit(len).map_err(|_| ()),
+                expected,
+                "{:?}, {}",
+                budget,
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let cases = [
+            ("65536", Ok(65536)),
+            ("4096K", Ok(4 << 20)),
+            ("512M", Ok(512 << 20)),
+            ("512MiB", Ok(512 << 20)),
+            ("512MB", Ok(512 << 20)),
+            ("12g", Ok(12 << 30)),
+            (" 1.5G ", Ok(3 << 29)),
+            ("1T", Ok(1 << 40)),
+            ("4096B", Ok(4096)),
+        ];
+        for (size, expected) in cases {
+            assert_eq!(parse_size(size), expected, "{}", size);
+        }
+        for size in ["", "G", "12X", "-1G", "0", "twelve", "1.2.3M"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn parses_meminfo_fields() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc/vm-6.18/meminfo");
+        let meminfo = fs::read_to_string(path).unwrap();
+        let cases = [
+            ("MemTotal", Some(6147400 << 10)),
+            ("MemAvailable", Some(5608052 << 10)),
+            ("Hugepagesize", Some(2048 << 10)),
+            // Counts without a unit are kept as they are
+            ("HugePages_Total", Some(0)),
+            ("HugePages_Free", Some(0)),
+            ("MemAvail", None),
+            ("memtotal", None),
+            ("", None),
+        ];
+        for (field, expected) in cases {
+            assert_eq!(parse_meminfo_field(&meminfo, field), expected, "{}", field);
+        }
+    }
+
+    #[test]
+    fn parses_cgroup2_paths() {
+        let cases = [
+            ("0::/user.slice/user-1000.slice/session-2.scope\n", Some("/user.slice/user-1000.slice/session-2.scope")),
+            ("0::/\n", Some("/")),
+            // Hybrid hierarchy, with cgroup v1 controllers next to the unified one
+            (
+                "12:memory:/user.slice\n2:cpu,cpuacct:/\n1:name=systemd:/user.slice\n0::/user.slice\n",
+                Some("/user.slice"),
+            ),
+            // Only cgroup v1
+            ("4:memory:/docker/abc\n1:name=systemd:/docker/abc\n", None),
+            ("", None),
+        ];
+        for (cgroup, expected) in cases {
+            assert_eq!(parse_cgroup2_path(cgroup), expected, "{:?}", cgroup);
+        }
+    }
+
+    #[test]
+    fn parses_cgroup_memory() {
+        let cases = [
+            ("max\n", None),
+            ("1073741824\n", Some(1 << 30)),
+            ("0", Some(0)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_cgroup_memory(value), expected);
+        }
+    }
+}
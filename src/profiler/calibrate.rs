@@ -0,0 +1,485 @@
+use std::{fmt, fs, io, path::Path, process, str::FromStr, time::Instant};
+
+use crate::profiler::{
+    mapping::DramMapping,
+    timing::{cycles_per_ns, measure_access_time, measure_latency_trace, median},
+    utils::{
+        collect_pages_by_row, rowhammer, rowpress, setup_mapping, Page, LINES_PER_PAGE,
+        NO_OF_READS, ROWPRESS_ACTIVATIONS, ROWPRESS_ITERATIONS, ROWPRESS_READS,
+    },
+    verify::{different_rows, measure_pairs, sample_pairs},
+};
+use crate::{AttackMethod, Backing};
+
+/// File the machine profile is saved to and loaded from, in the working directory
+pub(crate) const MACHINE_PROFILE: &str = "machine.profile";
+/// Refresh interval of DDR3 and DDR4 at normal temperatures, used if it couldn't be measured
+const DEFAULT_REFRESH_INTERVAL_NS: f64 = 7800.0;
+/// Refresh commands in one refresh window, after which every row has been refreshed once
+const REFRESHES_PER_WINDOW: f64 = 8192.0;
+/// How many refresh windows each aggressor pair is hammered for
+const REFRESH_WINDOWS: f64 = 4.0;
+/// Reads of a single address used to find the refresh interval
+const TRACE_SAMPLES: usize = 200_000;
+/// Rounds of timing measurements per pair of addresses
+const ROUNDS: usize = 100;
+/// Iterations timed to find how long one hammer iteration takes
+const TIMED_ITERATIONS: usize = 100_000;
+
+/// Whether the memory controller leaves rows open after an access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PagePolicy {
+    /// Rows stay open, so accesses to the open row are fast and other rows conflict
+    Open,
+    /// Rows are closed after every access, so there are neither row hits nor conflicts
+    Closed,
+}
+
+impl fmt::Display for PagePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PagePolicy::Open => write!(f, "open"),
+            PagePolicy::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+impl FromStr for PagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(PagePolicy::Open),
+            "closed" => Ok(PagePolicy::Closed),
+            _ => Err(format!("unknown page policy `{}`", s)),
+        }
+    }
+}
+
+/// How many iterations to hammer each aggressor pair for.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HammerCounts {
+    pub(crate) rowhammer: usize,
+    pub(crate) rowpress: usize,
+}
+
+impl Default for HammerCounts {
+    fn default() -> Self {
+        Self {
+            rowhammer: NO_OF_READS,
+            rowpress: ROWPRESS_ITERATIONS,
+        }
+    }
+}
+
+impl HammerCounts {
+    /// The number of iterations for `method`.
+    pub(crate) fn iterations(&self, method: AttackMethod) -> usize {
+        match method {
+            AttackMethod::RowHammer => self.rowhammer,
+            AttackMethod::RowPress => self.rowpress,
+        }
+    }
+}
+
+/// The timing properties of the memory controller measured by the `calibrate` subcommand.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MachineProfile {
+    pub(crate) page_policy: PagePolicy,
+    /// `None` if no periodic latency spikes were found
+    pub(crate) refresh_interval_ns: Option<f64>,
+    /// Time of one `rowhammer` iteration
+    pub(crate) rowhammer_iteration_ns: f64,
+    /// Time of one `rowpress` iteration
+    pub(crate) rowpress_iteration_ns: f64,
+}
+
+impl MachineProfile {
+    /// The time between two refreshes of the same row.
+    pub(crate) fn refresh_window_ns(&self) -> f64 {
+        self.refresh_interval_ns
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL_NS)
+            * REFRESHES_PER_WINDOW
+    }
+
+    /// Derives the hammer counts from the refresh window, so that every aggressor pair is
+    /// hammered for `REFRESH_WINDOWS` windows.
+    pub(crate) fn hammer_counts(&self) -> HammerCounts {
+        let hammer_ns = self.refresh_window_ns() * REFRESH_WINDOWS;
+        HammerCounts {
+            rowhammer: (hammer_ns / self.rowhammer_iteration_ns) as usize,
+            rowpress: (hammer_ns / self.rowpress_iteration_ns) as usize,
+        }
+    }
+
+    /// Loads the machine profile from `path`.
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Writes the profile in the same `key = value` format as mapping descriptions.
+impl fmt::Display for MachineProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "page_policy = {}", self.page_policy)?;
+        if let Some(refresh_interval) = self.refresh_interval_ns {
+            writeln!(f, "refresh_interval_ns = {:.0}", refresh_interval)?;
+        }
+        writeln!(
+            f,
+            "rowhammer_iteration_ns = {:.2}",
+            self.rowhammer_iteration_ns
+        )?;
+        writeln!(
+            f,
+            "rowpress_iteration_ns = {:.2}",
+            self.rowpress_iteration_ns
+        )
+    }
+}
+
+impl FromStr for MachineProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut page_policy, mut refresh_interval_ns) = (None, None);
+        let (mut rowhammer_iteration_ns, mut rowpress_iteration_ns) = (None, None);
+        for (line_number, line) in s.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `key = value`", line_number));
+            };
+            let value = value.trim();
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| *n > 0.0)
+                    .ok_or_else(|| format!("line {}: invalid number `{}`", line_number, value))
+            };
+            match key.trim() {
+                "page_policy" => page_policy = Some(value.parse()?),
+                "refresh_interval_ns" => refresh_interval_ns = Some(number()?),
+                "rowhammer_iteration_ns" => rowhammer_iteration_ns = Some(number()?),
+                "rowpress_iteration_ns" => rowpress_iteration_ns = Some(number()?),
+                key => return Err(format!("line {}: unknown key `{}`", line_number, key)),
+            }
+        }
+        Ok(Self {
+            page_policy: page_policy.ok_or("`page_policy` is missing")?,
+            refresh_interval_ns,
+            rowhammer_iteration_ns: rowhammer_iteration_ns
+                .ok_or("`rowhammer_iteration_ns` is missing")?,
+            rowpress_iteration_ns: rowpress_iteration_ns
+                .ok_or("`rowpress_iteration_ns` is missing")?,
+        })
+    }
+}
+
+/// Loads the hammer counts from the machine profile in the working directory, falling back to
+/// the hand tuned defaults if there is none.
+pub(crate) fn load_hammer_counts() -> HammerCounts {
+    match MachineProfile::from_file(MACHINE_PROFILE) {
+        Ok(profile) => {
+            let counts = profile.hammer_counts();
+            println!(
+                "Using hammer counts from {} ({} rowhammer, {} rowpress iterations)",
+                MACHINE_PROFILE, counts.rowhammer, counts.rowpress
+            );
+            counts
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HammerCounts::default(),
+        Err(e) => {
+            eprintln!(
+                "[!] Couldn't load {}, using the default hammer counts: {}",
+                MACHINE_PROFILE, e
+            );
+            HammerCounts::default()
+        }
+    }
+}
+
+/// Decides the page policy from the median latencies of reading two addresses in the same row,
+/// in different banks, and in the same bank but different rows.
+///
+/// With an open page policy the second read of the same row hits the open row and is about as
+/// fast as reading from another bank, while a different row has to wait for the open row to be
+/// closed. With a closed page policy every read opens its row again, so reading the same row
+/// twice is as slow as reading two rows of the same bank.
+pub(crate) fn detect_page_policy(same_row: u64, different_bank: u64, same_bank: u64) -> PagePolicy {
+    if same_row.abs_diff(different_bank) < same_row.abs_diff(same_bank) {
+        PagePolicy::Open
+    } else {
+        PagePolicy::Closed
+    }
+}
+
+/// Estimates the refresh interval from a trace of (timestamp, latency) pairs of reads, as
+/// returned by `measure_latency_trace`. Reads which collide with a refresh are much slower than
+/// the others, so the interval is the typical distance between two of these latency spikes.
+///
+/// # Returns
+/// The refresh interval in cycles, or `None` if there are too few spikes.
+pub(crate) fn estimate_refresh_interval(trace: &[(u64, u64)]) -> Option<u64> {
+    let mut latencies = trace
+        .iter()
+        .map(|(_, latency)| *latency)
+        .collect::<Vec<_>>();
+    let threshold = median(&mut latencies) * 3 / 2;
+    let spikes = trace
+        .iter()
+        .filter(|(_, latency)| *latency > threshold)
+        .map(|(timestamp, _)| *timestamp)
+        .collect::<Vec<_>>();
+
+    // A refresh can slow down a couple of consecutive reads, so spikes closer than a read apart
+    // from each other are counted once
+    let mut read_time = trace
+        .windows(2)
+        .map(|w| w[1].0 - w[0].0)
+        .collect::<Vec<_>>();
+    let read_time = median(&mut read_time);
+    let mut intervals = spikes
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|interval| *interval > read_time * 2)
+        .collect::<Vec<_>>();
+    if intervals.len() < 10 {
+        return None;
+    }
+
+    // Missed spikes give multiples of the interval and interrupts give spikes in between, so we
+    // average the intervals close to the median one
+    let typical = median(&mut intervals);
+    let close = intervals
+        .iter()
+        .filter(|interval| interval.abs_diff(typical) < typical / 4)
+        .collect::<Vec<_>>();
+    if close.is_empty() {
+        return None;
+    }
+    Some(close.iter().copied().sum::<u64>() / close.len() as u64)
+}
+
+/// Times `iterations` calls of `hammer`.
+///
+/// # Returns
+/// The time per iteration in nanoseconds.
+fn time_iterations(iterations: usize, hammer: impl Fn(usize)) -> f64 {
+    let start = Instant::now();
+    hammer(iterations);
+    start.elapsed().as_nanos() as f64 / iterations as f64
+}
+
+/// Finds two cache lines in the same row and bank within one page of `pages`.
+fn find_same_row_lines(
+    pages: &[&Page],
+    mapping: &dyn DramMapping,
+) -> Option<(*const u8, *const u8)> {
+    pages.iter().take(1000).find_map(|page| {
+        (1..LINES_PER_PAGE).find_map(|line| {
+            let (a, b) = (
+                page.line_dram_address(0, mapping),
+                page.line_dram_address(line, mapping),
+            );
+            (a.bank_string() == b.bank_string() && a.row == b.row).then(|| {
+                (
+                    page.line_addr(0) as *const u8,
+                    page.line_addr(line) as *const u8,
+                )
+            })
+        })
+    })
+}
+
+/// Measures the page policy, the refresh interval and how long hammering takes, and saves them
+/// as the machine profile.
+pub(crate) fn main(fraction_of_phys_memory: f64, mapping: &dyn DramMapping, samples: usize) {
+    println!("Setting up memory mapping...");
+    let mut mmap = setup_mapping(fraction_of_phys_memory, Backing::Small).unwrap_or_else(|e| {
+        eprintln!("[!] {}", e);
+        process::exit(1);
+    });
+
+    println!("Collecting all pages in all rows...");
+    let index =
+        collect_pages_by_row(&mut mmap, mapping, Backing::Small).expect("Couldn't collect pages");
+    let pages = index.pages().collect::<Vec<_>>();
+    if pages.len() < 2 {
+        eprintln!(
+            "[!] Can't calibrate - only got {} pages. Make sure you're running as sudo!",
+            pages.len()
+        );
+        return;
+    }
+
+    println!("Measuring the page policy...");
+    let Some((row_a, row_b)) = find_same_row_lines(&pages, mapping) else {
+        eprintln!("[!] Couldn't find two cache lines in the same row");
+        return;
+    };
+    let mut same_row = (0..samples)
+        .map(|_| measure_access_time(row_a, row_b, ROUNDS))
+        .collect::<Vec<_>>();
+    let same_bank_pairs = sample_pairs(&pages, samples, |a, b| {
+        a.bank_index(mapping) == b.bank_index(mapping) && different_rows(mapping, a, b)
+    });
+    let different_bank_pairs = sample_pairs(&pages, samples, |a, b| {
+        a.bank_index(mapping) != b.bank_index(mapping)
+    });
+    if same_bank_pairs.is_empty() || different_bank_pairs.is_empty() {
+        eprintln!("[!] Couldn't find pages in the same and in different banks");
+        return;
+    }
+    let (same_row, different_bank, same_bank) = (
+        median(&mut same_row),
+        median(&mut measure_pairs(&different_bank_pairs)),
+        median(&mut measure_pairs(&same_bank_pairs)),
+    );
+    let page_policy = detect_page_policy(same_row, different_bank, same_bank);
+    println!(
+        "Same row: {} cycles, different banks: {} cycles, same bank: {} cycles",
+        same_row, different_bank, same_bank
+    );
+    println!("Page policy: {}\n", page_policy);
+
+    println!("Measuring the refresh interval...");
+    let cycles_per_ns = cycles_per_ns();
+    let trace = measure_latency_trace(pages[0].virt_addr, TRACE_SAMPLES);
+    let refresh_interval_ns =
+        estimate_refresh_interval(&trace).map(|cycles| cycles as f64 / cycles_per_ns);
+    match refresh_interval_ns {
+        Some(interval) => println!("Refresh interval (tREFI): {:.0} ns\n", interval),
+        None => println!(
+            "[!] Couldn't find periodic latency spikes, assuming the standard {} ns\n",
+            DEFAULT_REFRESH_INTERVAL_NS
+        ),
+    }
+
+    println!("Timing hammer iterations...");
+    // Hammer a pair of rows in the same bank, as the profiler does
+    let (a, b) = (
+        same_bank_pairs[0].0.virt_addr,
+        same_bank_pairs[0].1.virt_addr,
+    );
+    let profile = MachineProfile {
+        page_policy,
+        refresh_interval_ns,
+        rowhammer_iteration_ns: time_iterations(TIMED_ITERATIONS, |iters| rowhammer(a, b, iters)),
+        rowpress_iteration_ns: time_iterations(TIMED_ITERATIONS / 100, |iters| {
+            rowpress(a, b, iters, ROWPRESS_ACTIVATIONS, ROWPRESS_READS)
+        }),
+    };
+    let counts = profile.hammer_counts();
+    println!(
+        "Hammer counts for {} refresh windows of {:.1} ms: {} rowhammer, {} rowpress iterations",
+        REFRESH_WINDOWS,
+        profile.refresh_window_ns() / 1e6,
+        counts.rowhammer,
+        counts.rowpress
+    );
+
+    let description = format!("# Measured by calibrate\n{}", profile);
+    fs::write(MACHINE_PROFILE, description).expect("Couldn't write the machine profile");
+    println!("Machine profile written to {}", MACHINE_PROFILE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trace of reads `read_time` cycles apart, where every `spike_every`th read is slow.
+    fn trace(reads: u64, read_time: u64, spike_every: u64) -> Vec<(u64, u64)> {
+        (0..reads)
+            .map(|i| {
+                let latency = if i % spike_every == 0 { 400 } else { 100 };
+                (i * read_time, latency)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn refresh_interval_is_the_distance_between_spikes() {
+        assert_eq!(
+            estimate_refresh_interval(&trace(20_000, 10, 780)),
+            Some(7800)
+        );
+    }
+
+    #[test]
+    fn refresh_interval_ignores_missed_and_extra_spikes() {
+        let mut trace = trace(20_000, 10, 780);
+        // A missed refresh and an interrupt in between two refreshes
+        trace[780 * 5].1 = 100;
+        trace[780 * 9 + 300].1 = 400;
+        assert_eq!(estimate_refresh_interval(&trace), Some(7800));
+    }
+
+    #[test]
+    fn refresh_interval_needs_enough_spikes() {
+        assert_eq!(estimate_refresh_interval(&trace(5000, 10, 780)), None);
+        assert_eq!(estimate_refresh_interval(&[]), None);
+    }
+
+    #[test]
+    fn refresh_interval_of_tiny_intervals_is_none() {
+        // The typical interval is 3 cycles, so no interval is within a quarter of it
+        assert_eq!(estimate_refresh_interval(&trace(1000, 1, 3)), None);
+    }
+
+    #[test]
+    fn page_policy_from_latencies() {
+        assert_eq!(detect_page_policy(150, 160, 300), PagePolicy::Open);
+        assert_eq!(detect_page_policy(300, 160, 310), PagePolicy::Closed);
+        assert_eq!(detect_page_policy(230, 160, 300), PagePolicy::Closed);
+    }
+
+    #[test]
+    fn machine_profile_round_trips() {
+        let profile = MachineProfile {
+            page_policy: PagePolicy::Open,
+            refresh_interval_ns: Some(7800.0),
+            rowhammer_iteration_ns: 52.5,
+            rowpress_iteration_ns: 1210.25,
+        };
+        assert_eq!(profile.to_string().parse::<MachineProfile>(), Ok(profile));
+    }
+
+    #[test]
+    fn machine_profile_without_refresh_interval() {
+        let profile =
+            "# measured by calibrate\n\npage_policy = closed\nrowhammer_iteration_ns = 50\n\
+                       rowpress_iteration_ns = 1000\n"
+                .parse::<MachineProfile>()
+                .unwrap();
+        assert_eq!(profile.page_policy, PagePolicy::Closed);
+        assert_eq!(profile.refresh_interval_ns, None);
+        assert_eq!(
+            profile.refresh_window_ns(),
+            DEFAULT_REFRESH_INTERVAL_NS * REFRESHES_PER_WINDOW
+        );
+    }
+
+    #[test]
+    fn invalid_machine_profiles() {
+        for (profile, error) in [
+            (
+                "page_policy = open\nrowpress_iteration_ns = 1",
+                "`rowhammer_iteration_ns` is missing",
+            ),
+            ("page_policy = ajar", "unknown page policy `ajar`"),
+            ("page_policy open", "line 1: expected `key = value`"),
+            (
+                "page_policy = open\nrowhammer_iteration_ns = -1",
+                "line 2: invalid number `-1`",
+            ),
+            ("refresh = 7800", "line 1: unknown key `refresh`"),
+        ] {
+            assert_eq!(profile.parse::<MachineProfile>(), Err(error.to_string()));
+        }
+    }
+}
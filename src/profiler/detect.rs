@@ -0,0 +1,536 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+    sync::OnceLock,
+};
+
+use crate::{Bridge, DramGeneration};
+
+/// SMBIOS structure type describing one memory device (DIMM slot).
+const SMBIOS_MEMORY_DEVICE: u8 = 17;
+/// SMBIOS structure type marking the end of the table.
+const SMBIOS_END_OF_TABLE: u8 = 127;
+/// SMBIOS memory types of the DRAM generations we have mappings for.
+const SMBIOS_DDR3: u8 = 0x18;
+const SMBIOS_DDR4: u8 = 0x1a;
+
+/// The CPU model as reported by `/proc/cpuinfo`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CpuInfo {
+    pub(crate) vendor: String,
+    pub(crate) family: u32,
+    pub(crate) model: u32,
+    pub(crate) model_name: String,
+}
+
+impl CpuInfo {
+    /// The CPU generation with the same memory controller as this CPU, if we have a mapping for
+    /// it.
+    pub(crate) fn bridge(&self) -> Option<Bridge> {
+        match (self.vendor.as_str(), self.family, self.model) {
+            ("GenuineIntel", 6, 0x2a | 0x2d) => Some(Bridge::Sandy),
+            ("GenuineIntel", 6, 0x3a | 0x3e) => Some(Bridge::Ivy),
+            ("GenuineIntel", 6, 0x3c | 0x3f | 0x45 | 0x46) => Some(Bridge::Haswell),
+            ("GenuineIntel", 6, 0x4e | 0x55 | 0x5e) => Some(Bridge::Skylake),
+            // Kaby Lake, Coffee Lake and Comet Lake
+            ("GenuineIntel", 6, 0x8e | 0x9e | 0xa5 | 0xa6) => Some(Bridge::CoffeeLake),
+            // Zen, Zen+ and Zen 2
+            ("AuthenticAMD", 0x17, _) => Some(Bridge::Zen),
+            _ => None,
+        }
+    }
+}
+
+/// One populated DIMM slot from the SMBIOS tables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MemoryDevice {
+    pub(crate) size_mib: u64,
+    /// `None` if the DIMM is neither DDR3 nor DDR4
+    pub(crate) generation: Option<DramGeneration>,
+    /// Where the slot is, e.g. `ChannelA-DIMM0` or `DIMM_B1`
+    pub(crate) locator: String,
+    /// The memory channel of the slot, `None` if the locators don't name it
+    pub(crate) channel: Option<String>,
+}
+
+/// Everything detected about the machine, where `None` means it couldn't be detected.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Machine {
+    pub(crate) cpu: Option<CpuInfo>,
+    pub(crate) cores: Option<u8>,
+    pub(crate) dimms: Option<Vec<MemoryDevice>>,
+}
+
+impl Machine {
+    /// Detects the machine from the files below `root`, which is `/` except when reading
+    /// captured files.
+    pub(crate) fn detect(root: &Path) -> Self {
+        let cpu = fs::read_to_string(root.join("proc/cpuinfo"))
+            .ok()
+            .and_then(|cpuinfo| parse_cpuinfo(&cpuinfo));
+        let cores = count_cores(&root.join("sys/devices/system/cpu"));
+        let dimms = fs::read(root.join("sys/firmware/dmi/tables/DMI"))
+            .ok()
+            .map(|table| parse_memory_devices(&table))
+            .filter(|dimms| !dimms.is_empty());
+        Self { cpu, cores, dimms }
+    }
+
+    pub(crate) fn bridge(&self) -> Option<Bridge> {
+        self.cpu.as_ref()?.bridge()
+    }
+
+    /// The number of DIMMs in each memory channel, fewest first, if every DIMM's channel is known.
+    pub(crate) fn dimms_per_channel(&self) -> Option<Vec<u8>> {
+        let mut channels = HashMap::new();
+        for dimm in self.dimms.as_ref()? {
+            *channels.entry(dimm.channel.as_deref()?).or_insert(0) += 1;
+        }
+        let mut counts = channels.into_values().collect::<Vec<u8>>();
+        counts.sort_unstable();
+        Some(counts)
+    }
+
+    /// The DRAM generation of the DIMMs, if all of them are of the same known generation.
+    pub(crate) fn generation(&self) -> Option<DramGeneration> {
+        let dimms = self.dimms.as_ref()?;
+        let generation = dimms.first()?.generation?;
+        dimms
+            .iter()
+            .all(|dimm| dimm.generation == Some(generation))
+            .then_some(generation)
+    }
+}
+
+/// Prints one line per detected property, e.g. for the user to check the defaults.
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cpu {
+            Some(cpu) => writeln!(
+                f,
+                "CPU: {} (family {:#x}, model {:#x}), mapping: {}",
+                cpu.model_name,
+                cpu.family,
+                cpu.model,
+                cpu.bridge()
+                    .map_or("unknown".to_string(), |bridge| format!("{:?}", bridge))
+            )?,
+            None => writeln!(f, "CPU: not detected")?,
+        }
+        match self.cores {
+            Some(cores) => writeln!(f, "Physical cores: {}", cores)?,
+            None => writeln!(f, "Physical cores: not detected")?,
+        }
+        match &self.dimms {
+            Some(dimms) => {
+                match self.dimms_per_channel() {
+                    Some(counts) => writeln!(
+                        f,
+                        "DIMMs: {} in {} channel(s) ({} per channel)",
+                        dimms.len(),
+                        counts.len(),
+                        counts
+                            .iter()
+                            .map(|count| count.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )?,
+                    None => writeln!(f, "DIMMs: {} in unknown channels", dimms.len())?,
+                }
+                for dimm in dimms {
+                    writeln!(
+                        f,
+                        "    {}: {} MiB, {}",
+                        dimm.locator,
+                        dimm.size_mib,
+                        dimm.generation
+                            .map_or("unknown type".to_string(), |g| format!("{:?}", g)),
+                    )?;
+                }
+                Ok(())
+            }
+            None => writeln!(
+                f,
+                "DIMMs: not detected (SMBIOS tables are missing or need root)"
+            ),
+        }
+    }
+}
+
+/// Detects the machine this program runs on, only reading the system files the first time.
+pub(crate) fn machine() -> &'static Machine {
+    static MACHINE: OnceLock<Machine> = OnceLock::new();
+    MACHINE.get_or_init(|| Machine::detect(Path::new("/")))
+}
+
+/// Parses the first processor in the contents of `/proc/cpuinfo`.
+pub(crate) fn parse_cpuinfo(cpuinfo: &str) -> Option<CpuInfo> {
+    let processor = cpuinfo.split("\n\n").next()?;
+    let field = |name: &str| {
+        processor.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name).then(|| value.trim().to_string())
+        })
+    };
+    Some(CpuInfo {
+        vendor: field("vendor_id")?,
+        family: field("cpu family")?.parse().ok()?,
+        model: field("model")?.parse().ok()?,
+        model_name: field("model name").unwrap_or_default(),
+    })
+}
+
+/// Counts the physical cores below `cpu_dir` (normally `/sys/devices/system/cpu`), counting
+/// hyperthreads of the same core once.
+pub(crate) fn count_cores(cpu_dir: &Path) -> Option<u8> {
+    let mut cores = HashSet::new();
+    for entry in fs::read_dir(cpu_dir).ok()?.flatten() {
+        let name = entry.file_name();
+        let Some(number) = name.to_str().and_then(|name| name.strip_prefix("cpu")) else {
+            continue;
+        };
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let topology = entry.path().join("topology");
+        let read = |file: &str| fs::read_to_string(topology.join(file)).ok();
+        let (Some(package), Some(core)) = (read("physical_package_id"), read("core_id")) else {
+            continue;
+        };
+        cores.insert((package.trim().to_string(), core.trim().to_string()));
+    }
+    (!cores.is_empty()).then(|| cores.len().min(u8::MAX as usize) as u8)
+}
+
+/// Parses the populated memory devices from an SMBIOS table, as found in
+/// `/sys/firmware/dmi/tables/DMI`.
+pub(crate) fn parse_memory_devices(table: &[u8]) -> Vec<MemoryDevice> {
+    let mut devices = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= table.len() {
+        let kind = table[offset];
+        let length = table[offset + 1] as usize;
+        if kind == SMBIOS_END_OF_TABLE || length < 4 || offset + length > table.len() {
+            break;
+        }
+        let structure = &table[offset..offset + length];
+        // The formatted area is followed by strings, ending with two zero bytes
+        let strings_start = offset + length;
+        let mut strings_end = strings_start;
+        while strings_end + 1 < table.len() && table[strings_end..strings_end + 2] != [0, 0] {
+            strings_end += 1;
+        }
+        if kind == SMBIOS_MEMORY_DEVICE {
+            let strings = table[strings_start..strings_end.min(table.len())]
+                .split(|b| *b == 0)
+                .map(|string| String::from_utf8_lossy(string).trim().to_string())
+                .collect::<Vec<_>>();
+            if let Some(device) = parse_memory_device(structure, &strings) {
+                devices.push(device);
+            }
+        }
+        offset = strings_end + 2;
+    }
+    devices
+}
+
+/// Parses the formatted area of a memory device structure, with `strings` the strings following
+/// it.
+///
+/// # Returns
+/// The device, or `None` if the slot is empty or the structure is too short.
+fn parse_memory_device(structure: &[u8], strings: &[String]) -> Option<MemoryDevice> {
+    let word = |at: usize| {
+        Some(u16::from_le_bytes(
+            structure.get(at..at + 2)?.try_into().ok()?,
+        ))
+    };
+    // Strings are numbered from 1, 0 meaning there is none
+    let string = |at: usize| {
+        let index = *structure.get(at)? as usize;
+        strings.get(index.checked_sub(1)?).map(String::as_str)
+    };
+    let size_mib = match word(0x0c)? {
+        0 | 0xffff => return None,
+        // The size is in the extended size field
+        0x7fff => u32::from_le_bytes(structure.get(0x1c..0x20)?.try_into().ok()?) as u64,
+        // Bit 15 means the size is in KiB
+        size if size & 0x8000 != 0 => (size & 0x7fff) as u64 / 1024,
+        size => size as u64,
+    };
+    let generation = match *structure.get(0x12)? {
+        SMBIOS_DDR3 => Some(DramGeneration::Ddr3),
+        SMBIOS_DDR4 => Some(DramGeneration::Ddr4),
+        _ => None,
+    };
+    let device_locator = string(0x10).unwrap_or_default();
+    let bank_locator = string(0x11).unwrap_or_default();
+    Some(MemoryDevice {
+        size_mib,
+        generation,
+        locator: device_locator.to_string(),
+        channel: channel_of(device_locator, bank_locator),
+    })
+}
+
+/// Finds the memory channel in the device and bank locators of a DIMM slot. Vendors name them
+/// differently, e.g. `ChannelA-DIMM0`, `P0 CHANNEL A`, `P0_Node0_Channel1_Dimm0` or `DIMM_B1`.
+fn channel_of(device_locator: &str, bank_locator: &str) -> Option<String> {
+    let separators: &[char] = &[' ', '_', '-'];
+    for locator in [bank_locator, device_locator] {
+        let locator = locator.to_ascii_uppercase();
+        let Some(at) = locator.find("CHANNEL") else {
+            continue;
+        };
+        let channel = locator[at + "CHANNEL".len()..]
+            .trim_start_matches(separators)
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>();
+        if !channel.is_empty() {
+            return Some(channel);
+        }
+    }
+    // Slots named after the channel letter and the DIMM within it
+    let locator = device_locator.to_ascii_uppercase();
+    let at = locator.find("DIMM")?;
+    let mut slot = locator[at + "DIMM".len()..]
+        .trim_start_matches(separators)
+        .chars();
+    match (slot.next(), slot.next()) {
+        (Some(channel @ 'A'..='H'), Some('0'..='9')) => Some(channel.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(machine: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/detect")
+            .join(machine)
+    }
+
+    #[test]
+    fn parses_cpuinfo() {
+        let cases = [
+            (
+                "haswell-desktop",
+                "GenuineIntel",
+                6,
+                0x3c,
+                Some(Bridge::Haswell),
+            ),
+            (
+                "skylake-laptop",
+                "GenuineIntel",
+                6,
+                0x4e,
+                Some(Bridge::Skylake),
+            ),
+            (
+                "haswell-ep-server",
+                "GenuineIntel",
+                6,
+                0x3f,
+                Some(Bridge::Haswell),
+            ),
+            (
+                "zen2-desktop",
+                "AuthenticAMD",
+                0x17,
+                0x71,
+                Some(Bridge::Zen),
+            ),
+            ("sapphire-rapids-vm", "GenuineIntel", 6, 0x8f, None),
+        ];
+        for (machine, vendor, family, model, bridge) in cases {
+            let cpuinfo = fs::read_to_string(fixture(machine).join("proc/cpuinfo")).unwrap();
+            let cpu = parse_cpuinfo(&cpuinfo).unwrap();
+            assert_eq!(
+                (cpu.vendor.as_str(), cpu.family, cpu.model),
+                (vendor, family, model),
+                "{}",
+                machine
+            );
+            assert_eq!(cpu.bridge(), bridge, "{}", machine);
+        }
+        assert_eq!(
+            parse_cpuinfo(
+                &fs::read_to_string(fixture("zen2-desktop").join("proc/cpuinfo")).unwrap()
+            )
+            .unwrap()
+            .model_name,
+            "AMD Ryzen 7 3700X 8-Core Processor"
+        );
+        assert_eq!(parse_cpuinfo(""), None);
+        assert_eq!(
+            parse_cpuinfo("vendor_id : GenuineIntel\nmodel : 60\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn counts_cores() {
+        let cases = [
+            ("haswell-desktop", Some(4)),
+            ("skylake-laptop", Some(2)),
+            ("haswell-ep-server", Some(16)),
+            ("zen2-desktop", Some(8)),
+            ("sapphire-rapids-vm", Some(1)),
+            ("missing", None),
+        ];
+        for (machine, cores) in cases {
+            let cpu_dir = fixture(machine).join("sys/devices/system/cpu");
+            assert_eq!(count_cores(&cpu_dir), cores, "{}", machine);
+        }
+    }
+
+    #[test]
+    fn parses_memory_devices() {
+        let dimm = |size_mib, generation, locator: &str, channel: &str| MemoryDevice {
+            size_mib,
+            generation: Some(generation),
+            locator: locator.to_string(),
+            channel: Some(channel.to_string()),
+        };
+        let cases = [
+            (
+                "haswell-desktop",
+                vec![
+                    dimm(8192, DramGeneration::Ddr3, "ChannelA-DIMM0", "A"),
+                    dimm(8192, DramGeneration::Ddr3, "ChannelB-DIMM0", "B"),
+                ],
+            ),
+            (
+                "skylake-laptop",
+                vec![dimm(4096, DramGeneration::Ddr3, "ChannelA-DIMM0", "A")],
+            ),
+            (
+                "haswell-ep-server",
+                vec![
+                    dimm(16384, DramGeneration::Ddr4, "P1-DIMMA1", "0"),
+                    dimm(16384, DramGeneration::Ddr4, "P1-DIMMB1", "1"),
+                    dimm(16384, DramGeneration::Ddr4, "P1-DIMMC1", "2"),
+                    dimm(32768, DramGeneration::Ddr4, "P1-DIMMD1", "3"),
+                ],
+            ),
+            (
+                "zen2-desktop",
+                vec![
+                    dimm(8192, DramGeneration::Ddr4, "DIMM_A1", "A"),
+                    dimm(8192, DramGeneration::Ddr4, "DIMM_A2", "A"),
+                    dimm(8192, DramGeneration::Ddr4, "DIMM_B1", "B"),
+                    dimm(8192, DramGeneration::Ddr4, "DIMM_B2", "B"),
+                ],
+            ),
+        ];
+        for (machine, dimms) in cases {
+            let table = fs::read(fixture(machine).join("sys/firmware/dmi/tables/DMI")).unwrap();
+            assert_eq!(parse_memory_devices(&table), dimms, "{}", machine);
+        }
+
+        // A table cut off in the middle of a structure keeps the devices before it
+        let table =
+            fs::read(fixture("haswell-desktop").join("sys/firmware/dmi/tables/DMI")).unwrap();
+        assert_eq!(parse_memory_devices(&table[..0x120]).len(), 1);
+        assert!(parse_memory_devices(&[]).is_empty());
+    }
+
+    #[test]
+    fn finds_channels() {
+        let cases = [
+            ("ChannelA-DIMM0", "BANK 0", Some("A")),
+            ("DIMM 0", "P0 CHANNEL B", Some("B")),
+            ("P1-DIMMA1", "P0_Node0_Channel1_Dimm0", Some("1")),
+            ("DIMM_C2", "BANK 5", Some("C")),
+            ("P2-DIMMD1", "", Some("D")),
+            ("DIMM 0", "BANK 0", None),
+            ("DIMM1", "", None),
+            ("", "", None),
+        ];
+        for (device, bank, channel) in cases {
+            assert_eq!(
+                channel_of(device, bank).as_deref(),
+                channel,
+                "{} / {}",
+                device,
+                bank
+            );
+        }
+    }
+
+    #[test]
+    fn detects_machines() {
+        let cases = [
+            (
+                "haswell-desktop",
+                Some(Bridge::Haswell),
+                Some(4),
+                Some(DramGeneration::Ddr3),
+                Some(vec![1, 1]),
+            ),
+            (
+                "skylake-laptop",
+                Some(Bridge::Skylake),
+                Some(2),
+                Some(DramGeneration::Ddr3),
+                Some(vec![1]),
+            ),
+            (
+                "haswell-ep-server",
+                Some(Bridge::Haswell),
+                Some(16),
+                Some(DramGeneration::Ddr4),
+                Some(vec![1, 1, 1, 1]),
+            ),
+            (
+                "zen2-desktop",
+                Some(Bridge::Zen),
+                Some(8),
+                Some(DramGeneration::Ddr4),
+                Some(vec![2, 2]),
+            ),
+            ("sapphire-rapids-vm", None, Some(1), None, None),
+            ("missing", None, None, None, None),
+        ];
+        for (name, bridge, cores, generation, dimms_per_channel) in cases {
+            let machine = Machine::detect(&fixture(name));
+            assert_eq!(machine.bridge(), bridge, "{}", name);
+            assert_eq!(machine.cores, cores, "{}", name);
+            assert_eq!(machine.generation(), generation, "{}", name);
+            assert_eq!(machine.dimms_per_channel(), dimms_per_channel, "{}", name);
+        }
+    }
+
+    #[test]
+    fn mixed_or_unplaced_dimms() {
+        let dimm = |generation, channel: Option<&str>| MemoryDevice {
+            size_mib: 8192,
+            generation,
+            locator: String::new(),
+            channel: channel.map(str::to_string),
+        };
+        let machine = Machine {
+            dimms: Some(vec![
+                dimm(Some(DramGeneration::Ddr4), Some("A")),
+                dimm(None, Some("A")),
+                dimm(Some(DramGeneration::Ddr4), Some("B")),
+            ]),
+            ..Machine::default()
+        };
+        assert_eq!(machine.generation(), None);
+        assert_eq!(machine.dimms_per_channel(), Some(vec![1, 2]));
+
+        let machine = Machine {
+            dimms: Some(vec![dimm(Some(DramGeneration::Ddr3), None)]),
+            ..Machine::default()
+        };
+        assert_eq!(machine.generation(), Some(DramGeneration::Ddr3));
+        assert_eq!(machine.dimms_per_channel(), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::profiler::{
+    mapping::DramMapping,
+    utils::{Page, Row},
+};
+
+/// The pages of a mapping, indexed by page frame number and by DRAM row. Only rows holding at
+/// least one page are stored, so a mapping high up in physical memory doesn't cost a `Row` for
+/// every row below it.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryIndex {
+    rows: BTreeMap<usize, Row>,
+    /// The row index and position in that row of every page, by page frame number
+    pfns: HashMap<u64, (usize, usize)>,
+}
+
+impl MemoryIndex {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `page` to the row `mapping` places it in. Pages whose page frame is already indexed
+    /// are ignored.
+    ///
+    /// # Returns
+    /// Whether the page was added.
+    pub(crate) fn insert(&mut self, page: Page, mapping: &dyn DramMapping) -> bool {
+        if self.pfns.contains_key(&page.pfn) {
+            return false;
+        }
+        let row_index = mapping.row(page.phys_addr() as u64);
+        let row = self
+            .rows
+            .entry(row_index)
+            .or_insert_with(|| Row::new(row_index));
+        self.pfns.insert(page.pfn, (row_index, row.len()));
+        row.push(page);
+        true
+    }
+
+    /// The number of rows holding at least one page.
+    pub(crate) fn row_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The number of pages.
+    pub(crate) fn page_count(&self) -> usize {
+        self.pfns.len()
+    }
+
+    /// The page holding page frame `pfn`.
+    pub(crate) fn page(&self, pfn: u64) -> Option<&Page> {
+        let (row_index, position) = self.pfns.get(&pfn)?;
+        Some(&self.rows[row_index][*position])
+    }
+
+    /// The row with DRAM row number `index`, if any of its pages are mapped.
+    pub(crate) fn row(&self, index: usize) -> Option<&Row> {
+        self.rows.get(&index)
+    }
+
+    /// The rows holding pages, ordered by their DRAM row number.
+    pub(crate) fn rows(&self) -> impl Iterator<Item = &Row> {
+        self.rows.values()
+    }
+
+    pub(crate) fn pages(&self) -> impl Iterator<Item = &Page> {
+        self.rows.values().flatten()
+    }
+
+    /// Finds the row `distance` rows away from row `index` on the chips of `rank`, which isn't
+    /// the row with the number `index + distance` if the rank mirrors or inverts its row address.
+    pub(crate) fn neighbour_row(
+        &self,
+        mapping: &dyn DramMapping,
+        index: usize,
+        rank: u8,
+        distance: isize,
+    ) -> Option<&Row> {
+        self.row(mapping.adjacent_row(index, rank, distance)?)
+    }
+
+    /// Finds the row with DRAM row number `index` and the rows physically above and below it on
+    /// the chips of `rank`.
+    ///
+    /// # Returns
+    /// The rows as (above, target, below), or `None` if any of them holds no pages.
+    pub(crate) fn adjacent_rows(
+        &self,
+        mapping: &dyn DramMapping,
+        index: usize,
+        rank: u8,
+    ) -> Option<(&Row, &Row, &Row)> {
+        Some((
+            self.neighbour_row(mapping, index, rank, -1)?,
+            self.row(index)?,
+            self.neighbour_row(mapping, index, rank, 1)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::mapping::get_mapping;
+    use crate::Bridge;
+
+    /// Haswell with one DIMM has 128 KiB rows, so row `r` starts at page frame `r << 5`.
+    const ROW_PAGES: u64 = 32;
+
+    fn page(pfn: u64) -> Page {
+        Page::new((0x7f00_0000_0000 + pfn as usize * 0x1000) as *mut u8, pfn)
+    }
+
+    fn rows_of(index: &MemoryIndex) -> Vec<usize> {
+        index.rows().map(|row| row.index).collect()
+    }
+
+    #[test]
+    fn ignores_page_frames_indexed_twice() {
+        let mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        let mut index = MemoryIndex::new();
+        assert!(index.insert(page(0x100), &mapping));
+        let mut again = page(0x100);
+        again.virt_addr = 0x1000 as *mut u8;
+        assert!(!index.insert(again, &mapping));
+        assert!(index.insert(page(0x101), &mapping));
+        assert_eq!((index.page_count(), index.row_count()), (2, 1));
+        assert_eq!(index.page(0x100).unwrap().virt_addr, page(0x100).virt_addr);
+        assert!(index.page(0x102).is_none());
+        assert_eq!(index.row(0x100 / ROW_PAGES as usize).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn only_stores_rows_with_pages() {
+        let mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        let mut index = MemoryIndex::new();
+        // Far apart in physical memory, and out of order
+        for pfn in [0x40_0000, 8 * ROW_PAGES + 3, 8 * ROW_PAGES, 0x40_0001] {
+            index.insert(page(pfn), &mapping);
+        }
+        assert_eq!(rows_of(&index), [8, 0x40_0000 / ROW_PAGES as usize]);
+        assert!(index.row(9).is_none());
+        assert_eq!(index.page_count(), 4);
+        let pfns = index.pages().map(|page| page.pfn).collect::<Vec<_>>();
+        assert_eq!(
+            pfns,
+            [8 * ROW_PAGES + 3, 8 * ROW_PAGES, 0x40_0000, 0x40_0001]
+        );
+        for pfn in pfns {
+            assert_eq!(index.page(pfn).unwrap().pfn, pfn);
+        }
+    }
+
+    #[test]
+    fn finds_adjacent_rows_per_rank() {
+        let mut mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        mapping.rank_mirroring = true;
+        let mut index = MemoryIndex::new();
+        for row in [7, 8, 9, 23] {
+            index.insert(page(row * ROW_PAGES), &mapping);
+        }
+        let rows = |rank| {
+            index
+                .adjacent_rows(&mapping, 8, rank)
+                .map(|(above, target, below)| (above.index, target.index, below.index))
+        };
+        assert_eq!(rows(0), Some((7, 8, 9)));
+        // Row 8 is row 16 on the mirrored chips, whose neighbours 15 and 17 are rows 23 and 9
+        assert_eq!(rows(1), Some((23, 8, 9)));
+        assert_eq!(index.neighbour_row(&mapping, 8, 1, -1).unwrap().index, 23);
+        assert!(index.adjacent_rows(&mapping, 9, 0).is_none());
+        assert!(index.adjacent_rows(&mapping, 0, 0).is_none());
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use memmap2::MmapMut;
+use procfs::process::Process;
+use rand::Rng;
+
+use crate::profiler::{
+    numa::node_string,
+    pattern::Hammering,
+    rowhammer::{init_row, INIT_PATTERN, PATTERN},
+    timing::{find_conflict_threshold, measure_access_time},
+    utils::{count_flips_by_bit, get_page_frame_number, Page, PAGE_SIZE},
+};
+use crate::Backing;
+
+/// Rounds of timing measurements per pair of pages
+const ROUNDS: usize = 100;
+/// Random page pairs measured to find the row conflict threshold
+const CALIBRATION_PAIRS: usize = 1000;
+/// Largest part of a huge page grouped at once, as grouping takes time quadratic in its size
+const WINDOW_SIZE: usize = 0x80_0000;
+
+/// The pages of one bank in a window, grouped into rows.
+struct BankSet {
+    /// Rows ordered by their offset in the window, so neighbouring rows are (most likely)
+    /// physically adjacent
+    rows: Vec<Vec<Page>>,
+}
+
+/// Checks whether the page frame numbers of `mmap` can be read, which needs root.
+pub(crate) fn pfns_available(mmap: &mut MmapMut) -> bool {
+    let Ok(mut pagemap) = Process::myself().and_then(|process| process.pagemap()) else {
+        return false;
+    };
+    mmap.is_empty() || get_page_frame_number(&mut pagemap, mmap.as_ptr()).is_ok()
+}
+
+fn conflicts(a: &Page, b: &Page, threshold: u64) -> bool {
+    measure_access_time(a.virt_addr, b.virt_addr, ROUNDS) > threshold
+}
+
+/// Finds the row conflict threshold from random page pairs in `mmap`.
+fn find_threshold(mmap: &mut MmapMut) -> Option<u64> {
+    let pages = mmap.len() / PAGE_SIZE;
+    if pages < 2 {
+        return None;
+    }
+    let base_ptr = mmap.as_mut_ptr();
+    let mut rng = rand::thread_rng();
+    let latencies = (0..CALIBRATION_PAIRS)
+        .map(|_| unsafe {
+            let a = base_ptr.add(rng.gen_range(0..pages) * PAGE_SIZE);
+            let b = base_ptr.add(rng.gen_range(0..pages) * PAGE_SIZE);
+            measure_access_time(a, b, ROUNDS)
+        })
+        .collect::<Vec<_>>();
+    find_conflict_threshold(&latencies)
+}
+
+/// Groups `count` pages of a physically contiguous window, given by their index in the window,
+/// into same-bank sets and the sets into rows, only using whether two pages `conflict`. Pages in
+/// the same bank but different rows conflict, pages in the same row or in different banks don't.
+///
+/// # Returns
+/// The sets, each with its rows ordered by the position of their first page in the window.
+fn group_by_conflicts(
+    count: usize,
+    conflict: impl Fn(usize, usize) -> bool,
+) -> Vec<Vec<Vec<usize>>> {
+    let mut unassigned = (0..count).collect::<Vec<_>>();
+    let mut sets = Vec::new();
+    while let Some((&reference, rest)) = unassigned.split_first() {
+        let (other_rows, rest): (Vec<usize>, Vec<usize>) =
+            rest.iter().partition(|&&page| conflict(reference, page));
+        // Pages conflicting with another row of the bank but not with the reference are in the
+        // same row as the reference
+        let same_row = match other_rows.first() {
+            Some(&other) => rest
+                .iter()
+                .copied()
+                .filter(|&page| conflict(other, page))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let mut rows = vec![[vec![reference], same_row].concat()];
+        for &page in &other_rows {
+            match rows[1..].iter_mut().find(|row| !conflict(row[0], page)) {
+                Some(row) => row.push(page),
+                None => rows.push(vec![page]),
+            }
+        }
+        // The row bits are the highest bits of the address, so rows are in the same order as
+        // their offsets in the window
+        rows.sort_by_key(|row| row[0]);
+
+        let assigned = rows.iter().flatten().copied().collect::<HashSet<_>>();
+        unassigned.retain(|page| !assigned.contains(page));
+        sets.push(rows);
+    }
+    sets
+}
+
+/// Groups the pages in `window`, which has to be physically contiguous, into same-bank sets and
+/// the sets into rows by timing row buffer conflicts between them.
+///
+/// Each page is represented by its first cache line, so pages spread over several banks are
+/// grouped by the bank of that line.
+fn group_by_bank(window: &[Page], threshold: u64) -> Vec<BankSet> {
+    group_by_conflicts(window.len(), |a, b| {
+        conflicts(&window[a], &window[b], threshold)
+    })
+    .into_iter()
+    .map(|rows| BankSet {
+        rows: rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|page| window[page].clone()).collect())
+            .collect(),
+    })
+    .collect()
+}
+
+/// Hammers every row of `mmap` with its neighbours, which are found with row buffer conflicts
+/// instead of page frame numbers and the DRAM mapping. Works without root, but needs huge page
+/// backing as only huge pages are known to be physically contiguous.
+///
+/// The output lists the offsets of the pages in their huge page instead of page frame numbers,
+/// with records starting with `~` so `evaluate` doesn't mistake them for physical addresses.
+pub(crate) fn hammer_all_reachable_pages(
+    mmap: &mut MmapMut,
+    output: impl AsRef<Path>,
+    hammering: &Hammering,
+    backing: Backing,
+) -> io::Result<()> {
+    if backing == Backing::Small {
+        eprintln!(
+            "[!] Can't infer rows in 4 KiB pages, use huge pages with --backing or run as sudo"
+        );
+        return Ok(());
+    }
+    if backing == Backing::Thp {
+        println!("[!] Assuming the kernel backed the mapping with transparent huge pages");
+    }
+
+    println!("Finding the row conflict threshold...");
+    let Some(threshold) = find_threshold(mmap) else {
+        eprintln!("[!] Couldn't find a row conflict threshold, can't infer rows");
+        return Ok(());
+    };
+    println!("Row conflict threshold: {} cycles", threshold);
+
+    let mut status_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("std.out")?;
+    let mut outfile = OpenOptions::new().create(true).append(true).open(output)?;
+
+    let huge_page_size = backing.page_size();
+    let window_size = huge_page_size.min(WINDOW_SIZE);
+    let width = 12;
+    writeln!(
+        outfile,
+        "# Physical addresses are inferred from row buffer conflicts: addresses are offsets into a \
+         {} KiB huge page, whose page frame number is unknown",
+        huge_page_size >> 10
+    )?;
+    writeln!(
+        outfile,
+        "\t{:<width$}{:<width$}{:<width$}{:<width$}{:<7}Flipped bits\tInferred address\tNUMA node",
+        "Page", "Offset", "aOffset", "bOffset", "Flips"
+    )?;
+
+    let base_ptr = mmap.as_mut_ptr();
+    let mut total_flips = 0;
+    let mut rows_tested = 0;
+    let mut pages_tested = 0;
+    for window_offset in (0..mmap.len()).step_by(window_size) {
+        let window = (window_offset..window_offset + window_size)
+            .step_by(PAGE_SIZE)
+            .map(|offset| Page::new(unsafe { base_ptr.add(offset) }, 0))
+            .collect::<Vec<_>>();
+        let huge_page = window_offset / huge_page_size;
+        let offset =
+            |page: &Page| page.virt_addr as usize - base_ptr as usize - huge_page * huge_page_size;
+
+        let before = Instant::now();
+        let sets = group_by_bank(&window, threshold);
+        writeln!(
+            status_file,
+            "Grouped window {:#x} into {} same-bank sets in {:.2?}",
+            window_offset,
+            sets.len(),
+            before.elapsed()
+        )?;
+
+        for (set_index, set) in sets.iter().enumerate() {
+            // The rows are in physical order, so the aggressors are the rows at the pattern's
+            // distances from the target, and the ones directly next to it are listed in the output
+            let reach = hammering.pattern.reach().max(1);
+            for row_index in reach..set.rows.len().saturating_sub(reach) {
+                let row_at = |distance: isize| &set.rows[row_index.wrapping_add_signed(distance)];
+                let (above_row, target_row, below_row) = (row_at(-1), row_at(0), row_at(1));
+                let aggressors = hammering
+                    .pattern
+                    .distances()
+                    .map(row_at)
+                    .collect::<Vec<_>>();
+                init_row(target_row, INIT_PATTERN);
+                for row in &aggressors {
+                    init_row(row, PATTERN);
+                }
+
+                let before = Instant::now();
+                let addrs = aggressors
+                    .iter()
+                    .map(|row| row[0].virt_addr as *const u8)
+                    .collect::<Vec<_>>();
+                hammering.hammer(&addrs);
+                writeln!(
+                    status_file,
+                    "Hammering huge page {} set {} row {} took {:.2?}",
+                    huge_page,
+                    set_index,
+                    row_index,
+                    before.elapsed()
+                )?;
+
+                for target_page in target_row {
+                    let (flips, _) = count_flips_by_bit(target_page, INIT_PATTERN);
+                    let flip_sum = flips.iter().sum::<u64>();
+                    total_flips += flip_sum;
+                    if flip_sum == 0 {
+                        continue;
+                    }
+                    writeln!(
+                        outfile,
+                        "~\t{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<7}{:?}\thuge page {}, set {}, row {}\t{}",
+                        target_page.virt_addr as usize,
+                        offset(target_page),
+                        offset(&above_row[0]),
+                        offset(&below_row[0]),
+                        flip_sum,
+                        flips,
+                        huge_page,
+                        set_index,
+                        row_index,
+                        node_string(target_page.virt_addr),
+                    )?;
+                }
+                rows_tested += 1;
+                pages_tested += target_row.len();
+            }
+        }
+        writeln!(
+            status_file,
+            "So far: {} flips total over {} rows ({} pages) tested, {:.2}% of allocated memory \
+             analyzed (addresses inferred)\n",
+            total_flips,
+            rows_tested,
+            pages_tested,
+            (window_offset + window_size) as f64 * 100.0 / mmap.len() as f64,
+        )?;
+    }
+    writeln!(status_file, "Done!")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Latencies of a synthetic window where page `i` is in bank `i % banks` and row
+    /// `i / row_pages`, with some noise. Same-bank pairs in different rows are row buffer
+    /// conflicts, a few of the other pairs are slowed down by interrupts.
+    fn latencies(pages: usize, banks: usize, row_pages: usize) -> Vec<Vec<u64>> {
+        (0..pages)
+            .map(|a| {
+                (0..pages)
+                    .map(|b| {
+                        let noise = ((a * 7 + b * 13) % 11) as u64;
+                        let same_bank = a % banks == b % banks;
+                        let same_row = a / row_pages == b / row_pages;
+                        match (same_bank, same_row) {
+                            _ if (a * 31 + b) % 401 == 0 => 1500,
+                            (true, false) => 330 + noise,
+                            (true, true) => 190 + noise,
+                            (false, _) => 210 + noise,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_pages_into_banks_and_rows() {
+        // pages, banks, pages per row
+        let cases = [(64, 4, 8), (64, 8, 16), (48, 2, 4), (32, 16, 16)];
+        for (pages, banks, row_pages) in cases {
+            let matrix = latencies(pages, banks, row_pages);
+            let all = matrix
+                .iter()
+                .enumerate()
+                .flat_map(|(a, row)| row.iter().enumerate().filter(move |(b, _)| a != *b))
+                .map(|(_, latency)| *latency)
+                .collect::<Vec<_>>();
+            let threshold = find_conflict_threshold(&all).unwrap();
+            assert!((220..330).contains(&threshold), "threshold {}", threshold);
+
+            // The interrupts are only seen when measuring once, not in the median of the rounds
+            let conflict = |a: usize, b: usize| matrix[a][b].min(matrix[b][a]) > threshold;
+            let sets = group_by_conflicts(pages, conflict);
+            let name = format!("{} pages, {} banks, {} per row", pages, banks, row_pages);
+            assert_eq!(sets.len(), banks, "{}", name);
+            for (bank, rows) in sets.iter().enumerate() {
+                assert_eq!(rows.len(), pages / row_pages, "{}", name);
+                for (row, pages) in rows.iter().enumerate() {
+                    let mut pages = pages.clone();
+                    pages.sort();
+                    let expected = (0..row_pages / banks)
+                        .map(|i| row * row_pages + i * banks + bank)
+                        .collect::<Vec<_>>();
+                    assert_eq!(pages, expected, "{}, bank {} row {}", name, bank, row);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pages_without_conflicts_are_their_own_sets() {
+        // Without another row in the bank, a row can't be told apart from a different bank
+        let sets = group_by_conflicts(3, |_, _| false);
+        assert_eq!(sets, [vec![vec![0]], vec![vec![1]], vec![vec![2]]]);
+        assert!(group_by_conflicts(0, |_, _| true).is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use std::{collections::BTreeMap, fmt, fs::File, io, mem::size_of, os::unix::fs::FileExt};
+
+/// One 64-bit set of flags per page frame, needs root
+const KPAGEFLAGS: &str = "/proc/kpageflags";
+
+/// The flags in `/proc/kpageflags` (see the kernel's include/uapi/linux/kernel-page-flags.h),
+/// with the names they are listed by.
+const FLAG_NAMES: [(u32, &str); 28] = [
+    (0, "locked"),
+    (1, "error"),
+    (2, "referenced"),
+    (3, "uptodate"),
+    (4, "dirty"),
+    (5, "lru"),
+    (6, "active"),
+    (7, "slab"),
+    (8, "writeback"),
+    (9, "reclaim"),
+    (10, "buddy"),
+    (11, "mmap"),
+    (12, "anon"),
+    (13, "swapcache"),
+    (14, "swapbacked"),
+    (15, "compound_head"),
+    (16, "compound_tail"),
+    (17, "huge"),
+    (18, "unevictable"),
+    (19, "hwpoison"),
+    (20, "nopage"),
+    (21, "ksm"),
+    (22, "thp"),
+    (23, "offline"),
+    (24, "zero_page"),
+    (25, "idle"),
+    (26, "pgtable"),
+    (32, "reserved"),
+];
+const KPF_LRU: u32 = 5;
+const KPF_SLAB: u32 = 7;
+const KPF_BUDDY: u32 = 10;
+const KPF_ANON: u32 = 12;
+const KPF_COMPOUND_HEAD: u32 = 15;
+const KPF_COMPOUND_TAIL: u32 = 16;
+const KPF_HUGE: u32 = 17;
+const KPF_HWPOISON: u32 = 19;
+const KPF_NOPAGE: u32 = 20;
+const KPF_THP: u32 = 22;
+const KPF_OFFLINE: u32 = 23;
+const KPF_PGTABLE: u32 = 26;
+const KPF_RESERVED: u32 = 32;
+
+/// What a page frame is used for, as far as its flags tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum PageState {
+    /// No page frame with that number exists
+    NoPage,
+    /// Reserved by the firmware or the kernel, never handed out
+    Reserved,
+    /// Offline or poisoned
+    Unusable,
+    /// Free in the buddy allocator
+    Free,
+    /// Part of a hugetlb page
+    Hugetlb,
+    /// Part of a transparent huge page
+    Thp,
+    /// Part of a higher order allocation by the kernel
+    Compound,
+    Slab,
+    PageTable,
+    /// Anonymous memory of a process
+    Anon,
+    /// Page cache
+    File,
+    /// None of the flags are set, e.g. pages on a per-CPU free list or allocated by the kernel
+    /// without any bookkeeping
+    Unknown,
+}
+
+impl fmt::Display for PageState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            PageState::NoPage => "no such page frame",
+            PageState::Reserved => "reserved",
+            PageState::Unusable => "offline or poisoned",
+            PageState::Free => "free (buddy allocator)",
+            PageState::Hugetlb => "hugetlb page",
+            PageState::Thp => "transparent huge page",
+            PageState::Compound => "compound kernel allocation",
+            PageState::Slab => "slab",
+            PageState::PageTable => "page table",
+            PageState::Anon => "anonymous memory (LRU)",
+            PageState::File => "page cache (LRU)",
+            PageState::Unknown => "allocated or on a per-CPU free list",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// The flags of one page frame in `/proc/kpageflags`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PageFlags(pub(crate) u64);
+
+impl PageFlags {
+    pub(crate) fn has(&self, flag: u32) -> bool {
+        self.0 & (1 << flag) != 0
+    }
+
+    /// Classifies the page frame by its flags, from the most to the least telling flag.
+    pub(crate) fn state(&self) -> PageState {
+        if self.has(KPF_NOPAGE) {
+            PageState::NoPage
+        } else if self.has(KPF_RESERVED) {
+            PageState::Reserved
+        } else if self.has(KPF_OFFLINE) || self.has(KPF_HWPOISON) {
+            PageState::Unusable
+        } else if self.has(KPF_BUDDY) {
+            PageState::Free
+        } else if self.has(KPF_HUGE) {
+            PageState::Hugetlb
+        } else if self.has(KPF_THP) {
+            PageState::Thp
+        } else if self.has(KPF_SLAB) {
+            PageState::Slab
+        } else if self.has(KPF_PGTABLE) {
+            PageState::PageTable
+        } else if self.has(KPF_LRU) && self.has(KPF_ANON) {
+            PageState::Anon
+        } else if self.has(KPF_LRU) {
+            PageState::File
+        } else if self.has(KPF_COMPOUND_HEAD) || self.has(KPF_COMPOUND_TAIL) {
+            PageState::Compound
+        } else {
+            PageState::Unknown
+        }
+    }
+
+    /// The names of the flags which are set.
+    pub(crate) fn names(&self) -> Vec<&'static str> {
+        FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// Lists the state and the flags, e.g. `free (buddy allocator) [buddy]`.
+impl fmt::Display for PageFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.state(), self.names().join(", "))
+    }
+}
+
+/// Reads the flags of every page frame in `pfns` from `/proc/kpageflags`, which needs root.
+pub(crate) fn page_flags(pfns: &[u64]) -> io::Result<Vec<PageFlags>> {
+    let kpageflags = File::open(KPAGEFLAGS)?;
+    pfns.iter()
+        .map(|pfn| {
+            let mut raw = [0u8; size_of::<u64>()];
+            match kpageflags.read_exact_at(&mut raw, pfn * size_of::<u64>() as u64) {
+                Ok(()) => Ok(PageFlags(u64::from_ne_bytes(raw))),
+                // Page frames past the end of the memory aren't in the file at all
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Ok(PageFlags(1 << KPF_NOPAGE))
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .collect()
+}
+
+/// Prints the state of every page frame in `pfns` under `title`, or why it couldn't be read.
+pub(crate) fn print_page_states(title: &str, pfns: &[u64]) {
+    println!("{}", title);
+    match page_flags(pfns) {
+        Ok(flags) => {
+            for (pfn, flags) in pfns.iter().zip(flags) {
+                println!("    {:#x}: {}", pfn, flags);
+            }
+        }
+        Err(e) => println!("[!] Couldn't read {}: {}", KPAGEFLAGS, e),
+    }
+}
+
+/// Counts how many of the page frames with `flags` are in each state.
+fn count_states(flags: &[PageFlags]) -> BTreeMap<PageState, usize> {
+    let mut counts = BTreeMap::new();
+    for flags in flags {
+        *counts.entry(flags.state()).or_default() += 1;
+    }
+    counts
+}
+
+/// Checks that the page frames in `pfns`, which were just released, went back to the kernel.
+/// Pages freed from inside a transparent huge page stay part of it until the huge page is split,
+/// so the kernel can't hand them out yet.
+///
+pub(crate) fn check_released(pfns: &[u64]) -> io::Result<()> {
+    let counts = count_states(&page_flags(pfns)?);
+    let summary = counts
+        .iter()
+        .map(|(state, count)| format!("{} {}", count, state))
+        .collect::<Vec<_>>();
+    println!("Released page frames: {}", summary.join(", "));
+    let in_use = counts
+        .iter()
+        .filter(|(state, _)| !matches!(state, PageState::Free | PageState::Unknown))
+        .map(|(_, count)| count)
+        .sum::<usize>();
+    if in_use > 0 {
+        eprintln!(
+            "[!] {} of {} released page frames are still in use and can't be handed out",
+            in_use,
+            pfns.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(bits: &[u32]) -> PageFlags {
+        PageFlags(bits.iter().fold(0, |flags, bit| flags | 1 << bit))
+    }
+
+    #[test]
+    fn classifies_pages() {
+        let cases = [
+            (flags(&[]), PageState::Unknown),
+            (flags(&[KPF_NOPAGE]), PageState::NoPage),
+            // Reserved pages are also marked as referenced and up to date
+            (flags(&[2, 3, KPF_RESERVED]), PageState::Reserved),
+            (flags(&[KPF_OFFLINE]), PageState::Unusable),
+            (
+                flags(&[KPF_HWPOISON, KPF_LRU, KPF_ANON]),
+                PageState::Unusable,
+            ),
+            (flags(&[KPF_BUDDY]), PageState::Free),
+            // The head of a free block of a higher order
+            (flags(&[KPF_BUDDY, KPF_COMPOUND_HEAD]), PageState::Free),
+            (flags(&[KPF_HUGE, KPF_COMPOUND_HEAD]), PageState::Hugetlb),
+            (flags(&[KPF_HUGE, KPF_COMPOUND_TAIL]), PageState::Hugetlb),
+            (
+                flags(&[KPF_THP, KPF_COMPOUND_HEAD, KPF_LRU, KPF_ANON, 11, 14]),
+                PageState::Thp,
+            ),
+            (
+                flags(&[KPF_THP, KPF_COMPOUND_TAIL, KPF_ANON]),
+                PageState::Thp,
+            ),
+            (flags(&[KPF_SLAB]), PageState::Slab),
+            (flags(&[KPF_SLAB, KPF_COMPOUND_HEAD]), PageState::Slab),
+            (flags(&[KPF_PGTABLE]), PageState::PageTable),
+            // Referenced, up to date, active, mapped and swap backed anonymous memory
+            (
+                flags(&[2, 3, KPF_LRU, 6, 11, KPF_ANON, 14]),
+                PageState::Anon,
+            ),
+            (flags(&[KPF_LRU, KPF_ANON]), PageState::Anon),
+            (flags(&[2, 3, 4, KPF_LRU, 6, 11]), PageState::File),
+            (flags(&[KPF_LRU]), PageState::File),
+            // Anonymous pages off the LRU lists, e.g. isolated for migration
+            (flags(&[KPF_ANON]), PageState::Unknown),
+            (flags(&[KPF_COMPOUND_HEAD]), PageState::Compound),
+            (flags(&[KPF_COMPOUND_TAIL]), PageState::Compound),
+        ];
+        for (flags, state) in cases {
+            assert_eq!(flags.state(), state, "{}", flags);
+        }
+    }
+
+    #[test]
+    fn lists_flag_names() {
+        let cases = [
+            (flags(&[]), "allocated or on a per-CPU free list []"),
+            (flags(&[KPF_BUDDY]), "free (buddy allocator) [buddy]"),
+            (
+                flags(&[2, 3, KPF_RESERVED]),
+                "reserved [referenced, uptodate, reserved]",
+            ),
+            (
+                flags(&[KPF_HUGE, KPF_COMPOUND_TAIL]),
+                "hugetlb page [compound_tail, huge]",
+            ),
+            // Bits without a name aren't listed
+            (PageFlags(1 << 40 | 1 << KPF_LRU), "page cache (LRU) [lru]"),
+        ];
+        for (flags, expected) in cases {
+            assert_eq!(flags.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn counts_states() {
+        let released = [
+            flags(&[KPF_BUDDY]),
+            flags(&[KPF_BUDDY, KPF_COMPOUND_HEAD]),
+            flags(&[]),
+            flags(&[KPF_THP, KPF_COMPOUND_TAIL, KPF_ANON]),
+            flags(&[KPF_BUDDY]),
+        ];
+        let counts = count_states(&released);
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [
+                (PageState::Free, 3),
+                (PageState::Thp, 1),
+                (PageState::Unknown, 1)
+            ]
+        );
+        assert!(count_states(&[]).is_empty());
+    }
+}
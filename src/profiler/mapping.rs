@@ -0,0 +1,995 @@
+use std::{collections::HashSet, fmt, fs, path::Path, str::FromStr};
+
+use crate::profiler::utils::PAGE_SIZE;
+use crate::{Bridge, DramGeneration};
+
+/// Physical address bits considered when describing rows; enough for 1 TiB of memory.
+pub(crate) const MAX_PHYS_BITS: u32 = 40;
+/// Physical address bits selecting the column (including the byte offset) in the presets.
+const COLUMN_BITS: u32 = 13;
+/// Physical address bits selecting the byte within a cache line.
+const CACHE_LINE_MASK: u64 = 0x3f;
+/// Physical address bits selecting the cache line within a page.
+const PAGE_LINE_MASK: u64 = (PAGE_SIZE as u64 - 1) & !CACHE_LINE_MASK;
+
+/// Describes how the memory controller maps physical addresses onto DRAM.
+///
+/// All functions take a physical address and return the corresponding DRAM coordinate.
+pub(crate) trait DramMapping {
+    /// The memory channel which `phys_addr` maps to.
+    fn channel(&self, phys_addr: u64) -> u8;
+    /// The DIMM within a channel which `phys_addr` maps to.
+    fn dimm(&self, _phys_addr: u64) -> u8 {
+        0
+    }
+    /// The rank within a DIMM which `phys_addr` maps to.
+    fn rank(&self, phys_addr: u64) -> u8;
+    /// The bank group within a rank which `phys_addr` maps to (always 0 before DDR4).
+    fn bank_group(&self, _phys_addr: u64) -> u8 {
+        0
+    }
+    /// The bank within a bank group which `phys_addr` maps to.
+    fn bank(&self, phys_addr: u64) -> u8;
+    /// The row within a bank which `phys_addr` maps to.
+    fn row(&self, phys_addr: u64) -> usize;
+    /// The column within a row which `phys_addr` maps to, including the byte offset.
+    fn column(&self, phys_addr: u64) -> usize;
+
+    /// The row number `distance` rows away from `row` on the chips of `rank`. This is simply
+    /// `row + distance` unless the rank mirrors or inverts its row address bits.
+    fn adjacent_row(&self, row: usize, _rank: u8, distance: isize) -> Option<usize> {
+        row.checked_add_signed(distance)
+    }
+
+    /// The number of bytes of physical memory sharing one row number, across all banks.
+    fn row_size(&self) -> usize;
+    /// The number of DIMMs in each channel.
+    fn dimms(&self) -> u8 {
+        1
+    }
+    /// The number of ranks on each DIMM.
+    fn ranks(&self) -> u8;
+    /// The number of bank groups in each rank.
+    fn bank_groups(&self) -> u8 {
+        1
+    }
+    /// The number of banks in each bank group.
+    fn banks(&self) -> u8;
+
+    /// Combines channel, DIMM, rank, bank group and bank into a single index, unique for every
+    /// bank in the system.
+    fn bank_index(&self, phys_addr: u64) -> u8 {
+        let dimm = self.channel(phys_addr) * self.dimms() + self.dimm(phys_addr);
+        let rank = dimm * self.ranks() + self.rank(phys_addr);
+        let bank_group = rank * self.bank_groups() + self.bank_group(phys_addr);
+        bank_group * self.banks() + self.bank(phys_addr)
+    }
+
+    /// Decodes every DRAM coordinate of `phys_addr`.
+    fn decode(&self, phys_addr: u64) -> DramAddress {
+        DramAddress {
+            channel: self.channel(phys_addr),
+            dimm: self.dimm(phys_addr),
+            rank: self.rank(phys_addr),
+            bank_group: self.bank_group(phys_addr),
+            bank: self.bank(phys_addr),
+            row: self.row(phys_addr),
+            column: self.column(phys_addr),
+        }
+    }
+}
+
+/// The location of a physical address in DRAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct DramAddress {
+    pub(crate) channel: u8,
+    pub(crate) dimm: u8,
+    pub(crate) rank: u8,
+    pub(crate) bank_group: u8,
+    pub(crate) bank: u8,
+    pub(crate) row: usize,
+    pub(crate) column: usize,
+}
+
+impl DramAddress {
+    /// Formats the coordinates identifying the bank, leaving out row and column.
+    pub(crate) fn bank_string(&self) -> String {
+        format!(
+            "ch{}/dimm{}/rank{}/bg{}/bank{}",
+            self.channel, self.dimm, self.rank, self.bank_group, self.bank
+        )
+    }
+}
+
+/// Formats the address as a single whitespace free token, e.g.
+/// `ch0/dimm0/rank1/bg0/bank5/row0x1a2b/col0x3c0`.
+impl fmt::Display for DramAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/row{:#x}/col{:#x}",
+            self.bank_string(),
+            self.row,
+            self.column
+        )
+    }
+}
+
+/// A mapping where every channel, DIMM, rank, bank group and bank bit is the XOR (parity) of a
+/// set of physical address bits, which is the case for all Intel and AMD memory controllers we
+/// know of.
+#[derive(Clone, Debug)]
+pub(crate) struct XorMapping {
+    /// One mask per bank bit, least significant bit first
+    pub(crate) bank_masks: Vec<u64>,
+    /// One mask per bank group bit, least significant bit first
+    pub(crate) bank_group_masks: Vec<u64>,
+    /// One mask per rank bit, least significant bit first
+    pub(crate) rank_masks: Vec<u64>,
+    /// One mask per DIMM bit, least significant bit first
+    pub(crate) dimm_masks: Vec<u64>,
+    /// One mask per channel bit, least significant bit first
+    pub(crate) channel_masks: Vec<u64>,
+    /// The physical address bits selecting the row
+    pub(crate) row_mask: u64,
+    /// The physical address bits selecting the column (including the byte offset)
+    pub(crate) column_mask: u64,
+    pub(crate) row_size: usize,
+    /// Whether odd ranks have their address bits mirrored (A3-A4, A5-A6, A7-A8 and on DDR4
+    /// A11-A13 swapped), as on the back side of most unbuffered DIMMs
+    pub(crate) rank_mirroring: bool,
+    /// The row address bits inverted on odd ranks, as done by the register of DDR4 RDIMMs
+    pub(crate) rank_inversion: u64,
+}
+
+impl XorMapping {
+    /// Creates a mapping from lists of physical address bits, where each list forms one XOR
+    /// function. The first `bank_group_bits` of the bank address functions select the bank group
+    /// and the rest the bank within it.
+    ///
+    /// The lowest bit of each function is taken from the column, so the row starts above the 13
+    /// column bits and one bit per function.
+    pub(crate) fn from_bits(
+        bank_group_bits: usize,
+        bank_bits: &[&[u8]],
+        rank_bits: &[&[u8]],
+        dimm_bits: &[&[u8]],
+        channel_bits: &[&[u8]],
+    ) -> Self {
+        let masks = |bits: &[&[u8]]| bits.iter().map(|bits| bits_to_mask(bits)).collect();
+        let (bank_group_bits, bank_bits) = bank_bits.split_at(bank_group_bits);
+        let mut mapping = Self {
+            bank_masks: masks(bank_bits),
+            bank_group_masks: masks(bank_group_bits),
+            rank_masks: masks(rank_bits),
+            dimm_masks: masks(dimm_bits),
+            channel_masks: masks(channel_bits),
+            row_mask: 0,
+            column_mask: 0,
+            row_size: 0,
+            rank_mirroring: false,
+            rank_inversion: 0,
+        };
+        let functions = mapping.functions();
+        let row_shift = COLUMN_BITS + functions.len() as u32;
+        let lowest_bits = functions
+            .iter()
+            .fold(0, |bits, (_, mask)| bits | 1 << mask.trailing_zeros());
+        mapping.row_size = 1 << row_shift;
+        mapping.row_mask = row_mask_from_size(mapping.row_size);
+        mapping.column_mask = ((1 << row_shift) - 1) & !lowest_bits;
+        mapping
+    }
+
+    /// Swaps the row address bits which are mirrored on odd ranks, if the mapping has any.
+    fn mirror_row(&self, mut row: usize) -> usize {
+        if !self.rank_mirroring {
+            return row;
+        }
+        let ddr4_pairs: &[(u32, u32)] = if self.bank_group_masks.is_empty() {
+            &[]
+        } else {
+            &[(11, 13)]
+        };
+        for (a, b) in [(3, 4), (5, 6), (7, 8)].iter().chain(ddr4_pairs) {
+            if (row >> a ^ row >> b) & 1 != 0 {
+                row ^= 1 << a | 1 << b;
+            }
+        }
+        row
+    }
+
+    /// Lists every XOR function in the mapping together with what it selects.
+    pub(crate) fn functions(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("bank", &self.bank_masks),
+            ("bank_group", &self.bank_group_masks),
+            ("rank", &self.rank_masks),
+            ("dimm", &self.dimm_masks),
+            ("channel", &self.channel_masks),
+        ]
+        .into_iter()
+        .flat_map(|(kind, masks)| masks.iter().map(move |mask| (kind, *mask)))
+        .collect()
+    }
+
+    /// Checks that the mapping describes a one-to-one mapping of physical addresses onto DRAM
+    /// coordinates.
+    ///
+    /// # Returns
+    /// A description of the first problem found, if any.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let address_bits = (1 << MAX_PHYS_BITS) - 1;
+        let functions = self.functions();
+        if self.bank_masks.is_empty() {
+            return Err("at least one `bank` function has to be given".to_string());
+        }
+        // The bank index has to fit in a u8
+        if functions.len() > 8 {
+            return Err(format!(
+                "{} functions given, at most 8 are supported",
+                functions.len()
+            ));
+        }
+        let mut basis = Vec::new();
+        for (key, mask) in &functions {
+            if *mask == 0 || mask & !address_bits != 0 {
+                return Err(format!(
+                    "{} function {:#x} has to use bits between 0 and {}",
+                    key,
+                    mask,
+                    MAX_PHYS_BITS - 1
+                ));
+            }
+            if mask & !CACHE_LINE_MASK == 0 {
+                return Err(format!(
+                    "{} function {:#x} only uses bits within a cache line",
+                    key, mask
+                ));
+            }
+            if !insert_into_basis(&mut basis, *mask) {
+                return Err(format!(
+                    "{} function {:#x} is a combination of the functions before it",
+                    key, mask
+                ));
+            }
+        }
+        if self.rank_inversion != 0 && self.rank_masks.is_empty() {
+            return Err("`rank_inversion` given without any `rank` function".to_string());
+        }
+        if !self.row_size.is_power_of_two() {
+            return Err(format!(
+                "row_size {:#x} isn't a power of two",
+                self.row_size
+            ));
+        }
+        if self.row_size != 1 << self.row_mask.trailing_zeros() {
+            return Err(format!(
+                "row_size {:#x} doesn't match the lowest row bit of {:#x}",
+                self.row_size, self.row_mask
+            ));
+        }
+        if self.row_mask & !address_bits != 0 {
+            return Err(format!(
+                "row {:#x} has to use bits between 0 and {}",
+                self.row_mask,
+                MAX_PHYS_BITS - 1
+            ));
+        }
+        if self.row_mask & self.column_mask != 0 {
+            return Err(format!(
+                "row {:#x} and column {:#x} share bits",
+                self.row_mask, self.column_mask
+            ));
+        }
+        if self.column_mask & CACHE_LINE_MASK != CACHE_LINE_MASK {
+            return Err(format!(
+                "column {:#x} has to include the cache line offset {:#x}",
+                self.column_mask, CACHE_LINE_MASK
+            ));
+        }
+        // Every address bit below the row has to be a column bit or be decided by a function
+        let row_shift = self.row_mask.trailing_zeros();
+        let column_bits = self.column_mask.count_ones();
+        if column_bits + functions.len() as u32 != row_shift {
+            return Err(format!(
+                "{} column bits and {} functions don't describe the {} bits below the row",
+                column_bits,
+                functions.len(),
+                row_shift
+            ));
+        }
+        if self.row_size < PAGE_SIZE {
+            return Err(format!(
+                "row_size {:#x} is smaller than a page ({:#x})",
+                self.row_size, PAGE_SIZE
+            ));
+        }
+        // The profiler hammers with lines from two different pages of a row in the same bank. The
+        // functions which the line within a page can't change are decided by the page number
+        // within the row, and the page number bits left over tell the pages of a bank apart.
+        let mut line_basis = Vec::new();
+        let line_functions = functions
+            .iter()
+            .filter(|(_, mask)| insert_into_basis(&mut line_basis, mask & PAGE_LINE_MASK))
+            .count();
+        let page_bits = row_shift - PAGE_SIZE.trailing_zeros();
+        if page_bits + (line_functions as u32) < functions.len() as u32 + 1 {
+            return Err(format!(
+                "every bank only has lines in one page of each {:#x} byte row, at least two are \
+                 needed",
+                self.row_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Loads a mapping description, as written by the `reverse-map` subcommand, from `path`.
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?
+            .parse()
+    }
+}
+
+/// Writes the mapping as a description file which can be loaded with `XorMapping::from_file`.
+impl fmt::Display for XorMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, mask) in self.functions() {
+            writeln!(f, "{} = {:#x}", key, mask)?;
+        }
+        writeln!(f, "row = {:#x}", self.row_mask)?;
+        writeln!(f, "column = {:#x}", self.column_mask)?;
+        if self.rank_mirroring {
+            writeln!(f, "rank_mirroring = 1")?;
+        }
+        if self.rank_inversion != 0 {
+            writeln!(f, "rank_inversion = {:#x}", self.rank_inversion)?;
+        }
+        writeln!(f, "row_size = {:#x}", self.row_size)
+    }
+}
+
+/// Parses a mapping description. Every line is either empty, a comment starting with `#` or a
+/// `key = value` pair, where the function keys (bank, bank_group, rank, dimm and channel) may be
+/// repeated once per function.
+impl FromStr for XorMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mapping = Self {
+            bank_masks: Vec::new(),
+            bank_group_masks: Vec::new(),
+            rank_masks: Vec::new(),
+            dimm_masks: Vec::new(),
+            channel_masks: Vec::new(),
+            row_mask: 0,
+            column_mask: 0,
+            row_size: 0,
+            rank_mirroring: false,
+            rank_inversion: 0,
+        };
+        let mut seen = HashSet::new();
+        for (line_number, line) in s.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `key = value`", line_number));
+            };
+            let value = parse_number(value.trim()).ok_or_else(|| {
+                format!("line {}: invalid number `{}`", line_number, value.trim())
+            })?;
+            let key = key.trim();
+            if !matches!(key, "bank" | "bank_group" | "rank" | "dimm" | "channel")
+                && !seen.insert(key.to_string())
+            {
+                return Err(format!("line {}: `{}` is given twice", line_number, key));
+            }
+            match key {
+                "bank" => mapping.bank_masks.push(value),
+                "bank_group" => mapping.bank_group_masks.push(value),
+                "rank" => mapping.rank_masks.push(value),
+                "dimm" => mapping.dimm_masks.push(value),
+                "channel" => mapping.channel_masks.push(value),
+                "row" => mapping.row_mask = value,
+                "column" => mapping.column_mask = value,
+                "row_size" => mapping.row_size = value as usize,
+                "rank_mirroring" => mapping.rank_mirroring = value != 0,
+                "rank_inversion" => mapping.rank_inversion = value,
+                key => return Err(format!("line {}: unknown key `{}`", line_number, key)),
+            }
+        }
+        if mapping.row_size == 0 {
+            if mapping.row_mask == 0 {
+                return Err("either `row` or `row_size` has to be given".to_string());
+            }
+            mapping.row_size = 1 << mapping.row_mask.trailing_zeros();
+        }
+        if mapping.row_mask == 0 {
+            mapping.row_mask = row_mask_from_size(mapping.row_size);
+        }
+        mapping.validate()?;
+        Ok(mapping)
+    }
+}
+
+impl DramMapping for XorMapping {
+    fn channel(&self, phys_addr: u64) -> u8 {
+        apply_xor_functions(&self.channel_masks, phys_addr)
+    }
+
+    fn dimm(&self, phys_addr: u64) -> u8 {
+        apply_xor_functions(&self.dimm_masks, phys_addr)
+    }
+
+    fn rank(&self, phys_addr: u64) -> u8 {
+        apply_xor_functions(&self.rank_masks, phys_addr)
+    }
+
+    fn bank_group(&self, phys_addr: u64) -> u8 {
+        apply_xor_functions(&self.bank_group_masks, phys_addr)
+    }
+
+    fn bank(&self, phys_addr: u64) -> u8 {
+        apply_xor_functions(&self.bank_masks, phys_addr)
+    }
+
+    fn row(&self, phys_addr: u64) -> usize {
+        extract_bits(phys_addr, self.row_mask) as usize
+    }
+
+    fn column(&self, phys_addr: u64) -> usize {
+        extract_bits(phys_addr, self.column_mask) as usize
+    }
+
+    fn adjacent_row(&self, row: usize, rank: u8, distance: isize) -> Option<usize> {
+        if rank & 1 == 0 {
+            return row.checked_add_signed(distance);
+        }
+        let inversion = self.rank_inversion as usize;
+        let chip_row = (self.mirror_row(row) ^ inversion).checked_add_signed(distance)?;
+        Some(self.mirror_row(chip_row ^ inversion))
+    }
+
+    fn row_size(&self) -> usize {
+        self.row_size
+    }
+
+    fn dimms(&self) -> u8 {
+        1 << self.dimm_masks.len()
+    }
+
+    fn ranks(&self) -> u8 {
+        1 << self.rank_masks.len()
+    }
+
+    fn bank_groups(&self) -> u8 {
+        1 << self.bank_group_masks.len()
+    }
+
+    fn banks(&self) -> u8 {
+        1 << self.bank_masks.len()
+    }
+}
+
+/// Converts a list of bit positions to a bitmask.
+pub(crate) fn bits_to_mask(bits: &[u8]) -> u64 {
+    bits.iter().fold(0, |mask, bit| mask | 1 << bit)
+}
+
+/// Converts a bitmask to the list of bit positions set in it.
+pub(crate) fn mask_to_bits(mask: u64) -> Vec<u8> {
+    (0..64).filter(|bit| mask & 1 << bit != 0).collect()
+}
+
+/// Inserts `mask` into the GF(2) `basis` if it is linearly independent of it.
+///
+/// # Returns
+/// `true` if `mask` was inserted.
+pub(crate) fn insert_into_basis(basis: &mut Vec<u64>, mut mask: u64) -> bool {
+    for b in basis.iter() {
+        mask = mask.min(mask ^ b);
+    }
+    if mask == 0 {
+        return false;
+    }
+    basis.push(mask);
+    basis.sort_unstable_by(|a, b| b.cmp(a));
+    true
+}
+
+/// Returns a mask of all address bits from `row_size` up to `MAX_PHYS_BITS`.
+pub(crate) fn row_mask_from_size(row_size: usize) -> u64 {
+    !(row_size as u64 - 1) & ((1 << MAX_PHYS_BITS) - 1)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Gathers the bits of `value` selected by `mask` into the lowest bits of the result, keeping
+/// their order (like the `pext` instruction).
+pub(crate) fn extract_bits(value: u64, mask: u64) -> u64 {
+    mask_to_bits(mask)
+        .iter()
+        .enumerate()
+        .fold(0, |out, (i, bit)| out | (value >> bit & 1) << i)
+}
+
+/// Evaluates the XOR function given by `mask` on `phys_addr`.
+pub(crate) fn xor_function(mask: u64, phys_addr: u64) -> u8 {
+    ((phys_addr & mask).count_ones() & 1) as u8
+}
+
+/// Evaluates every function in `masks` and concatenates the results, the first function giving
+/// the least significant bit.
+fn apply_xor_functions(masks: &[u64], phys_addr: u64) -> u8 {
+    masks.iter().enumerate().fold(0, |out, (i, mask)| {
+        out | xor_function(*mask, phys_addr) << i
+    })
+}
+
+/// The XOR functions of a preset mapping, as lists of physical address bits.
+struct Preset {
+    /// Bank address functions, the bank group bits first on DDR4
+    bank: &'static [&'static [u8]],
+    rank: &'static [&'static [u8]],
+    dimm: &'static [&'static [u8]],
+    channel: &'static [&'static [u8]],
+}
+
+/// Channel function of the dual channel Ivy Bridge and Haswell controllers.
+const HASWELL_CHANNEL: &[u8] = &[7, 8, 9, 12, 13, 18, 19];
+/// Channel function of the dual channel Skylake and Coffee Lake controllers.
+const SKYLAKE_CHANNEL: &[u8] = &[8, 9, 12, 13, 18, 19];
+/// Channel function of the dual channel Zen 2 controller (256 byte interleaving).
+const ZEN_CHANNEL: &[u8] = &[8, 12, 13, 18, 19, 22, 23];
+
+/// Returns the preset for `bridge` with `dimms` RAM sticks installed, or `None` if we don't know
+/// the mapping of that configuration.
+///
+/// One DIMM means a single channel, two DIMMs one DIMM in each of two channels and four DIMMs
+/// two DIMMs in each of two channels.
+fn get_preset(bridge: Bridge, dimms: u8) -> Option<Preset> {
+    let preset = match (bridge, dimms) {
+        (Bridge::Sandy, 1) => Preset {
+            bank: &[&[13, 17], &[14, 18], &[15, 19]],
+            rank: &[&[16]],
+            dimm: &[],
+            channel: &[],
+        },
+        (Bridge::Sandy, 2) => Preset {
+            bank: &[&[14, 18], &[15, 19], &[16, 20]],
+            rank: &[&[17, 21]],
+            dimm: &[],
+            channel: &[&[6]],
+        },
+        (Bridge::Sandy, 4) => Preset {
+            bank: &[&[15, 19], &[16, 20], &[17, 21]],
+            rank: &[&[18, 22]],
+            dimm: &[&[14]],
+            channel: &[&[6]],
+        },
+        (Bridge::Ivy | Bridge::Haswell, 1) => Preset {
+            bank: &[&[13, 17], &[14, 18], &[16, 20]],
+            rank: &[&[15, 19]],
+            dimm: &[],
+            channel: &[],
+        },
+        (Bridge::Ivy | Bridge::Haswell, 2) => Preset {
+            bank: &[&[14, 18], &[15, 19], &[16, 20]],
+            rank: &[&[17, 21]],
+            dimm: &[],
+            channel: &[HASWELL_CHANNEL],
+        },
+        (Bridge::Ivy | Bridge::Haswell, 4) => Preset {
+            bank: &[&[16, 20], &[17, 21], &[18, 22]],
+            rank: &[&[15, 19]],
+            dimm: &[&[14]],
+            channel: &[HASWELL_CHANNEL],
+        },
+        // Coffee Lake uses the same memory controller as Skylake
+        (Bridge::Skylake | Bridge::CoffeeLake, 1) => Preset {
+            bank: &[&[6, 13], &[14, 18], &[15, 19], &[16, 20]],
+            rank: &[&[17, 21]],
+            dimm: &[],
+            channel: &[],
+        },
+        (Bridge::Skylake | Bridge::CoffeeLake, 2) => Preset {
+            bank: &[&[7, 14], &[15, 19], &[16, 20], &[17, 21]],
+            rank: &[&[18, 22]],
+            dimm: &[],
+            channel: &[SKYLAKE_CHANNEL],
+        },
+        (Bridge::Skylake | Bridge::CoffeeLake, 4) => Preset {
+            bank: &[&[7, 14], &[15, 20], &[16, 21], &[17, 22]],
+            rank: &[&[18, 23]],
+            dimm: &[&[19, 24]],
+            channel: &[SKYLAKE_CHANNEL],
+        },
+        (Bridge::Zen, 1) => Preset {
+            bank: &[&[13, 17], &[14, 18], &[15, 19], &[16, 20]],
+            rank: &[&[17, 21]],
+            dimm: &[],
+            channel: &[],
+        },
+        (Bridge::Zen, 2) => Preset {
+            bank: &[&[14, 18], &[15, 19], &[16, 20], &[17, 21]],
+            rank: &[&[18, 22]],
+            dimm: &[],
+            channel: &[ZEN_CHANNEL],
+        },
+        (Bridge::Zen, 4) => Preset {
+            bank: &[&[14, 19], &[15, 20], &[16, 21], &[17, 22]],
+            rank: &[&[18, 23]],
+            dimm: &[&[19, 24]],
+            channel: &[ZEN_CHANNEL],
+        },
+        _ => return None,
+    };
+    Some(preset)
+}
+
+/// The number of DIMMs of the preset with `dimms_per_channel` DIMMs in each channel, fewest
+/// first, or `None` if there is no preset with that layout.
+pub(crate) fn preset_dimms(dimms_per_channel: &[u8]) -> Option<u8> {
+    match dimms_per_channel {
+        [1] => Some(1),
+        [1, 1] => Some(2),
+        [2, 2] => Some(4),
+        _ => None,
+    }
+}
+
+impl DramGeneration {
+    /// The generation of the DIMMs supported by `bridge`.
+    pub(crate) fn default_for(bridge: Bridge) -> Self {
+        match bridge {
+            Bridge::Sandy | Bridge::Ivy | Bridge::Haswell => DramGeneration::Ddr3,
+            Bridge::Skylake | Bridge::CoffeeLake | Bridge::Zen => DramGeneration::Ddr4,
+        }
+    }
+
+    /// The number of banks in each rank, across all bank groups.
+    pub(crate) fn banks(self) -> u8 {
+        match self {
+            DramGeneration::Ddr3 => 8,
+            DramGeneration::Ddr4 => 16,
+        }
+    }
+
+    /// The number of bank groups in each rank.
+    pub(crate) fn bank_groups(self) -> u8 {
+        match self {
+            DramGeneration::Ddr3 => 1,
+            DramGeneration::Ddr4 => 4,
+        }
+    }
+
+    /// Checks that `mapping` has the bank layout of this generation.
+    pub(crate) fn check(self, mapping: &dyn DramMapping) -> Result<(), String> {
+        let banks = mapping.bank_groups() as usize * mapping.banks() as usize;
+        if mapping.bank_groups() != self.bank_groups() || banks != self.banks() as usize {
+            return Err(format!(
+                "{:?} has {} bank groups of {} banks, but the mapping has {} bank groups of {} banks",
+                self,
+                self.bank_groups(),
+                self.banks() / self.bank_groups(),
+                mapping.bank_groups(),
+                mapping.banks(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the DRAM mapping for `bridge` with `dimms` RAM sticks of the `generation` installed,
+/// where `None` means the generation the bridge normally uses.
+///
+/// The Intel functions are the ones reverse engineered in the DRAMA paper (Pessl et al.) and
+/// later work on DDR4 (TRRespass, Blacksmith), the AMD functions the ones from ZenHammer. They
+/// assume dual rank DIMMs and the default BIOS settings, so run `verify-mapping` before trusting
+/// them on a new machine.
+pub(crate) fn get_mapping(
+    bridge: Bridge,
+    generation: Option<DramGeneration>,
+    dimms: u8,
+) -> Result<XorMapping, String> {
+    let generation = generation.unwrap_or(DramGeneration::default_for(bridge));
+    let preset = get_preset(bridge, dimms).ok_or_else(|| {
+        format!(
+            "no known mapping for {:?} with {} DIMMs (use 1, 2 or 4)",
+            bridge, dimms
+        )
+    })?;
+    if 1 << preset.bank.len() != generation.banks() {
+        return Err(format!(
+            "no known {:?} mapping for {:?}",
+            generation, bridge
+        ));
+    }
+    Ok(XorMapping::from_bits(
+        generation.bank_groups().trailing_zeros() as usize,
+        preset.bank,
+        preset.rank,
+        preset.dimm,
+        preset.channel,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An address with bits set all over the bank, rank and row functions.
+    const ADDR: u64 = 0x3_5a5c_3040;
+
+    #[test]
+    fn presets() {
+        // bridge, DIMMs, row size, and the bank group, bank, rank and row of ADDR
+        let cases = [
+            (Bridge::Sandy, 1, 0x20000, 0, 7, 0, 0x1ad2e),
+            (Bridge::Sandy, 2, 0x40000, 0, 7, 0, 0xd697),
+            (Bridge::Sandy, 4, 0x80000, 0, 3, 0, 0x6b4b),
+            (Bridge::Ivy, 1, 0x20000, 0, 7, 1, 0x1ad2e),
+            (Bridge::Ivy, 2, 0x40000, 0, 7, 0, 0xd697),
+            (Bridge::Ivy, 4, 0x80000, 0, 1, 1, 0x6b4b),
+            (Bridge::Haswell, 1, 0x20000, 0, 7, 1, 0x1ad2e),
+            (Bridge::Haswell, 2, 0x40000, 0, 7, 0, 0xd697),
+            (Bridge::Haswell, 4, 0x80000, 0, 1, 1, 0x6b4b),
+            (Bridge::Skylake, 1, 0x40000, 2, 3, 0, 0xd697),
+            (Bridge::Skylake, 2, 0x80000, 2, 1, 0, 0x6b4b),
+            (Bridge::Skylake, 4, 0x100000, 2, 2, 1, 0x35a5),
+            (Bridge::CoffeeLake, 1, 0x40000, 2, 3, 0, 0xd697),
+            (Bridge::CoffeeLake, 2, 0x80000, 2, 1, 0, 0x6b4b),
+            (Bridge::CoffeeLake, 4, 0x100000, 2, 2, 1, 0x35a5),
+            (Bridge::Zen, 1, 0x40000, 3, 3, 0, 0xd697),
+            (Bridge::Zen, 2, 0x80000, 3, 1, 0, 0x6b4b),
+            (Bridge::Zen, 4, 0x100000, 3, 2, 1, 0x35a5),
+        ];
+        for (bridge, dimms, row_size, bank_group, bank, rank, row) in cases {
+            let mapping = get_mapping(bridge, None, dimms).unwrap();
+            let name = format!("{:?} with {} DIMMs", bridge, dimms);
+            mapping
+                .validate()
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            DramGeneration::default_for(bridge).check(&mapping).unwrap();
+            assert_eq!(mapping.row_size(), row_size, "{}", name);
+            assert_eq!(mapping.dimms(), dimms.max(2) / 2, "{}", name);
+
+            let addr = mapping.decode(ADDR);
+            assert_eq!(
+                (addr.bank_group, addr.bank, addr.rank, addr.row),
+                (bank_group, bank, rank, row),
+                "{}",
+                name
+            );
+            // The whole row size shares one row number
+            let row_size = row_size as u64;
+            assert_eq!(mapping.row(ADDR / row_size * row_size), row, "{}", name);
+            assert_eq!(mapping.row(ADDR + row_size), row + 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn haswell_single_dimm_bits() {
+        let mapping = get_mapping(Bridge::Haswell, Some(DramGeneration::Ddr3), 1).unwrap();
+        let cases = [
+            // address, bank, rank, row
+            (0, 0, 0, 0),
+            // Bit 13 is the lowest bit of the first bank function, not a column bit
+            (1 << 13, 1, 0, 0),
+            (1 << 13 | 1 << 17, 0, 0, 1),
+            (1 << 16, 4, 0, 0),
+            (1 << 15, 0, 1, 0),
+            (1 << 19, 0, 1, 4),
+            (0x1fff, 0, 0, 0),
+        ];
+        for (phys_addr, bank, rank, row) in cases {
+            assert_eq!(mapping.bank(phys_addr), bank, "{:#x}", phys_addr);
+            assert_eq!(mapping.rank(phys_addr), rank, "{:#x}", phys_addr);
+            assert_eq!(mapping.row(phys_addr), row, "{:#x}", phys_addr);
+        }
+        assert_eq!(mapping.column(1 << 13), 0);
+        assert_eq!(mapping.column(0x1fff), 0x1fff);
+    }
+
+    /// The single DIMM Haswell preset as a description file.
+    const HASWELL_1: &str = "\
+        # Haswell, one DIMM
+        bank = 0x22000
+        bank = 0x44000
+        bank = 0x110000
+        rank = 0x88000
+        column = 0x1fff
+        row_size = 0x20000
+    ";
+
+    #[test]
+    fn parses_descriptions() {
+        let mapping = HASWELL_1.parse::<XorMapping>().unwrap();
+        let preset = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        assert_eq!(mapping.to_string(), preset.to_string());
+        assert_eq!(mapping.row_mask, 0xff_fffe_0000);
+
+        // The row can be given instead of the row size, and the other way around
+        let by_row = HASWELL_1.replace("row_size = 0x20000", "row = 0xfffe0000");
+        let by_row = by_row.parse::<XorMapping>().unwrap();
+        assert_eq!((by_row.row_size, by_row.row_mask), (0x20000, 0xfffe_0000));
+
+        let flags = format!("{}rank_mirroring = 1\nrank_inversion = 0x1f8", HASWELL_1);
+        let flags = flags.parse::<XorMapping>().unwrap();
+        assert!(flags.rank_mirroring);
+        assert_eq!(flags.rank_inversion, 0x1f8);
+    }
+
+    #[test]
+    fn descriptions_round_trip() {
+        for bridge in [Bridge::Sandy, Bridge::Haswell, Bridge::Skylake, Bridge::Zen] {
+            for dimms in [1, 2, 4] {
+                let mut preset = get_mapping(bridge, None, dimms).unwrap();
+                preset.rank_mirroring = true;
+                let parsed = preset.to_string().parse::<XorMapping>().unwrap();
+                assert_eq!(parsed.to_string(), preset.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_descriptions() {
+        let cases = [
+            ("bank 0x22000", "line 1: expected `key = value`"),
+            ("bank = 0xzz", "line 1: invalid number `0xzz`"),
+            ("\n# comment\ncolour = 1", "line 3: unknown key `colour`"),
+            (
+                &format!("{}row_size = 0x20000", HASWELL_1),
+                "line 8: `row_size` is given twice",
+            ),
+            (
+                "bank = 0x22000",
+                "either `row` or `row_size` has to be given",
+            ),
+            (
+                "rank = 0x2000\ncolumn = 0x1fff\nrow_size = 0x4000",
+                "at least one `bank` function has to be given",
+            ),
+            (
+                "bank = 0x2000\nbank = 0x4000\nbank = 0x8000\nbank = 0x10000\nbank = 0x20000\n\
+                 bank = 0x40000\nbank = 0x80000\nbank = 0x100000\nbank = 0x200000\n\
+                 row_size = 0x400000",
+                "9 functions given, at most 8 are supported",
+            ),
+            (
+                "bank = 0\nrow_size = 0x4000",
+                "bank function 0x0 has to use bits",
+            ),
+            (
+                "bank = 0x10000000000\nrow_size = 0x4000",
+                "bank function 0x10000000000 has to use bits between 0 and 39",
+            ),
+            (
+                "bank = 0x30\nrow_size = 0x4000",
+                "bank function 0x30 only uses bits within a cache line",
+            ),
+            (
+                &HASWELL_1.replace("rank = 0x88000", "rank = 0x66000"),
+                "rank function 0x66000 is a combination of the functions before it",
+            ),
+            (
+                &format!("{}rank_inversion = 0x1f8", HASWELL_1.replace("rank", "#")),
+                "`rank_inversion` given without any `rank` function",
+            ),
+            (
+                &HASWELL_1.replace("row_size = 0x20000", "row_size = 0x30000"),
+                "row_size 0x30000 isn't a power of two",
+            ),
+            (
+                &format!("{}row = 0xfffc0000", HASWELL_1),
+                "row_size 0x20000 doesn't match the lowest row bit of 0xfffc0000",
+            ),
+            (
+                &HASWELL_1.replace("column = 0x1fff", "column = 0x21fff"),
+                "row 0xfffffe0000 and column 0x21fff share bits",
+            ),
+            (
+                &HASWELL_1.replace("column = 0x1fff", "column = 0x1fc0"),
+                "column 0x1fc0 has to include the cache line offset 0x3f",
+            ),
+            (
+                &HASWELL_1.replace("column = 0x1fff", "column = 0xfff"),
+                "12 column bits and 4 functions don't describe the 17 bits below the row",
+            ),
+            (
+                "bank = 0x440\ncolumn = 0x7bf\nrow_size = 0x800",
+                "row_size 0x800 is smaller than a page (0x1000)",
+            ),
+            // Lines of the same bank are in one page per row, which leaves no pair to hammer
+            (
+                "bank = 0x1000\nbank = 0x2000\ncolumn = 0xfff\nrow_size = 0x4000",
+                "every bank only has lines in one page of each 0x4000 byte row",
+            ),
+        ];
+        for (description, error) in cases {
+            let result = description.parse::<XorMapping>().unwrap_err();
+            assert!(result.starts_with(error), "{:?}: {}", description, result);
+        }
+
+        // A function using a line bit lets two pages of a row share the bank again
+        "bank = 0x1040\nbank = 0x2000\ncolumn = 0x1fbf\nrow_size = 0x4000"
+            .parse::<XorMapping>()
+            .unwrap();
+    }
+
+    #[test]
+    fn adjacent_rows_on_odd_ranks() {
+        let plain = get_mapping(Bridge::Haswell, Some(DramGeneration::Ddr3), 1).unwrap();
+        let mut mirrored = plain.clone();
+        mirrored.rank_mirroring = true;
+        let mut inverted = plain.clone();
+        inverted.rank_inversion = 0x1f8;
+        let mut both = mirrored.clone();
+        both.rank_inversion = 0x1f8;
+        let mut ddr4 = get_mapping(Bridge::Skylake, Some(DramGeneration::Ddr4), 1).unwrap();
+        ddr4.rank_mirroring = true;
+
+        // mapping, row, rank, distance, adjacent row
+        let cases = [
+            (&plain, 8, 1, -1, Some(7)),
+            (&plain, 8, 1, 1, Some(9)),
+            // Row 8 is chip row 16, whose neighbours 15 and 17 are rows 23 and 9
+            (&mirrored, 8, 0, -1, Some(7)),
+            (&mirrored, 8, 1, -1, Some(23)),
+            (&mirrored, 8, 1, 1, Some(9)),
+            (&mirrored, 0, 1, -1, None),
+            // Row 8 is chip row 0x1f0, whose neighbours 0x1ef and 0x1f1 are rows 23 and 9
+            (&inverted, 8, 0, 1, Some(9)),
+            (&inverted, 8, 1, -1, Some(23)),
+            (&inverted, 8, 1, 1, Some(9)),
+            // Row 0 is chip row 0x1f8, so it has a neighbour below
+            (&inverted, 0, 1, -1, Some(15)),
+            (&inverted, 0, 1, 1, Some(1)),
+            // Row 8 is chip row 0x1e8, whose neighbours 0x1e7 and 0x1e9 are rows 31 and 9
+            (&both, 8, 1, -1, Some(31)),
+            (&both, 8, 1, 1, Some(9)),
+            // DDR3 doesn't mirror bits 11 and 13, DDR4 does
+            (&mirrored, 0x800, 1, -1, Some(0x7ff)),
+            (&ddr4, 0x800, 1, -1, Some(0x37ff)),
+            (&ddr4, 0x800, 1, 1, Some(0x801)),
+        ];
+        for (i, (mapping, row, rank, distance, adjacent)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                mapping.adjacent_row(row, rank, distance),
+                adjacent,
+                "case {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn mirroring_rows_twice_is_identity() {
+        let mut mapping = get_mapping(Bridge::Skylake, Some(DramGeneration::Ddr4), 1).unwrap();
+        assert_eq!(mapping.mirror_row(0x8), 0x8);
+        mapping.rank_mirroring = true;
+        assert_eq!(mapping.mirror_row(0x8), 0x10);
+        assert_eq!(mapping.mirror_row(0xa8), 0x150);
+        assert_eq!(mapping.mirror_row(0x818), 0x2018);
+        for row in 0..0x4000 {
+            assert_eq!(mapping.mirror_row(mapping.mirror_row(row)), row);
+        }
+    }
+
+    #[test]
+    fn unknown_presets() {
+        assert!(get_mapping(Bridge::Haswell, None, 3).is_err());
+        assert!(get_mapping(Bridge::Zen, None, 8).is_err());
+        assert!(get_mapping(Bridge::Haswell, Some(DramGeneration::Ddr4), 2).is_err());
+        assert!(get_mapping(Bridge::Skylake, Some(DramGeneration::Ddr3), 1).is_err());
+    }
+}
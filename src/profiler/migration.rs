@@ -0,0 +1,217 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+};
+
+use memmap2::MmapMut;
+
+use crate::profiler::{
+    index::MemoryIndex,
+    pagemap::{pagemap_threads, read_entry, scan_pagemap, PagemapEntry},
+    utils::{Page, PAGE_SIZE},
+};
+
+/// Moved pages listed in a warning, the rest are only counted
+const MAX_LISTED_PAGES: usize = 10;
+
+/// A page which isn't in the page frame it was indexed with anymore, as compaction, NUMA
+/// balancing or KSM moved it (or it was swapped out).
+#[derive(Clone, Debug)]
+pub(crate) struct MovedPage {
+    pub(crate) virt_addr: *mut u8,
+    pub(crate) old_pfn: u64,
+    /// `None` if the page isn't in memory anymore
+    pub(crate) new_pfn: Option<u64>,
+}
+
+impl fmt::Display for MovedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.new_pfn {
+            Some(pfn) => write!(
+                f,
+                "{:?}: page frame {:#x} -> {:#x}",
+                self.virt_addr, self.old_pfn, pfn
+            ),
+            None => write!(
+                f,
+                "{:?}: page frame {:#x} -> not present",
+                self.virt_addr, self.old_pfn
+            ),
+        }
+    }
+}
+
+/// Compares the page frame `page` was indexed with to the one it is in now.
+///
+/// # Returns
+/// The move, or `None` if the page is still in the same page frame.
+fn moved_page(page: &Page, new_pfn: Option<u64>) -> Option<MovedPage> {
+    (new_pfn != Some(page.pfn)).then_some(MovedPage {
+        virt_addr: page.virt_addr,
+        old_pfn: page.pfn,
+        new_pfn,
+    })
+}
+
+/// Looks up the current page frame of every page in `pages` one by one, which is fast for the
+/// few rows being hammered.
+///
+/// # Returns
+/// The pages which moved since they were indexed.
+pub(crate) fn moved_pages<'a>(
+    pages: impl IntoIterator<Item = &'a Page>,
+) -> io::Result<Vec<MovedPage>> {
+    let pagemap = File::open("/proc/self/pagemap")?;
+    let mut moved = Vec::new();
+    for page in pages {
+        moved.extend(moved_page(
+            page,
+            read_entry(&pagemap, page.virt_addr)?.pfn(),
+        ));
+    }
+    Ok(moved)
+}
+
+/// Compares the page frames in `index` with the pagemap `entries` of the memory starting at
+/// `base`. Pages outside of `entries` are left out.
+///
+/// # Returns
+/// The indexed pages which moved since they were indexed.
+fn moved_pages_in_entries(
+    index: &MemoryIndex,
+    base: usize,
+    entries: &[PagemapEntry],
+) -> Vec<MovedPage> {
+    index
+        .pages()
+        .filter_map(|page| {
+            let entry = entries.get((page.virt_addr as usize).checked_sub(base)? / PAGE_SIZE)?;
+            moved_page(page, entry.pfn())
+        })
+        .collect()
+}
+
+/// Rescans the pagemap of all of `mmap` and compares it with the page frames in `index`.
+///
+/// # Returns
+/// The indexed pages which moved since they were indexed.
+pub(crate) fn moved_pages_in(mmap: &MmapMut, index: &MemoryIndex) -> io::Result<Vec<MovedPage>> {
+    let scan = scan_pagemap(mmap.as_ptr(), mmap.len(), pagemap_threads())?;
+    Ok(moved_pages_in_entries(
+        index,
+        mmap.as_ptr() as usize,
+        &scan.entries,
+    ))
+}
+
+/// Writes a warning about `moved` pages to `out`, as flips found in them can't be attributed to
+/// the right page frames.
+pub(crate) fn warn_moved_pages(
+    out: &mut impl Write,
+    moved: &[MovedPage],
+    context: &str,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "[!] {} pages moved to other page frames {}, re-indexing the mapping",
+        moved.len(),
+        context
+    )?;
+    for page in moved.iter().take(MAX_LISTED_PAGES) {
+        writeln!(out, "        {}", page)?;
+    }
+    if moved.len() > MAX_LISTED_PAGES {
+        writeln!(out, "        ...")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::mapping::get_mapping;
+    use crate::Bridge;
+
+    const PRESENT: u64 = 1 << 63;
+    const SWAPPED: u64 = 1 << 62;
+    const BASE: usize = 0x7f00_0000_0000;
+
+    fn page(i: usize, pfn: u64) -> Page {
+        Page::new((BASE + i * PAGE_SIZE) as *mut u8, pfn)
+    }
+
+    #[test]
+    fn finds_moved_pages() {
+        let mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        let mut index = MemoryIndex::new();
+        for (i, pfn) in [0x1000, 0x1001, 0x2000, 0x2001, 0x3000]
+            .into_iter()
+            .enumerate()
+        {
+            index.insert(page(i, pfn), &mapping);
+        }
+        // Only four pages were scanned, the last one is left out
+        let entries = [PRESENT | 0x1000, PRESENT | 0x1801, SWAPPED, 0].map(PagemapEntry::parse);
+        let mut moved = moved_pages_in_entries(&index, BASE, &entries)
+            .into_iter()
+            .map(|page| (page.old_pfn, page.new_pfn))
+            .collect::<Vec<_>>();
+        moved.sort();
+        assert_eq!(
+            moved,
+            [(0x1001, Some(0x1801)), (0x2000, None), (0x2001, None)]
+        );
+
+        let entries =
+            [0x1000, 0x1001, 0x2000, 0x2001, 0x3000].map(|pfn| PagemapEntry::parse(PRESENT | pfn));
+        assert!(moved_pages_in_entries(&index, BASE, &entries).is_empty());
+        // Pages below the scanned memory are left out as well
+        assert!(moved_pages_in_entries(&index, BASE + PAGE_SIZE, &entries[1..2]).is_empty());
+    }
+
+    #[test]
+    fn compares_page_frames() {
+        let cases = [
+            (Some(0x1000), None),
+            (Some(0x1001), Some(Some(0x1001))),
+            (None, Some(None)),
+        ];
+        for (new_pfn, expected) in cases {
+            let moved = moved_page(&page(0, 0x1000), new_pfn);
+            assert_eq!(moved.map(|page| page.new_pfn), expected);
+        }
+    }
+
+    #[test]
+    fn lists_a_few_moved_pages() {
+        let moved = (0..12)
+            .map(|i| MovedPage {
+                virt_addr: (BASE + i * PAGE_SIZE) as *mut u8,
+                old_pfn: 0x1000 + i as u64,
+                new_pfn: (i % 2 == 0).then_some(0x2000 + i as u64),
+            })
+            .collect::<Vec<_>>();
+        let cases = [
+            (0, 1),
+            (1, 2),
+            (MAX_LISTED_PAGES, MAX_LISTED_PAGES + 1),
+            (12, MAX_LISTED_PAGES + 2),
+        ];
+        for (count, lines) in cases {
+            let mut out = Vec::new();
+            warn_moved_pages(&mut out, &moved[..count], "while hammering").unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert_eq!(out.lines().count(), lines, "{}", out);
+            assert!(out.starts_with(&format!(
+                "[!] {} pages moved to other page frames while hammering",
+                count
+            )));
+        }
+        let mut out = Vec::new();
+        warn_moved_pages(&mut out, &moved[..2], "while hammering").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("page frame 0x1000 -> 0x2000"), "{}", out);
+        assert!(out.contains("page frame 0x1001 -> not present"), "{}", out);
+    }
+}
@@ -1,3 +1,20 @@
+pub(crate) mod allocator;
+pub(crate) mod budget;
+pub(crate) mod calibrate;
+pub(crate) mod detect;
+pub(crate) mod index;
+pub(crate) mod inferred;
+pub(crate) mod kpageflags;
+pub(crate) mod mapping;
+pub(crate) mod migration;
+pub(crate) mod numa;
 pub(crate) mod pagefinder;
+pub(crate) mod pagemap;
+pub(crate) mod pattern;
+pub(crate) mod pool;
+pub(crate) mod residency;
+pub(crate) mod reverse;
 pub(crate) mod rowhammer;
+pub(crate) mod timing;
 pub(crate) mod utils;
+pub(crate) mod verify;
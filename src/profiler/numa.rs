@@ -0,0 +1,158 @@
+use std::{fs, ptr};
+
+use nix::{
+    libc::{self, c_int, c_ulong},
+    sched::{sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
+
+/// Where the kernel lists the NUMA nodes and their CPUs
+const NODE_DIR: &str = "/sys/devices/system/node";
+/// Memory policy only allocating from the given nodes (see set_mempolicy(2))
+const MPOL_BIND: c_int = 2;
+/// `get_mempolicy` flags returning the node of the page at an address
+const MPOL_F_NODE: c_ulong = 1;
+const MPOL_F_ADDR: c_ulong = 2;
+
+/// Parses a list of CPUs or nodes like `0-3,8-11` or `0`, as used in sysfs.
+pub(crate) fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid CPU list `{}`", list.trim()))
+        };
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if last < first {
+                    return Err(format!("invalid CPU list `{}`", list.trim()));
+                }
+                cpus.extend(first..=last)
+            }
+            None => cpus.push(parse(range)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// The NUMA nodes which are online, just node 0 on kernels without NUMA support.
+pub(crate) fn online_nodes() -> Vec<usize> {
+    fs::read_to_string(format!("{}/online", NODE_DIR))
+        .ok()
+        .and_then(|online| parse_cpu_list(&online).ok())
+        .unwrap_or_else(|| vec![0])
+}
+
+/// The CPUs of NUMA node `node`.
+pub(crate) fn node_cpus(node: usize) -> Result<Vec<usize>, String> {
+    let path = format!("{}/node{}/cpulist", NODE_DIR, node);
+    let list = fs::read_to_string(&path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    parse_cpu_list(&list)
+}
+
+/// Only allocates memory from NUMA node `node` and only runs on its CPUs from now on, in this
+/// thread and every thread or process it starts.
+///
+/// # Returns
+/// The CPUs of the node.
+pub(crate) fn bind_to_node(node: usize) -> Result<Vec<usize>, String> {
+    let nodes = online_nodes();
+    if !nodes.contains(&node) {
+        return Err(format!(
+            "NUMA node {} isn't online, the online nodes are {:?}",
+            node, nodes
+        ));
+    }
+    let cpus = node_cpus(node)?;
+    if cpus.is_empty() {
+        return Err(format!("NUMA node {} has no CPUs to hammer from", node));
+    }
+
+    let bits = c_ulong::BITS as usize;
+    let mut nodemask = vec![0 as c_ulong; node / bits + 1];
+    nodemask[node / bits] |= 1 << (node % bits);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            nodemask.len() * bits + 1,
+        )
+    };
+    if result != 0 {
+        return Err(format!(
+            "couldn't bind memory to NUMA node {}: {}",
+            node,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let mut cpu_set = CpuSet::new();
+    for &cpu in &cpus {
+        cpu_set
+            .set(cpu)
+            .map_err(|e| format!("couldn't add CPU {}: {}", cpu, e))?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)
+        .map_err(|e| format!("couldn't bind to the CPUs of NUMA node {}: {}", node, e))?;
+    Ok(cpus)
+}
+
+/// The NUMA node the page at `virtual_addr` is on, which works without root.
+///
+/// # Returns
+/// The node, or `None` if the page isn't in memory.
+pub(crate) fn node_of(virtual_addr: *const u8) -> Option<usize> {
+    let mut node: c_int = -1;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut node as *mut c_int,
+            ptr::null_mut::<c_ulong>(),
+            0 as c_ulong,
+            virtual_addr,
+            MPOL_F_NODE | MPOL_F_ADDR,
+        )
+    };
+    (result == 0 && node >= 0).then_some(node as usize)
+}
+
+/// Formats the NUMA node of the page at `virtual_addr` for the output files, `?` if unknown.
+pub(crate) fn node_string(virtual_addr: *const u8) -> String {
+    match node_of(virtual_addr) {
+        Some(node) => format!("node{}", node),
+        None => "node?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists() {
+        let cases = [
+            ("0-3,8-11\n", vec![0, 1, 2, 3, 8, 9, 10, 11]),
+            ("0\n", vec![0]),
+            ("0", vec![0]),
+            ("0,2,4-5\n", vec![0, 2, 4, 5]),
+            ("7-7", vec![7]),
+            // A node without CPUs, e.g. one with only memory
+            ("\n", vec![]),
+            ("", vec![]),
+        ];
+        for (list, expected) in cases {
+            assert_eq!(parse_cpu_list(list), Ok(expected), "{:?}", list);
+        }
+        for list in ["x", "0-", "-3", "1,,a", "0-3-5", "3-1", "0 1", "-1"] {
+            assert_eq!(
+                parse_cpu_list(list),
+                Err(format!("invalid CPU list `{}`", list)),
+                "{:?}",
+                list
+            );
+        }
+    }
+}
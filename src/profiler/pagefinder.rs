@@ -1,4 +1,5 @@
 use std::{
+    collections::{BTreeMap, HashSet},
     fs::{create_dir, File},
     io::{self, BufRead, BufReader, Write},
     path::Path,
@@ -6,42 +7,106 @@ use std::{
     time::{self, Instant},
 };
 
+use crate::profiler::index::MemoryIndex;
+use crate::profiler::kpageflags::page_flags;
+use crate::profiler::mapping::DramMapping;
+use crate::profiler::numa::node_string;
+use crate::profiler::pattern::HammerPattern;
+use crate::profiler::pool::MappingPool;
+use crate::profiler::rowhammer::init_row;
 use crate::profiler::utils::{
-    self, collect_pages_by_row, count_flips_by_bit, fill_memory, rowhammer, setup_mapping, Page,
-    PageData, Row, NO_OF_READS,
+    self, aggressor_lines, count_flips_by_bit, fill_memory, Page, PageData, AGGRESSOR_PATTERN,
 };
+use crate::{AttackMethod, Backing};
 
 const TEST_ITERATIONS: u32 = 10;
 const RISK_THRESHOLD: u32 = 0;
 const SCORE_THRESHOLD: u32 = 3;
 const CANDIDATES_THRESHOLD: f64 = 0.9;
+/// How much of the physical memory the mapping grows by until enough candidates are found
+const POOL_CHUNK_FRACTION: f64 = 0.1;
+/// Page frames listed when explaining why candidates weren't found, the rest are only counted
+const MAX_EXPLAINED_PFNS: usize = 20;
 
 #[derive(Debug)]
 pub(crate) struct PageCandidate {
     pub(crate) target_page: Page,
     pub(crate) above_pages: (Page, Page),
     pub(crate) below_pages: (Page, Page),
+    /// Aggressor pages in rows further away than the adjacent ones, if the pattern hammers any
+    pub(crate) far_pages: Vec<Page>,
+    pub(crate) pattern: HammerPattern,
+    /// The cache lines to hammer for every bank the target page is spread over, one line in each
+    /// aggressor row of the pattern
+    pub(crate) aggressors: Vec<Vec<*const u8>>,
 
     pub(crate) score: u32,
 }
 
 impl PageCandidate {
+    /// Creates a candidate hammered with `pattern`. `far_pages` holds the pages found in the
+    /// pattern's aggressor rows which aren't adjacent to the target row, by distance.
     pub(crate) fn new(
         target_page: Page,
         above_pages: (Page, Page),
         below_pages: (Page, Page),
+        far_pages: Vec<(isize, Vec<Page>)>,
+        pattern: &HammerPattern,
+        mapping: &dyn DramMapping,
     ) -> Self {
         let target_flips = target_page.data.as_ref().unwrap().flips;
+        let aggressor_pages = pattern
+            .distances()
+            .map(|distance| match distance {
+                -1 => vec![&above_pages.0, &above_pages.1],
+                1 => vec![&below_pages.0, &below_pages.1],
+                _ => far_pages
+                    .iter()
+                    .find(|(d, _)| *d == distance)
+                    .map(|(_, pages)| pages.iter().collect())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        let aggressors = aggressor_lines(&target_page, &aggressor_pages, mapping);
 
         Self {
             target_page,
             above_pages,
             below_pages,
+            far_pages: far_pages.into_iter().flat_map(|(_, pages)| pages).collect(),
+            pattern: pattern.clone(),
+            aggressors,
 
             score: Self::calculate_score(&target_flips),
         }
     }
 
+    /// Zeroes the target page and writes the aggressor pattern to every aggressor page.
+    pub(crate) fn fill(&self) {
+        unsafe {
+            fill_memory(
+                self.target_page.virt_addr,
+                self.above_pages.0.virt_addr,
+                self.below_pages.0.virt_addr,
+            );
+            fill_memory(
+                self.target_page.virt_addr,
+                self.above_pages.1.virt_addr,
+                self.below_pages.1.virt_addr,
+            );
+        }
+        init_row(&self.far_pages, AGGRESSOR_PATTERN);
+    }
+
+    /// Hammers the aggressor cache lines of every bank with the pattern, for `iters` double-sided
+    /// iterations.
+    pub(crate) fn hammer(&self, iters: usize) {
+        for aggressors in &self.aggressors {
+            self.pattern
+                .hammer(aggressors, AttackMethod::RowHammer, iters);
+        }
+    }
+
     /// Calculates the score of the PageCandidate
     pub(crate) fn calculate_score(flips: &[u64]) -> u32 {
         let position_bonus = 10;
@@ -63,67 +128,107 @@ fn calculate_risk_score(flips: &[u64]) -> u32 {
     risk_score as u32
 }
 
-fn find_page(pages: &[Page], page_nbr: u64) -> Option<&Page> {
-    pages.iter().find(|page| page.pfn == page_nbr)
+/// The rows of the aggressors of `pattern` for the target page frame `pfn`, except the rows
+/// directly above and below it, whose pages are listed in the profiler's output.
+///
+/// # Returns
+/// The distance and row index of every such aggressor row, `None` if it's outside the bank.
+fn far_aggressor_rows(
+    mapping: &dyn DramMapping,
+    pfn: u64,
+    pattern: &HammerPattern,
+) -> Vec<(isize, Option<usize>)> {
+    let target = mapping.decode(pfn * utils::PAGE_SIZE as u64);
+    pattern
+        .distances()
+        .filter(|distance| distance.abs() > 1)
+        .map(|distance| {
+            (
+                distance,
+                mapping.adjacent_row(target.row, target.rank, distance),
+            )
+        })
+        .collect()
 }
 
 fn setup_page_candidate(
-    pages_by_row: &[Row],
-    pfn: u64,
-    above_pfns: (u64, u64),
-    below_pfns: (u64, u64),
+    index: &MemoryIndex,
+    mapping: &dyn DramMapping,
+    pattern: &HammerPattern,
+    (pfn, above_pfns, below_pfns): (u64, (u64, u64), (u64, u64)),
     target_flips: [u64; utils::MAX_BITS],
     flip_offsets: Vec<usize>,
 ) -> Result<PageCandidate, &'static str> {
-    // Find the rows that contains the target pages
-    for index in 0..pages_by_row.len() - 2 {
-        let above_row_index = index;
-        let target_row_index = index + 1;
-        let below_row_index = index + 2;
-
-        let above_row = &pages_by_row[above_row_index];
-        let target_row = &pages_by_row[target_row_index];
-        let below_row = &pages_by_row[below_row_index];
-
-        let Some(target_page) = find_page(&target_row[..], pfn) else {
-            continue;
-        };
-        let Some(above_page1) = find_page(&above_row[..], above_pfns.0) else {
-            continue;
-        };
-        let Some(above_page2) = find_page(&above_row[..], above_pfns.1) else {
-            continue;
-        };
-        let Some(below_page1) = find_page(&below_row[..], below_pfns.0) else {
-            continue;
-        };
-        let Some(below_page2) = find_page(&below_row[..], below_pfns.1) else {
-            continue;
-        };
-
-        let mut target_page = target_page.clone();
+    // The aggressors have to be in the DRAM rows physically above and below the target row,
+    // which depend on the rank if odd ranks mirror or invert their row address
+    let row_of = |pfn: u64| mapping.row(pfn * utils::PAGE_SIZE as u64);
+    let target = mapping.decode(pfn * utils::PAGE_SIZE as u64);
+    let above_row_index = mapping.adjacent_row(target.row, target.rank, -1);
+    let below_row_index = mapping.adjacent_row(target.row, target.rank, 1);
+    if [above_pfns.0, above_pfns.1]
+        .iter()
+        .any(|pfn| Some(row_of(*pfn)) != above_row_index)
+        || [below_pfns.0, below_pfns.1]
+            .iter()
+            .any(|pfn| Some(row_of(*pfn)) != below_row_index)
+    {
+        return Err("Aggressor pages aren't in the rows adjacent to the target page");
+    }
 
-        // If pages are found, create a PageCandidate
-        target_page.data = Some(PageData::new(
-            above_pfns,
-            below_pfns,
-            target_flips,
-            flip_offsets,
-        ));
-        let page_candidate = PageCandidate::new(
-            target_page,
-            (above_page1.clone(), above_page2.clone()),
-            (below_page1.clone(), below_page2.clone()),
-        );
+    let pages = (
+        index.page(pfn),
+        index.page(above_pfns.0),
+        index.page(above_pfns.1),
+        index.page(below_pfns.0),
+        index.page(below_pfns.1),
+    );
+    let (
+        Some(target_page),
+        Some(above_page1),
+        Some(above_page2),
+        Some(below_page1),
+        Some(below_page2),
+    ) = pages
+    else {
+        return Err("Pages not found in mapping");
+    };
 
-        return Ok(page_candidate);
+    // Any page we own in the further aggressor rows will do, they aren't in the profiler's output
+    let Some(far_pages) = far_aggressor_rows(mapping, pfn, pattern)
+        .into_iter()
+        .map(|(distance, row)| {
+            let row = index.row(row?)?;
+            Some((distance, row[..].to_vec()))
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Err("Pattern aggressor rows not found in mapping");
+    };
+    let mut target_page = target_page.clone();
+
+    // If pages are found, create a PageCandidate
+    target_page.data = Some(PageData::new(
+        above_pfns,
+        below_pfns,
+        target_flips,
+        flip_offsets,
+    ));
+    let candidate = PageCandidate::new(
+        target_page,
+        (above_page1.clone(), above_page2.clone()),
+        (below_page1.clone(), below_page2.clone()),
+        far_pages,
+        pattern,
+        mapping,
+    );
+    if candidate.aggressors.is_empty() {
+        return Err("No bank of the target page has a line in every aggressor row");
     }
-
-    Err("Pages not found in mapping")
+    Ok(candidate)
 }
 
 /// Output the PageCandidate to a file
-fn output_page(page_candidate: &PageCandidate) -> io::Result<()> {
+fn output_page(page_candidate: &PageCandidate, mapping: &dyn DramMapping) -> io::Result<()> {
     let mut path = std::env::current_dir()?;
 
     if !path.join("data").exists() {
@@ -157,14 +262,23 @@ fn output_page(page_candidate: &PageCandidate) -> io::Result<()> {
     let width = 12;
     file.write_all(
         format!(
-            "\t{:<width$}{:<width$}{:<width$}{:<width$}{:<width$}{:<7}{:<36}{}\n",
-            "Page", "aPFN1", "aPFN2", "bPFN1", "bPFN2", "Score", "Flipped bits", "Offsets"
+            "\t{:<width$}{:<width$}{:<width$}{:<width$}{:<width$}{:<7}{:<36}{}\t{}\t{}\n",
+            "Page",
+            "aPFN1",
+            "aPFN2",
+            "bPFN1",
+            "bPFN2",
+            "Score",
+            "Flipped bits",
+            "Offsets",
+            "DRAM address",
+            "NUMA node"
         )
         .as_bytes(),
     )?;
     file.write_all(
         format!(
-            ">\t{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<7}{:?}{:?}",
+            ">\t{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<7}{:?}{:?}\t{}\t{}",
             page_candidate.target_page.pfn,
             page_candidate.above_pages.0.pfn,
             page_candidate.above_pages.1.pfn,
@@ -173,6 +287,8 @@ fn output_page(page_candidate: &PageCandidate) -> io::Result<()> {
             page_candidate.score,
             flips,
             flip_offsets,
+            page_candidate.target_page.dram_address(mapping),
+            node_string(page_candidate.target_page.virt_addr),
         )
         .as_bytes(),
     )?;
@@ -180,8 +296,12 @@ fn output_page(page_candidate: &PageCandidate) -> io::Result<()> {
     Ok(())
 }
 
+/// Reads the candidate pages and their aggressor pages from the profiler's output. The profiler
+/// writes one record per bank a page is spread over, but every candidate is hammered in all of
+/// its banks, so only the first record which passes the thresholds is kept for each page.
 fn get_candidate_pfns(input_path: impl AsRef<Path>) -> Vec<(u64, (u64, u64), (u64, u64))> {
     let mut pfns = Vec::new();
+    let mut seen = HashSet::new();
     let file = File::open(input_path).expect("Failed to open file");
 
     for line in BufReader::new(file).lines() {
@@ -226,24 +346,28 @@ fn get_candidate_pfns(input_path: impl AsRef<Path>) -> Vec<(u64, (u64, u64), (u6
             })
             .collect::<Vec<_>>();
 
-        pfns.push((p[0], (p[1], p[2]), (p[3], p[4])));
+        if seen.insert(p[0]) {
+            pfns.push((p[0], (p[1], p[2]), (p[3], p[4])));
+        }
     }
     pfns
 }
 
 /// Read the flips.out file and return a vector of potential exploitable pages
 pub(crate) fn get_candidate_pages(
-    pages_by_row: &[Row],
+    index: &MemoryIndex,
+    mapping: &dyn DramMapping,
+    pattern: &HammerPattern,
     candidate_pfns: &[(u64, (u64, u64), (u64, u64))],
 ) -> Vec<PageCandidate> {
     candidate_pfns
         .iter()
-        .filter_map(|(pfn, above_pfns, below_pfns)| {
+        .filter_map(|pfns| {
             match setup_page_candidate(
-                pages_by_row,
-                *pfn,
-                *above_pfns,
-                *below_pfns,
+                index,
+                mapping,
+                pattern,
+                *pfns,
                 [0; utils::MAX_BITS],
                 Vec::new(),
             ) {
@@ -254,28 +378,22 @@ pub(crate) fn get_candidate_pages(
         .collect()
 }
 
-fn profile_candidate_pages(page_candidates: &mut [PageCandidate]) {
+fn profile_candidate_pages(
+    page_candidates: &mut [PageCandidate],
+    mapping: &dyn DramMapping,
+    hammer_iterations: usize,
+) {
     println!("Profiling {} Page Candidates", page_candidates.len());
 
     'candidate_loop: for candidate in page_candidates {
-        println!("Testing candidate: {:#?}", candidate.target_page.pfn);
+        println!(
+            "Testing candidate: {:#?} ({})",
+            candidate.target_page.pfn,
+            candidate.target_page.dram_address(mapping)
+        );
 
         let target_page = &candidate.target_page;
-        let above_pages = &candidate.above_pages;
-        let below_pages = &candidate.below_pages;
-
-        unsafe {
-            fill_memory(
-                target_page.virt_addr,
-                above_pages.0.virt_addr,
-                below_pages.0.virt_addr,
-            );
-            fill_memory(
-                target_page.virt_addr,
-                above_pages.1.virt_addr,
-                below_pages.1.virt_addr,
-            );
-        }
+        candidate.fill();
 
         let mut risk_score = 0;
         let mut score = 0;
@@ -288,11 +406,7 @@ fn profile_candidate_pages(page_candidates: &mut [PageCandidate]) {
 
             let before = Instant::now();
             for _ in 0..TEST_ITERATIONS {
-                rowhammer(
-                    above_pages.0.virt_addr,
-                    below_pages.0.virt_addr,
-                    NO_OF_READS,
-                );
+                candidate.hammer(hammer_iterations);
             }
             println!("Time: {:#?}", before.elapsed() / TEST_ITERATIONS);
 
@@ -337,51 +451,121 @@ fn profile_candidate_pages(page_candidates: &mut [PageCandidate]) {
 
         if candidate.score > 100 {
             println!("Good page found: {:#?}", candidate.target_page.pfn);
-            output_page(&candidate).expect("Failed to output page");
+            output_page(candidate, mapping).expect("Failed to output page");
+        }
+    }
+}
+
+/// Explains why some of the `wanted_pfns` never showed up in the mapping, using the flags of their
+/// page frames in `/proc/kpageflags`.
+fn explain_missing_pages(index: &MemoryIndex, wanted_pfns: &HashSet<u64>) {
+    let mut missing = wanted_pfns
+        .iter()
+        .copied()
+        .filter(|pfn| index.page(*pfn).is_none())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return;
+    }
+    missing.sort();
+    let flags = match page_flags(&missing) {
+        Ok(flags) => flags,
+        Err(e) => {
+            println!(
+                "[!] Couldn't read the page flags of the missing pages: {}",
+                e
+            );
+            return;
         }
+    };
+
+    let mut by_state = BTreeMap::new();
+    for page_flags in &flags {
+        *by_state.entry(page_flags.state().to_string()).or_insert(0) += 1;
+    }
+    println!(
+        "{} wanted page frames aren't in the mapping:",
+        missing.len()
+    );
+    for (state, count) in &by_state {
+        println!("    {}: {}", state, count);
+    }
+    for (pfn, page_flags) in missing.iter().zip(&flags).take(MAX_EXPLAINED_PFNS) {
+        println!("    {:#x}: {}", pfn, page_flags);
+    }
+    if missing.len() > MAX_EXPLAINED_PFNS {
+        println!("    ...");
     }
 }
 
-pub(crate) fn main(dimms: u8) {
+pub(crate) fn main(
+    mapping: &dyn DramMapping,
+    backing: Backing,
+    pattern: &HammerPattern,
+    hammer_iterations: usize,
+) {
     let mut fraction_of_phys_memory = 0.0;
-    let row_size = 128 * 1024 * dimms as usize;
-    let mut mmap = setup_mapping(0.0);
     let candidate_pfns = get_candidate_pfns("flips.out");
+    let wanted_pfns = candidate_pfns
+        .iter()
+        .flat_map(|(pfn, above, below)| [*pfn, above.0, above.1, below.0, below.1])
+        .collect::<HashSet<_>>();
+    // The further aggressor rows of the pattern aren't in flips.out, any of their pages will do
+    let wanted_rows = candidate_pfns
+        .iter()
+        .flat_map(|(pfn, _, _)| far_aggressor_rows(mapping, *pfn, pattern))
+        .filter_map(|(_, row)| row)
+        .collect::<HashSet<_>>();
+    let mut pool = MappingPool::new(backing);
 
     println!("number of pfns in flips.out: {}", candidate_pfns.len());
     let result = loop {
-        fraction_of_phys_memory += 0.1;
+        fraction_of_phys_memory += POOL_CHUNK_FRACTION;
         if fraction_of_phys_memory > 0.95 {
             break None;
         }
 
         println!(
-            "Setting up memory mapping with {} of physical memory",
+            "Growing memory mapping to {:.1} of physical memory",
             fraction_of_phys_memory
         );
 
-        // Drop the old mapping in order to create a new one
-        std::mem::drop(mmap);
-        mmap = setup_mapping(fraction_of_phys_memory);
-
-        println!("Collecting pages from mapping...");
-
-        let pages_by_row = match collect_pages_by_row(&mut mmap, row_size) {
-            Ok(pages_by_row) => {
-                if pages_by_row.len() < 3 {
-                    println!("Not enough rows in mapping, got {}", pages_by_row.len());
-                    continue;
-                }
-                pages_by_row
+        // Keep the pages found so far and only map the next chunk
+        let exhausted = match pool.grow(POOL_CHUNK_FRACTION, mapping, &wanted_pfns, &wanted_rows) {
+            Ok(found) => {
+                println!(
+                    "Found {} more wanted pages ({}/{}, {} pages kept in total)",
+                    found,
+                    pool.found(),
+                    wanted_pfns.len(),
+                    pool.index().page_count()
+                );
+                false
             }
             Err(e) => {
-                println!("Couldn't collect pages from mapping, got {:#?}", e);
-                continue;
+                println!("[!] Can't grow the mapping any further: {}", e);
+                true
             }
         };
 
+        if pool.index().row_count() < 3 {
+            println!(
+                "Not enough rows in mapping, got {}",
+                pool.index().row_count()
+            );
+            if exhausted {
+                break None;
+            }
+            continue;
+        }
+
         println!("Finding candidate pages...");
-        let candidates = get_candidate_pages(&pages_by_row, &candidate_pfns);
+        let candidates = get_candidate_pages(pool.index(), mapping, pattern, &candidate_pfns);
+
+        // Profile the candidates found so far instead of losing them
+        if exhausted {
+            break (!candidates.is_empty()).then_some(candidates);
+        }
 
         if (candidates.len() as f64 / candidate_pfns.len() as f64) < CANDIDATES_THRESHOLD {
             println!(
@@ -389,12 +573,24 @@ pub(crate) fn main(dimms: u8) {
                 candidates.len(),
                 candidate_pfns.len(),
             );
+            // Growing further won't give us any more of them
+            if pool.found() == wanted_pfns.len() {
+                println!("All wanted pages are already mapped");
+                break (!candidates.is_empty()).then_some(candidates);
+            }
             continue;
         }
 
         break Some(candidates);
     };
 
+    explain_missing_pages(pool.index(), &wanted_pfns);
+
+    match pool.release_unwanted() {
+        Ok(released) => println!("Released {} pages which weren't needed", released),
+        Err(e) => println!("[!] Couldn't release unneeded pages: {}", e),
+    }
+
     let mut candidates = match result {
         Some(mut candidates) => {
             println!(
@@ -412,5 +608,37 @@ pub(crate) fn main(dimms: u8) {
         }
     };
 
-    profile_candidate_pages(&mut candidates);
+    profile_candidate_pages(&mut candidates, mapping, hammer_iterations);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidate_pfns_are_deduplicated() {
+        let path = std::env::temp_dir().join(format!("flips_{}.out", std::process::id()));
+        let flips = "[0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]";
+        let low_score = "[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]";
+        std::fs::write(
+            &path,
+            format!(
+                "\tPage        aPFN1       aPFN2       bPFN1       bPFN2       Flips  Flipped bits\n\
+                 >\t0x100       0x80        0x81        0x180       0x181       1      {low_score}\n\
+                 >\t0x100       0x82        0x83        0x182       0x183       4      {flips}\n\
+                 >\t0x100       0x84        0x85        0x184       0x185       4      {flips}\n\
+                 >\t0x200       0x90        0x91        0x290       0x291       4      {flips}\n"
+            ),
+        )
+        .unwrap();
+        let pfns = get_candidate_pfns(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            pfns,
+            [
+                (0x100, (0x82, 0x83), (0x182, 0x183)),
+                (0x200, (0x90, 0x91), (0x290, 0x291)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,296 @@
+use std::{
+    fmt,
+    fs::File,
+    io,
+    mem::size_of,
+    os::unix::fs::FileExt,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::profiler::utils::PAGE_SIZE;
+
+/// Pagemap entries read at once, 512 KiB of the file covering 256 MiB of memory
+const BATCH_ENTRIES: usize = 0x1_0000;
+/// Bits 0-54 hold the page frame number of a present page (zero without root)
+const PFN_MASK: u64 = (1 << 55) - 1;
+const SOFT_DIRTY: u64 = 1 << 55;
+const SWAPPED: u64 = 1 << 62;
+const PRESENT: u64 = 1 << 63;
+
+/// One entry of `/proc/self/pagemap` (see the kernel's admin-guide/mm/pagemap.rst).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PagemapEntry {
+    pub(crate) present: bool,
+    pub(crate) swapped: bool,
+    /// Whether the page was written to since the soft-dirty bits were last cleared
+    pub(crate) soft_dirty: bool,
+    pfn: u64,
+}
+
+impl PagemapEntry {
+    pub(crate) fn parse(raw: u64) -> Self {
+        let present = raw & PRESENT != 0;
+        Self {
+            present,
+            swapped: raw & SWAPPED != 0,
+            soft_dirty: raw & SOFT_DIRTY != 0,
+            pfn: if present { raw & PFN_MASK } else { 0 },
+        }
+    }
+
+    /// The page frame number, or `None` if the page isn't in memory or the process isn't
+    /// allowed to see page frame numbers.
+    pub(crate) fn pfn(&self) -> Option<u64> {
+        (self.pfn != 0).then_some(self.pfn)
+    }
+
+    /// The entry of the page `pages` pages after this one in the same physically contiguous huge
+    /// page, which shares its flags.
+    fn offset(&self, pages: u64) -> Self {
+        Self {
+            pfn: self.pfn + pages,
+            ..*self
+        }
+    }
+}
+
+/// The pagemap entries of a range of virtual memory, with how long reading them took.
+pub(crate) struct PagemapScan {
+    pub(crate) entries: Vec<PagemapEntry>,
+    batches: usize,
+    threads: usize,
+    elapsed: Duration,
+}
+
+impl fmt::Display for PagemapScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |f: fn(&PagemapEntry) -> bool| self.entries.iter().filter(|e| f(e)).count();
+        write!(
+            f,
+            "Read {} pagemap entries in {} batches on {} threads in {:.2?} \
+             ({} present, {} with page frame numbers, {} soft-dirty, {} swapped)",
+            self.entries.len(),
+            self.batches,
+            self.threads,
+            self.elapsed,
+            count(|e| e.present),
+            count(|e| e.pfn().is_some()),
+            count(|e| e.soft_dirty),
+            count(|e| e.swapped),
+        )
+    }
+}
+
+/// Reads the pagemap entries of the `len` bytes starting at `start`, in batches of
+/// `BATCH_ENTRIES` entries. The batches are split over up to `max_threads` threads, but every
+/// thread gets at least one whole batch.
+pub(crate) fn scan_pagemap(
+    start: *const u8,
+    len: usize,
+    max_threads: usize,
+) -> io::Result<PagemapScan> {
+    let before = Instant::now();
+    let pagemap = File::open("/proc/self/pagemap")?;
+    let first_page = start as usize / PAGE_SIZE;
+    let pages = (start as usize + len).div_ceil(PAGE_SIZE) - first_page;
+    let batches = pages.div_ceil(BATCH_ENTRIES);
+    let threads = max_threads.clamp(1, batches.max(1));
+    let pages_per_thread = batches.div_ceil(threads) * BATCH_ENTRIES;
+
+    let mut entries = vec![PagemapEntry::default(); pages];
+    thread::scope(|scope| {
+        let handles = entries
+            .chunks_mut(pages_per_thread.max(1))
+            .enumerate()
+            .map(|(i, part)| {
+                let pagemap = &pagemap;
+                let first_page = first_page + i * pages_per_thread;
+                scope.spawn(move || read_entries(pagemap, first_page, part))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("Pagemap reader panicked"))
+    })?;
+
+    Ok(PagemapScan {
+        entries,
+        batches,
+        threads,
+        elapsed: before.elapsed(),
+    })
+}
+
+/// Reads the pagemap entries of the `len` bytes starting at `start`, which are backed by huge
+/// pages of `huge_page_size` bytes. Only the first entry of each huge page is read and the rest
+/// follow from the huge page being physically contiguous. Transparent huge pages may have been
+/// split or never merged, so with `check_contiguous` the last entry is read as well and huge pages
+/// which turn out not to be contiguous are read in full.
+pub(crate) fn scan_huge_pages(
+    start: *const u8,
+    len: usize,
+    huge_page_size: usize,
+    check_contiguous: bool,
+) -> io::Result<PagemapScan> {
+    let before = Instant::now();
+    let pagemap = File::open("/proc/self/pagemap")?;
+    let mut entries = Vec::with_capacity(len.div_ceil(PAGE_SIZE));
+    let mut batches = 0;
+
+    let mut offset = 0;
+    while offset < len {
+        // Huge pages start at virtual addresses aligned to their size
+        let chunk_end =
+            ((start as usize + offset) / huge_page_size + 1) * huge_page_size - start as usize;
+        let chunk = offset..chunk_end.min(len);
+        offset = chunk.end;
+        let pages = (chunk.end - chunk.start).div_ceil(PAGE_SIZE);
+
+        let first = read_entry(&pagemap, start.wrapping_add(chunk.start))?;
+        batches += 1;
+        let contiguous = match first.pfn() {
+            Some(_) if check_contiguous => {
+                let last = read_entry(&pagemap, start.wrapping_add(chunk.end - PAGE_SIZE))?;
+                batches += 1;
+                is_whole_huge_page(first.pfn(), last.pfn(), pages, huge_page_size)
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if contiguous {
+            entries.extend((0..pages as u64).map(|i| first.offset(i)));
+        } else {
+            let from = entries.len();
+            entries.resize(from + pages, PagemapEntry::default());
+            let first_page = (start as usize + chunk.start) / PAGE_SIZE;
+            read_entries(&pagemap, first_page, &mut entries[from..])?;
+            batches += pages.div_ceil(BATCH_ENTRIES);
+        }
+    }
+
+    Ok(PagemapScan {
+        entries,
+        batches,
+        threads: 1,
+        elapsed: before.elapsed(),
+    })
+}
+
+/// Whether the `pages` pages from page frame `first` to `last` make up a whole, aligned huge page
+/// of `huge_page_size` bytes.
+fn is_whole_huge_page(
+    first: Option<u64>,
+    last: Option<u64>,
+    pages: usize,
+    huge_page_size: usize,
+) -> bool {
+    let pages = pages as u64;
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            pages == (huge_page_size / PAGE_SIZE) as u64
+                && first % pages == 0
+                && last == first + pages - 1
+        }
+        _ => false,
+    }
+}
+
+/// Fills `entries` with the pagemap entries from page `first_page` on, one batch at a time.
+fn read_entries(pagemap: &File, first_page: usize, entries: &mut [PagemapEntry]) -> io::Result<()> {
+    let mut buf = vec![0u8; BATCH_ENTRIES * size_of::<u64>()];
+    for (i, batch) in entries.chunks_mut(BATCH_ENTRIES).enumerate() {
+        let bytes = &mut buf[..batch.len() * size_of::<u64>()];
+        let offset = (first_page + i * BATCH_ENTRIES) * size_of::<u64>();
+        pagemap.read_exact_at(bytes, offset as u64)?;
+        for (entry, raw) in batch.iter_mut().zip(bytes.chunks_exact(size_of::<u64>())) {
+            *entry = PagemapEntry::parse(u64::from_ne_bytes(raw.try_into().unwrap()));
+        }
+    }
+    Ok(())
+}
+
+/// The number of threads to scan the pagemap with.
+pub(crate) fn pagemap_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/// Reads the pagemap entry of the page holding `virtual_addr`.
+pub(crate) fn read_entry(pagemap: &File, virtual_addr: *const u8) -> io::Result<PagemapEntry> {
+    let mut raw = [0u8; size_of::<u64>()];
+    let offset = virtual_addr as usize / PAGE_SIZE * size_of::<u64>();
+    pagemap.read_exact_at(&mut raw, offset as u64)?;
+    Ok(PagemapEntry::parse(u64::from_ne_bytes(raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entries() {
+        let entry = |present, swapped, soft_dirty, pfn| PagemapEntry {
+            present,
+            swapped,
+            soft_dirty,
+            pfn,
+        };
+        let cases = [
+            (0, entry(false, false, false, 0)),
+            (PRESENT | 0x12345, entry(true, false, false, 0x12345)),
+            // Without root present pages read with a zero page frame number
+            (PRESENT, entry(true, false, false, 0)),
+            (
+                PRESENT | SOFT_DIRTY | 0x1_0000,
+                entry(true, false, true, 0x1_0000),
+            ),
+            // Swapped pages hold the swap type and offset where the page frame number would be
+            (SWAPPED | 0x2a1, entry(false, true, false, 0)),
+            (SWAPPED | SOFT_DIRTY | 0x2a1, entry(false, true, true, 0)),
+            // Bits above the page frame number which aren't flags here don't leak into it
+            (
+                PRESENT | (1 << 61) | PFN_MASK,
+                entry(true, false, false, PFN_MASK),
+            ),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(PagemapEntry::parse(raw), expected, "{:#x}", raw);
+        }
+        assert_eq!(PagemapEntry::parse(PRESENT).pfn(), None);
+        assert_eq!(PagemapEntry::parse(PRESENT | 7).pfn(), Some(7));
+    }
+
+    #[test]
+    fn huge_page_entries_keep_the_flags() {
+        let first = PagemapEntry::parse(PRESENT | SOFT_DIRTY | 0x200);
+        let entry = first.offset(5);
+        assert_eq!(entry.pfn(), Some(0x205));
+        assert!(entry.present && entry.soft_dirty && !entry.swapped);
+    }
+
+    #[test]
+    fn whole_huge_pages() {
+        const HUGE: usize = 2 << 20;
+        let cases = [
+            (Some(0x200), Some(0x3ff), 512, true),
+            // Not aligned to the huge page size
+            (Some(0x201), Some(0x400), 512, false),
+            // Not contiguous
+            (Some(0x200), Some(0x1234), 512, false),
+            // Only part of a huge page mapped
+            (Some(0x200), Some(0x2ff), 256, false),
+            (None, Some(0x3ff), 512, false),
+            (Some(0x200), None, 512, false),
+        ];
+        for (first, last, pages, whole) in cases {
+            assert_eq!(
+                is_whole_huge_page(first, last, pages, HUGE),
+                whole,
+                "{:?}..{:?}",
+                first,
+                last
+            );
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    arch::x86_64::{_mm_clflush, _mm_lfence, _mm_mfence},
+    fmt,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::profiler::calibrate::HammerCounts;
+use crate::profiler::utils::{ROWPRESS_ACTIVATIONS, ROWPRESS_READS};
+use crate::AttackMethod;
+
+/// Seed of the shuffled access order, so the same pattern always gives the same sequence
+const RANDOM_ORDER_SEED: u64 = 0x6b6e_6974;
+/// How many shuffles are tried to find one which never accesses an aggressor twice in a row
+const RANDOM_ORDER_ATTEMPTS: usize = 100;
+/// Weight of the far aggressors of the half-double preset, relative to the near ones
+const HALF_DOUBLE_WEIGHT: usize = 8;
+
+/// One aggressor row of a pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Aggressor {
+    /// Physical distance from the victim row, negative for rows above it
+    pub(crate) distance: isize,
+    /// How many times the aggressor is accessed in each round of the pattern
+    pub(crate) weight: usize,
+}
+
+/// The order the accesses to the aggressors are made in within one round. An aggressor is never
+/// accessed twice in a row (also from the end of one round to the start of the next), as the
+/// second access would hit the open row instead of activating it again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AccessOrder {
+    /// One access to each aggressor at a time, e.g. `a b c a c a c` for weights 3, 1 and 3
+    Interleaved,
+    /// All accesses to one aggressor, then the next, so only weights of 1 work, e.g. `a b c`
+    Sequential,
+    /// The accesses shuffled (always the same way), e.g. `c a b a c b a` for weights 3, 2 and 2
+    Random,
+}
+
+/// A hammer pattern: which rows around the victim are accessed, how often, and in which order.
+/// Double-sided hammering is the pattern `-1,+1`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HammerPattern {
+    pub(crate) aggressors: Vec<Aggressor>,
+    pub(crate) order: AccessOrder,
+    /// The indices of the aggressors in the order they are accessed in one round
+    sequence: Vec<usize>,
+}
+
+impl Default for HammerPattern {
+    fn default() -> Self {
+        parse_pattern("double-sided").expect("Preset patterns are valid")
+    }
+}
+
+impl HammerPattern {
+    pub(crate) fn new(aggressors: Vec<Aggressor>, order: AccessOrder) -> Result<Self, String> {
+        if aggressors.is_empty() {
+            return Err("a pattern needs at least one aggressor".to_string());
+        }
+        for (i, aggressor) in aggressors.iter().enumerate() {
+            if aggressor.distance == 0 {
+                return Err("an aggressor can't be the victim row (distance 0)".to_string());
+            }
+            if aggressor.weight == 0 {
+                return Err(format!(
+                    "the aggressor at distance {:+} has weight 0",
+                    aggressor.distance
+                ));
+            }
+            if aggressors[..i]
+                .iter()
+                .any(|other| other.distance == aggressor.distance)
+            {
+                return Err(format!(
+                    "distance {:+} is given more than once",
+                    aggressor.distance
+                ));
+            }
+        }
+
+        let max_weight = aggressors.iter().map(|a| a.weight).max().unwrap_or(0);
+        let sequence = match order {
+            AccessOrder::Interleaved => (0..max_weight)
+                .flat_map(|round| {
+                    aggressors
+                        .iter()
+                        .enumerate()
+                        .filter(move |(_, a)| a.weight > round)
+                        .map(|(i, _)| i)
+                })
+                .collect(),
+            AccessOrder::Sequential => aggressors
+                .iter()
+                .enumerate()
+                .flat_map(|(i, a)| std::iter::repeat_n(i, a.weight))
+                .collect::<Vec<_>>(),
+            AccessOrder::Random => random_sequence(&aggressors),
+        };
+        if let Some(i) = repeated_access(&sequence) {
+            return Err(format!(
+                "the {} order accesses the aggressor at distance {:+} twice in a row, which hits \
+                 the open row instead of activating it again",
+                order, aggressors[i].distance
+            ));
+        }
+        Ok(Self {
+            aggressors,
+            order,
+            sequence,
+        })
+    }
+
+    /// The distances of the aggressor rows from the victim row, in the order of `aggressors`.
+    pub(crate) fn distances(&self) -> impl Iterator<Item = isize> + '_ {
+        self.aggressors.iter().map(|a| a.distance)
+    }
+
+    /// The largest distance of an aggressor from the victim row.
+    pub(crate) fn reach(&self) -> usize {
+        self.distances()
+            .map(|d| d.unsigned_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Hammers `aggressors`, one address in each aggressor row in the order of `self.aggressors`.
+    /// `iters` is counted in double-sided iterations: the access sequence is repeated until it
+    /// has made as many accesses as `iters` double-sided iterations, so every pattern takes about
+    /// as long as double-sided hammering.
+    pub(crate) fn hammer(&self, aggressors: &[*const u8], method: AttackMethod, iters: usize) {
+        assert_eq!(
+            aggressors.len(),
+            self.aggressors.len(),
+            "Need one address per aggressor"
+        );
+        let sequence = self
+            .sequence
+            .iter()
+            .map(|i| aggressors[*i])
+            .collect::<Vec<_>>();
+        let rounds = (iters * 2 / sequence.len()).max(1);
+        match method {
+            AttackMethod::RowHammer => hammer_sequence(&sequence, rounds),
+            AttackMethod::RowPress => {
+                press_sequence(&sequence, rounds, ROWPRESS_ACTIVATIONS, ROWPRESS_READS)
+            }
+        }
+    }
+}
+
+/// Formats the pattern the way `parse_pattern` reads it, e.g. `-2*8,-1,+1,+2*8:interleaved`.
+impl fmt::Display for HammerPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let aggressors = self
+            .aggressors
+            .iter()
+            .map(|a| match a.weight {
+                1 => format!("{:+}", a.distance),
+                weight => format!("{:+}*{}", a.distance, weight),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}:{}", aggressors.join(","), self.order)
+    }
+}
+
+/// The name of the order, as `parse_pattern` reads it.
+impl fmt::Display for AccessOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let order = match self {
+            AccessOrder::Interleaved => "interleaved",
+            AccessOrder::Sequential => "sequential",
+            AccessOrder::Random => "random",
+        };
+        write!(f, "{}", order)
+    }
+}
+
+/// How the profiler hammers: the pattern, the attack method and the calibrated iteration counts.
+#[derive(Clone, Debug)]
+pub(crate) struct Hammering {
+    pub(crate) pattern: HammerPattern,
+    pub(crate) method: AttackMethod,
+    pub(crate) counts: HammerCounts,
+}
+
+impl Hammering {
+    /// Hammers `aggressors` with the pattern for as many iterations as calibrated for the method.
+    pub(crate) fn hammer(&self, aggressors: &[*const u8]) {
+        self.pattern
+            .hammer(aggressors, self.method, self.counts.iterations(self.method));
+    }
+}
+
+/// Finds an aggressor which `sequence` accesses twice in a row, counting the wrap around from the
+/// end of one round to the start of the next.
+///
+/// # Returns
+/// The index of the aggressor, or `None` if every access is to another row than the one before.
+fn repeated_access(sequence: &[usize]) -> Option<usize> {
+    let next = sequence.iter().cycle().skip(1);
+    sequence
+        .iter()
+        .zip(next)
+        .find(|(a, b)| a == b)
+        .map(|(a, _)| *a)
+}
+
+/// Shuffles the accesses to `aggressors` so that no aggressor is accessed twice in a row. The
+/// aggressor with the most accesses left is taken whenever it needs every other access to fit,
+/// the rest is picked at random. The shuffle is seeded, so it's the same every time.
+///
+/// # Returns
+/// The first shuffle without repeated accesses, or the last one tried if there is none, e.g.
+/// because one aggressor has more accesses than all the others together.
+fn random_sequence(aggressors: &[Aggressor]) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(RANDOM_ORDER_SEED);
+    let mut sequence = Vec::new();
+    for _ in 0..RANDOM_ORDER_ATTEMPTS {
+        let mut left = aggressors.iter().map(|a| a.weight).collect::<Vec<_>>();
+        sequence.clear();
+        loop {
+            let total = left.iter().sum::<usize>();
+            if total == 0 {
+                break;
+            }
+            let previous = sequence.last().copied();
+            let candidates = (0..left.len())
+                .filter(|i| left[*i] > 0 && Some(*i) != previous)
+                .collect::<Vec<_>>();
+            let most = candidates.iter().copied().max_by_key(|i| left[*i]);
+            let next = match most {
+                Some(i) if 2 * left[i] >= total => i,
+                _ => match candidates.choose_weighted(&mut rng, |i| left[*i]) {
+                    Ok(i) => *i,
+                    // Only the aggressor just accessed has accesses left
+                    Err(_) => previous.unwrap_or_default(),
+                },
+            };
+            left[next] -= 1;
+            sequence.push(next);
+        }
+        if repeated_access(&sequence).is_none() {
+            break;
+        }
+    }
+    sequence
+}
+
+/// The aggressors of an N-sided pattern: every other row around the victim, as close as possible
+/// and split evenly above and below, e.g. `-3,-1,+1,+3` for 4-sided.
+fn n_sided(n: usize) -> Vec<Aggressor> {
+    let mut distances = (0..n)
+        .map(|i| {
+            let distance = (2 * (i / 2) + 1) as isize;
+            if i % 2 == 0 {
+                -distance
+            } else {
+                distance
+            }
+        })
+        .collect::<Vec<_>>();
+    distances.sort();
+    distances
+        .into_iter()
+        .map(|distance| Aggressor {
+            distance,
+            weight: 1,
+        })
+        .collect()
+}
+
+/// Parses a pattern description: a preset (`double-sided`, `half-double` or `<N>-sided`) or a
+/// comma separated list of aggressor distances with optional weights, like `-2*8,-1,+1,+2*8`.
+/// Either can be followed by the access order, `:interleaved` (the default), `:sequential` or
+/// `:random`.
+pub(crate) fn parse_pattern(description: &str) -> Result<HammerPattern, String> {
+    let (aggressors, order) = match description.trim().split_once(':') {
+        Some((aggressors, order)) => (aggressors.trim(), Some(order.trim())),
+        None => (description.trim(), None),
+    };
+    let order = match order {
+        None | Some("interleaved") => AccessOrder::Interleaved,
+        Some("sequential") => AccessOrder::Sequential,
+        Some("random") => AccessOrder::Random,
+        Some(order) => {
+            return Err(format!(
+                "unknown access order `{}`, expected interleaved, sequential or random",
+                order
+            ))
+        }
+    };
+
+    let aggressors = match aggressors {
+        "double-sided" => n_sided(2),
+        // The far aggressors do the hammering, the near ones are only accessed now and then
+        // to let the disturbance through (see the Half-Double paper)
+        "half-double" => [
+            (-2, HALF_DOUBLE_WEIGHT),
+            (-1, 1),
+            (1, 1),
+            (2, HALF_DOUBLE_WEIGHT),
+        ]
+        .into_iter()
+        .map(|(distance, weight)| Aggressor { distance, weight })
+        .collect(),
+        preset if preset.ends_with("-sided") => {
+            let n = preset
+                .trim_end_matches("-sided")
+                .parse::<usize>()
+                .map_err(|_| format!("unknown preset `{}`", preset))?;
+            if n < 2 {
+                return Err("N-sided patterns need at least 2 aggressors".to_string());
+            }
+            n_sided(n)
+        }
+        list => list
+            .split(',')
+            .map(|aggressor| {
+                let (distance, weight) = match aggressor.split_once('*') {
+                    Some((distance, weight)) => (distance, weight.trim().parse().ok()),
+                    None => (aggressor, Some(1)),
+                };
+                match (distance.trim().parse::<isize>(), weight) {
+                    (Ok(distance), Some(weight)) => Ok(Aggressor { distance, weight }),
+                    _ => Err(format!(
+                        "invalid aggressor `{}`, expected a distance like -1 or +2*8",
+                        aggressor.trim()
+                    )),
+                }
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    HammerPattern::new(aggressors, order)
+}
+
+/// Reads every address in `sequence` from DRAM, in order, `iters` times.
+pub(crate) fn hammer_sequence(sequence: &[*const u8], iters: usize) {
+    for _ in 0..iters {
+        for &addr in sequence {
+            unsafe {
+                _mm_clflush(addr);
+                addr.read_volatile();
+            }
+        }
+    }
+}
+
+/// Keeps the rows of the addresses in `sequence` open for `reads` reads at a time, activating
+/// every row `aggressor_activations` times per iteration.
+pub(crate) fn press_sequence(
+    sequence: &[*const u8],
+    iters: usize,
+    aggressor_activations: usize,
+    reads: usize,
+) {
+    for _ in 0..iters {
+        unsafe {
+            _mm_lfence();
+        }
+
+        for _ in 0..aggressor_activations {
+            // Read the aggressor rows in sequence
+            for &addr in sequence {
+                for i in 0..reads {
+                    unsafe {
+                        addr.add(i).read_volatile();
+                    }
+                }
+            }
+
+            for &addr in sequence {
+                for i in 0..reads {
+                    unsafe {
+                        _mm_clflush(addr.add(i));
+                    }
+                }
+            }
+
+            unsafe {
+                _mm_mfence();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggressors(list: &[(isize, usize)]) -> Vec<Aggressor> {
+        list.iter()
+            .map(|&(distance, weight)| Aggressor { distance, weight })
+            .collect()
+    }
+
+    #[test]
+    fn parses_patterns() {
+        let cases = [
+            (
+                "double-sided",
+                aggressors(&[(-1, 1), (1, 1)]),
+                AccessOrder::Interleaved,
+            ),
+            (
+                "half-double",
+                aggressors(&[(-2, 8), (-1, 1), (1, 1), (2, 8)]),
+                AccessOrder::Interleaved,
+            ),
+            (
+                "3-sided",
+                aggressors(&[(-3, 1), (-1, 1), (1, 1)]),
+                AccessOrder::Interleaved,
+            ),
+            (
+                "4-sided:sequential",
+                aggressors(&[(-3, 1), (-1, 1), (1, 1), (3, 1)]),
+                AccessOrder::Sequential,
+            ),
+            (
+                "-1,+1",
+                aggressors(&[(-1, 1), (1, 1)]),
+                AccessOrder::Interleaved,
+            ),
+            (
+                " -2*8, -1, +1, +2*8 : random ",
+                aggressors(&[(-2, 8), (-1, 1), (1, 1), (2, 8)]),
+                AccessOrder::Random,
+            ),
+            (
+                "+3,+5:interleaved",
+                aggressors(&[(3, 1), (5, 1)]),
+                AccessOrder::Interleaved,
+            ),
+        ];
+        for (description, expected, order) in cases {
+            let pattern = parse_pattern(description).unwrap();
+            assert_eq!(pattern.aggressors, expected, "{}", description);
+            assert_eq!(pattern.order, order, "{}", description);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let cases = [
+            ("1-sided", "N-sided patterns need at least 2 aggressors"),
+            ("many-sided", "unknown preset `many-sided`"),
+            ("-1,+1:backwards", "unknown access order `backwards`"),
+            ("-1,x", "invalid aggressor `x`"),
+            ("-1*y,+1", "invalid aggressor `-1*y`"),
+            ("", "invalid aggressor ``"),
+            ("-1,0,+1", "an aggressor can't be the victim row"),
+            ("-1*0,+1", "the aggressor at distance -1 has weight 0"),
+            ("-1,+1,-1", "distance -1 is given more than once"),
+            // Repeated accesses to a row only hit the open row
+            (
+                "+3*2:interleaved",
+                "the interleaved order accesses the aggressor at distance +3",
+            ),
+            (
+                "+1",
+                "the interleaved order accesses the aggressor at distance +1",
+            ),
+            (
+                "-1*3,+1",
+                "the interleaved order accesses the aggressor at distance -1",
+            ),
+            (
+                "half-double:sequential",
+                "the sequential order accesses the aggressor at distance -2",
+            ),
+            (
+                "-1*3,+1:random",
+                "the random order accesses the aggressor at distance -1",
+            ),
+        ];
+        for (description, error) in cases {
+            let result = parse_pattern(description).unwrap_err();
+            assert!(result.starts_with(error), "{}: {}", description, result);
+        }
+    }
+
+    #[test]
+    fn needs_an_aggressor() {
+        assert!(HammerPattern::new(Vec::new(), AccessOrder::Interleaved).is_err());
+    }
+
+    #[test]
+    fn orders_the_accesses() {
+        let weights = aggressors(&[(-1, 3), (1, 2), (2, 3)]);
+        let interleaved = HammerPattern::new(weights.clone(), AccessOrder::Interleaved).unwrap();
+        assert_eq!(interleaved.sequence, [0, 1, 2, 0, 1, 2, 0, 2]);
+        let ones = aggressors(&[(-1, 1), (1, 1), (2, 1)]);
+        let sequential = HammerPattern::new(ones, AccessOrder::Sequential).unwrap();
+        assert_eq!(sequential.sequence, [0, 1, 2]);
+
+        // The shuffle is seeded, so it's the same every time and still has every access
+        let random = HammerPattern::new(weights.clone(), AccessOrder::Random).unwrap();
+        let again = HammerPattern::new(weights, AccessOrder::Random).unwrap();
+        assert_eq!(random.sequence, again.sequence);
+        let mut sorted = random.sequence.clone();
+        sorted.sort();
+        assert_eq!(sorted, [0, 0, 0, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn never_accesses_an_aggressor_twice_in_a_row() {
+        for description in [
+            "double-sided",
+            "half-double",
+            "half-double:random",
+            "3-sided",
+            "9-sided:sequential",
+            "9-sided:random",
+            "-2*8,-1,+1,+2*8,+3*8:random",
+            "-1*3,+1*2,+2*3",
+            "-1*3,+1*2,+2*3:random",
+            "-1*5,+1*5:random",
+        ] {
+            let pattern = parse_pattern(description).unwrap();
+            let sequence = pattern.sequence.iter().chain(&pattern.sequence[..1]);
+            for (a, b) in sequence.clone().zip(sequence.skip(1)) {
+                assert_ne!(a, b, "{}: {:?}", description, pattern.sequence);
+            }
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for description in [
+            "double-sided",
+            "half-double",
+            "5-sided:random",
+            "-4,+2:sequential",
+            "-4*3,+2*3:random",
+        ] {
+            let pattern = parse_pattern(description).unwrap();
+            assert_eq!(parse_pattern(&pattern.to_string()).unwrap(), pattern);
+        }
+        assert_eq!(
+            parse_pattern("half-double").unwrap().to_string(),
+            "-2*8,-1,+1,+2*8:interleaved"
+        );
+    }
+
+    #[test]
+    fn reach_is_the_farthest_aggressor() {
+        assert_eq!(parse_pattern("double-sided").unwrap().reach(), 1);
+        assert_eq!(parse_pattern("4-sided").unwrap().reach(), 3);
+        assert_eq!(parse_pattern("-5,+2").unwrap().reach(), 5);
+    }
+}
@@ -0,0 +1,242 @@
+use core::ffi::c_void;
+
+use std::{collections::HashSet, ops::Range};
+
+use memmap2::{Advice, MmapMut};
+use nix::sys::mman::munlock;
+
+use crate::profiler::{
+    index::MemoryIndex,
+    mapping::DramMapping,
+    utils::{collect_pages_by_row, setup_mapping, PAGE_SIZE},
+};
+use crate::Backing;
+
+/// Memory which is mapped in chunks until it holds the page frames we want. Every chunk stays
+/// mapped while growing, so the kernel has to give us new page frames for the next one.
+pub(crate) struct MappingPool {
+    backing: Backing,
+    chunks: Vec<MmapMut>,
+    /// The pages holding wanted page frames or in wanted rows
+    index: MemoryIndex,
+    /// The number of wanted page frames found
+    found: usize,
+}
+
+impl MappingPool {
+    pub(crate) fn new(backing: Backing) -> Self {
+        Self {
+            backing,
+            chunks: Vec::new(),
+            index: MemoryIndex::new(),
+            found: 0,
+        }
+    }
+
+    /// The number of wanted page frames found so far.
+    pub(crate) fn found(&self) -> usize {
+        self.found
+    }
+
+    /// Maps another `fraction_of_phys_memory` of the physical memory and keeps track of the pages
+    /// in it which hold a page frame in `wanted` or are in one of the `wanted_rows`.
+    ///
+    /// # Returns
+    /// The number of wanted page frames found in the new chunk, or why the pool can't grow any
+    /// further, e.g. because the chunk doesn't fit into the available memory anymore.
+    pub(crate) fn grow(
+        &mut self,
+        fraction_of_phys_memory: f64,
+        mapping: &dyn DramMapping,
+        wanted: &HashSet<u64>,
+        wanted_rows: &HashSet<usize>,
+    ) -> Result<usize, String> {
+        let mut chunk = setup_mapping(fraction_of_phys_memory, self.backing)?;
+        let index = collect_pages_by_row(&mut chunk, mapping, self.backing)
+            .map_err(|e| format!("Couldn't collect pages from the mapping: {}", e))?;
+        let found = self.keep_wanted(&index, mapping, wanted, wanted_rows);
+        self.chunks.push(chunk);
+        Ok(found)
+    }
+
+    /// Keeps the pages of a new chunk, indexed in `chunk`, which hold a page frame in `wanted` or
+    /// are in one of the `wanted_rows`. Page frames which are already kept aren't counted again.
+    ///
+    /// # Returns
+    /// The number of wanted page frames found in the chunk.
+    fn keep_wanted(
+        &mut self,
+        chunk: &MemoryIndex,
+        mapping: &dyn DramMapping,
+        wanted: &HashSet<u64>,
+        wanted_rows: &HashSet<usize>,
+    ) -> usize {
+        let before = self.found;
+        for row in chunk.rows() {
+            let row_wanted = wanted_rows.contains(&row.index);
+            for page in row {
+                let page_wanted = wanted.contains(&page.pfn);
+                if (page_wanted || row_wanted) && self.index.insert(page.clone(), mapping) {
+                    self.found += page_wanted as usize;
+                }
+            }
+        }
+        self.found - before
+    }
+
+    /// The pages found so far, indexed by page frame number and row.
+    pub(crate) fn index(&self) -> &MemoryIndex {
+        &self.index
+    }
+
+    /// Gives every page which doesn't hold a wanted page frame back to the kernel. Only the pages
+    /// found so far stay mapped.
+    ///
+    /// # Returns
+    /// The number of released pages.
+    pub(crate) fn release_unwanted(&self) -> std::io::Result<usize> {
+        // Huge pages from the hugetlb pool can only be released as a whole
+        let unit = if self.backing.is_hugetlb() {
+            self.backing.page_size()
+        } else {
+            PAGE_SIZE
+        };
+        let kept = self
+            .index
+            .pages()
+            .map(|page| page.virt_addr as usize / unit)
+            .collect::<HashSet<_>>();
+        let mut released = 0;
+        for chunk in &self.chunks {
+            let base = chunk.as_ptr() as usize;
+            for range in unwanted_ranges(base, chunk.len(), unit, &kept) {
+                // Locked pages can't be released
+                unsafe {
+                    munlock((base + range.start) as *const c_void, range.len())?;
+                }
+                chunk.advise_range(Advice::DontNeed, range.start, range.len())?;
+                released += range.len() / PAGE_SIZE;
+            }
+        }
+        Ok(released)
+    }
+}
+
+/// Finds the parts of the `len` bytes at `base` which don't hold a kept page, where `kept` holds
+/// the addresses of the kept pages divided by `unit`.
+///
+/// # Returns
+/// The ranges of unwanted pages, as offsets from `base`.
+fn unwanted_ranges(
+    base: usize,
+    len: usize,
+    unit: usize,
+    kept: &HashSet<usize>,
+) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for offset in (0..=len).step_by(unit) {
+        let unwanted = offset < len && !kept.contains(&((base + offset) / unit));
+        match (start, unwanted) {
+            (None, true) => start = Some(offset),
+            (Some(from), false) => {
+                ranges.push(from..offset);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::{mapping::get_mapping, utils::Page};
+    use crate::Bridge;
+
+    const BASE: usize = 0x7f00_0000_0000;
+    /// Haswell with one DIMM has 128 KiB rows, so row `r` starts at page frame `r << 5`.
+    const ROW_PAGES: u64 = 32;
+
+    /// Indexes a chunk holding `pfns`, one page after the other from `first`.
+    fn chunk(first: usize, pfns: &[u64]) -> MemoryIndex {
+        let mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        let mut index = MemoryIndex::new();
+        for (i, pfn) in pfns.iter().enumerate() {
+            let page = Page::new((BASE + (first + i) * PAGE_SIZE) as *mut u8, *pfn);
+            index.insert(page, &mapping);
+        }
+        index
+    }
+
+    #[test]
+    fn keeps_wanted_pages() {
+        let mapping = get_mapping(Bridge::Haswell, None, 1).unwrap();
+        let mut pool = MappingPool::new(Backing::Small);
+        let wanted = HashSet::from([0x100, 0x101, 0x200, 0x300]);
+        let wanted_rows = HashSet::from([0x400 / ROW_PAGES as usize]);
+
+        let first = chunk(0, &[0x100, 0x150, 0x200, 0x400, 0x401]);
+        assert_eq!(pool.keep_wanted(&first, &mapping, &wanted, &wanted_rows), 2);
+        // Pages in wanted rows are kept but not counted as found
+        assert_eq!((pool.found(), pool.index().page_count()), (2, 4));
+
+        // The same page frame again doesn't count twice
+        let second = chunk(5, &[0x100, 0x101, 0x402, 0x999]);
+        assert_eq!(
+            pool.keep_wanted(&second, &mapping, &wanted, &wanted_rows),
+            1
+        );
+        assert_eq!((pool.found(), pool.index().page_count()), (3, 6));
+        assert_eq!(
+            pool.index().page(0x100).unwrap().virt_addr as usize,
+            BASE,
+            "the first page holding a page frame is kept"
+        );
+        assert!(pool.index().page(0x150).is_none());
+        assert!(pool.index().page(0x999).is_none());
+
+        assert_eq!(
+            pool.keep_wanted(&MemoryIndex::new(), &mapping, &wanted, &wanted_rows),
+            0
+        );
+    }
+
+    #[test]
+    fn releases_everything_but_kept_pages() {
+        let page = |i: usize| (BASE + i * PAGE_SIZE) / PAGE_SIZE;
+        let len = 8 * PAGE_SIZE;
+        let offsets = |pages: &[(usize, usize)]| {
+            pages
+                .iter()
+                .map(|&(from, to)| from * PAGE_SIZE..to * PAGE_SIZE)
+                .collect::<Vec<_>>()
+        };
+        let cases = [
+            (vec![], offsets(&[(0, 8)])),
+            (vec![0, 1, 2, 3, 4, 5, 6, 7], vec![]),
+            (vec![0, 7], offsets(&[(1, 7)])),
+            (vec![2, 3, 6], offsets(&[(0, 2), (4, 6), (7, 8)])),
+            // Pages of other chunks don't matter
+            (vec![8, 100], offsets(&[(0, 8)])),
+        ];
+        for (kept, expected) in cases {
+            let kept_set = kept.iter().map(|i| page(*i)).collect::<HashSet<_>>();
+            assert_eq!(
+                unwanted_ranges(BASE, len, PAGE_SIZE, &kept_set),
+                expected,
+                "{:?}",
+                kept
+            );
+        }
+
+        // Hugetlb pages are only released as a whole, a kept page keeps its huge page
+        let huge = 0x20_0000;
+        let kept = HashSet::from([(BASE + huge + 5 * PAGE_SIZE) / huge]);
+        assert_eq!(
+            unwanted_ranges(BASE, 3 * huge, huge, &kept),
+            [0..huge, 2 * huge..3 * huge]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fmt, io};
+
+use memmap2::MmapMut;
+use nix::libc::{self, c_uchar, c_void};
+
+use crate::profiler::{
+    pagemap::{pagemap_threads, scan_pagemap, PagemapEntry},
+    utils::{locked_bytes, PAGE_SIZE},
+};
+use crate::Backing;
+
+/// How many times pages which aren't in memory are faulted in again before giving up
+const RESIDENCY_RETRIES: usize = 3;
+
+/// How many pages of a mapping are actually in memory, according to `mincore` and the pagemap.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Residency {
+    pub(crate) pages: usize,
+    pub(crate) swapped: usize,
+    /// Pages which are neither in memory nor swapped out, e.g. never touched or reclaimed
+    pub(crate) not_present: usize,
+    /// Pages outside the locked part of the mapping, which may be swapped out later
+    pub(crate) not_locked: usize,
+    /// Pages which had to be faulted in again
+    pub(crate) faulted_in: usize,
+}
+
+impl Residency {
+    /// Whether every page is in memory.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.swapped == 0 && self.not_present == 0
+    }
+}
+
+impl fmt::Display for Residency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} pages resident ({} swapped, {} not present, {} not locked, {} faulted in \
+             again)",
+            self.pages - self.swapped - self.not_present,
+            self.pages,
+            self.swapped,
+            self.not_present,
+            self.not_locked,
+            self.faulted_in
+        )
+    }
+}
+
+/// Asks the kernel which pages of `mmap` are in memory.
+fn mincore(mmap: &MmapMut) -> io::Result<Vec<bool>> {
+    let mut vec = vec![0 as c_uchar; mmap.len().div_ceil(PAGE_SIZE)];
+    let result =
+        unsafe { libc::mincore(mmap.as_ptr() as *mut c_void, mmap.len(), vec.as_mut_ptr()) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(vec.into_iter().map(|page| page & 1 != 0).collect())
+}
+
+/// Counts the pages which aren't in memory, given whether `mincore` reports each page as resident,
+/// its pagemap entry and how many pages of the mapping are locked. `locked_pages` is `None` for
+/// hugetlb pages, which are never counted as locked but can't be swapped out anyway.
+///
+/// # Returns
+/// The indices of the pages which aren't in memory, and the residency of the mapping.
+fn count_residency(
+    resident: &[bool],
+    entries: &[PagemapEntry],
+    locked_pages: Option<usize>,
+) -> (Vec<usize>, Residency) {
+    let mut missing = Vec::new();
+    let mut residency = Residency {
+        pages: resident.len(),
+        ..Default::default()
+    };
+    for (i, (resident, entry)) in resident.iter().zip(entries).enumerate() {
+        if entry.swapped {
+            residency.swapped += 1;
+        } else if !resident || !entry.present {
+            residency.not_present += 1;
+        } else {
+            continue;
+        }
+        missing.push(i);
+    }
+    if let Some(locked_pages) = locked_pages {
+        residency.not_locked = residency.pages.saturating_sub(locked_pages);
+    }
+    (missing, residency)
+}
+
+/// Finds the pages of `mmap`, backed by `backing`, which aren't in memory.
+///
+/// # Returns
+/// The indices of the pages which aren't in memory, and the residency of the mapping.
+fn check_residency(mmap: &MmapMut, backing: Backing) -> io::Result<(Vec<usize>, Residency)> {
+    let resident = mincore(mmap)?;
+    let scan = scan_pagemap(mmap.as_ptr(), mmap.len(), pagemap_threads())?;
+    let locked_pages = (!backing.is_hugetlb())
+        .then(|| locked_bytes(mmap).unwrap_or_default() as usize / PAGE_SIZE);
+    Ok(count_residency(&resident, &scan.entries, locked_pages))
+}
+
+/// Checks that every page of `mmap`, backed by `backing`, is in memory, faulting the missing ones
+/// in again (and locking the mapping again) up to `RESIDENCY_RETRIES` times.
+///
+/// # Returns
+/// The residency after the last retry.
+pub(crate) fn ensure_resident(mmap: &mut MmapMut, backing: Backing) -> io::Result<Residency> {
+    let mut faulted_in = 0;
+    for _ in 0..RESIDENCY_RETRIES {
+        let (missing, residency) = check_residency(mmap, backing)?;
+        if missing.is_empty() && residency.not_locked == 0 {
+            return Ok(Residency {
+                faulted_in,
+                ..residency
+            });
+        }
+        let base_ptr = mmap.as_mut_ptr();
+        for i in &missing {
+            unsafe {
+                let page = base_ptr.add(i * PAGE_SIZE);
+                page.write_volatile(page.read_volatile());
+            }
+        }
+        faulted_in += missing.len();
+        if residency.not_locked > 0 {
+            // Fails the same way as the first time if RLIMIT_MEMLOCK is too low
+            let _ = mmap.lock();
+        }
+    }
+    let (_, residency) = check_residency(mmap, backing)?;
+    Ok(Residency {
+        faulted_in,
+        ..residency
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESENT: u64 = 1 << 63;
+    const SWAPPED: u64 = 1 << 62;
+
+    #[test]
+    fn counts_missing_pages() {
+        let entries = [
+            PRESENT | 0x1000,
+            SWAPPED,
+            0,
+            PRESENT | 0x1001,
+            PRESENT | 0x1002,
+        ]
+        .map(PagemapEntry::parse);
+        // mincore and the pagemap disagree about the fourth page, which counts as missing
+        let resident = [true, false, false, false, true];
+        let cases = [
+            (Some(5), vec![1, 2, 3], (1, 2, 0)),
+            (Some(3), vec![1, 2, 3], (1, 2, 2)),
+            (Some(0), vec![1, 2, 3], (1, 2, 5)),
+            // Hugetlb pages are never counted as locked
+            (None, vec![1, 2, 3], (1, 2, 0)),
+        ];
+        for (locked_pages, expected_missing, (swapped, not_present, not_locked)) in cases {
+            let (missing, residency) = count_residency(&resident, &entries, locked_pages);
+            assert_eq!(missing, expected_missing);
+            assert_eq!(residency.pages, 5);
+            assert_eq!(
+                (
+                    residency.swapped,
+                    residency.not_present,
+                    residency.not_locked
+                ),
+                (swapped, not_present, not_locked),
+                "{:?}",
+                locked_pages
+            );
+            assert!(!residency.is_complete());
+        }
+    }
+
+    #[test]
+    fn counts_resident_pages() {
+        let entries = [PRESENT | 0x1000, PRESENT | 0x1001].map(PagemapEntry::parse);
+        for locked_pages in [None, Some(2), Some(4)] {
+            let (missing, residency) = count_residency(&[true, true], &entries, locked_pages);
+            assert!(missing.is_empty());
+            assert!(residency.is_complete());
+            assert_eq!(residency.not_locked, 0);
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::{
     mem::size_of_val,
     path::Path,
+    process,
     time::{Duration, Instant},
 };
 
@@ -11,25 +12,30 @@ use memmap2::MmapMut;
 use procfs::ProcResult;
 use rand::seq::SliceRandom;
 
-use crate::profiler::utils::{rowpress, NO_OF_READS};
-use crate::AttackMethod;
-use crate::{
-    profiler::utils::{
-        self, collect_pages_by_row, count_flips_by_bit, rowhammer, setup_mapping, Page, PageData,
-    },
-    Bridge,
+use crate::profiler::inferred::{self, pfns_available};
+use crate::profiler::mapping::DramMapping;
+use crate::profiler::migration::{moved_pages, moved_pages_in, warn_moved_pages};
+use crate::profiler::numa::node_string;
+use crate::profiler::pattern::Hammering;
+use crate::profiler::residency::ensure_resident;
+use crate::profiler::utils::{
+    self, collect_pages_by_row, count_flips_by_bit_in_lines, setup_mapping, Page, PageData,
 };
+use crate::Backing;
 
 // const OFF_ON: u16 = 0x5555;
 // const ON_OFF: u16 = 0xaaaa;
 // const STRIPE: u16 = 0x00FF;
 // const FRODO_HAMMER: u16 = 0x0100;
 const BLAST: u16 = u16::MAX;
-const INIT_PATTERN: u16 = 0x0;
-const PATTERN: u16 = BLAST;
+/// How often the page frames and residency of the whole mapping are checked, as compaction, NUMA
+/// balancing or KSM may move pages and reclaim may swap them out during a run
+const MAPPING_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+pub(crate) const INIT_PATTERN: u16 = 0x0;
+pub(crate) const PATTERN: u16 = BLAST;
 
 /// Initializes all halfword (16-bit) values in `row` to `pattern`.
-fn init_row(row: &[Page], pattern: u16) {
+pub(crate) fn init_row(row: &[Page], pattern: u16) {
     for page in row {
         let base_ptr = page.virt_addr as *mut u16;
         for i in 0..utils::PAGE_SIZE / size_of_val(&pattern) {
@@ -40,90 +46,109 @@ fn init_row(row: &[Page], pattern: u16) {
     }
 }
 
-/// Finds two pages in `row` which are in the same bank as `target`.
+/// Finds cache lines in two different pages in `row` which are in bank `bank_index`.
 ///
 /// # Returns
-/// A tuple of two pages in the same bank as `target`, sorted by their page frame number (PFN).
+/// A tuple of two (page, line) pairs in bank `bank_index`, sorted by their page frame number (PFN).
 fn find_pfns_in_same_bank<'a>(
-    target: &Page,
+    bank_index: u8,
     row: &'a [Page],
-    bridge: Bridge,
-    dimms: u8,
-) -> (&'a Page, &'a Page) {
-    let mut pages = (None, None);
-    for page in row {
-        if page.bank_index(bridge, dimms) == target.bank_index(bridge, dimms) {
-            if pages.0.is_none() {
-                pages.0 = Some(page);
-            } else {
-                pages.1 = Some(page);
-                break;
-            }
-        }
-    }
-    match pages {
-        (Some(p1), Some(p2)) => {
-            if p1.pfn < p2.pfn {
-                (p1, p2)
+    mapping: &dyn DramMapping,
+) -> ((&'a Page, usize), (&'a Page, usize)) {
+    let mut lines = row
+        .iter()
+        .filter_map(|page| Some((page, page.lines_in_bank(bank_index, mapping).next()?)));
+    match (lines.next(), lines.next()) {
+        (Some(l1), Some(l2)) => {
+            if l1.0.pfn < l2.0.pfn {
+                (l1, l2)
             } else {
-                (p2, p1)
+                (l2, l1)
             }
         }
         _ => panic!("Couldn't find two pages in the same bank!"),
     }
 }
 
-/// Collects all pages in `pages` by their bank index.
+/// Collects all cache lines of the pages in `pages` by their bank index.
 ///
 /// # Returns
-/// A vector of vectors of pages, where each inner vector contains all pages in the same bank. The
-/// index of the inner vector is the bank index.
-fn get_pages_by_bank<'a>(pages: &'a [Page], bridge: Bridge, dimms: u8) -> Vec<Vec<&'a Page>> {
-    let mut pages_by_bank = Vec::new();
+/// A vector of vectors of (page, line) pairs, where each inner vector contains all cache lines in
+/// the same bank. The index of the inner vector is the bank index.
+fn get_lines_by_bank<'a>(
+    pages: &'a [Page],
+    mapping: &dyn DramMapping,
+) -> Vec<Vec<(&'a Page, usize)>> {
+    let mut lines_by_bank = Vec::new();
     for page in pages {
-        let bank = page.bank_index(bridge, dimms) as usize;
-        if pages_by_bank.len() <= bank {
-            pages_by_bank.resize(bank + 1, Vec::new());
+        for line in 0..utils::LINES_PER_PAGE {
+            let bank = page.line_bank_index(line, mapping) as usize;
+            if lines_by_bank.len() <= bank {
+                lines_by_bank.resize(bank + 1, Vec::new());
+            }
+            lines_by_bank[bank].push((page, line));
         }
-        pages_by_bank[bank].push(page);
     }
-    pages_by_bank
+    lines_by_bank
 }
 
 fn hammer_all_reachable_pages(
     mmap: &mut MmapMut,
     _cores: u8,
-    dimms: u8,
-    bridge: Bridge,
+    mapping: &dyn DramMapping,
     output: impl AsRef<Path>,
-    attack_method: AttackMethod,
+    hammering: &Hammering,
+    backing: Backing,
 ) -> ProcResult<()> {
-    let row_size = 128 * 1024 * dimms as usize;
+    let row_size = mapping.row_size();
 
     // let mut outfile = std::fs::File::create(output)?;
 
     let data_file = Path::new("std.out");
 
+    // Pages which aren't in memory have no page frame and would silently be left out of the rows
+    println!("Checking that the mapping is in memory...");
+    let residency = ensure_resident(mmap, backing)?;
+    println!("{}", residency);
+    if !residency.is_complete() {
+        eprintln!(
+            "[!] {} pages aren't in memory and are left out",
+            residency.swapped + residency.not_present
+        );
+    }
+
+    // Without root the page frame numbers read as zero, so the rows have to be found by timing
+    if !pfns_available(mmap) {
+        println!("Inferring rows from row buffer conflicts instead of physical addresses...");
+        inferred::hammer_all_reachable_pages(mmap, output, hammering, backing)?;
+        return Ok(());
+    }
+
     println!("Collecting all pages in all rows...");
 
-    let pages_by_row = collect_pages_by_row(mmap, row_size)?;
+    let mut pages_by_row = collect_pages_by_row(mmap, mapping, backing)?;
 
     // If we don't have at least 3 rows we can't hammer rows. The reason for not getting rows is probably
     // that we're not running as root.
-    if pages_by_row.len() < 3 {
+    if pages_by_row.row_count() < 3 {
         eprintln!(
             "[!] Can't hammer rows - only got {} rows total. Make sure you're running as sudo!",
-            pages_by_row.len()
+            pages_by_row.row_count()
         );
         return Ok(());
     }
 
-    println!("Starting rowhammer test...");
+    println!(
+        "Starting rowhammer test with pattern {}...",
+        hammering.pattern
+    );
 
     // Initializing loop variables
     let mut total_flips = 0;
+    let mut flips_by_bank = BTreeMap::new();
     let mut rows_skipped = 0;
     let mut tested_rows = HashSet::new();
+    let mut last_mapping_check = Instant::now();
 
     if let Ok(indata) = File::open(&data_file) {
         let reader = BufReader::new(indata);
@@ -147,6 +172,7 @@ fn hammer_all_reachable_pages(
         .append(true)
         .open(&data_file)
         .expect("Couldn't open data file");
+    writeln!(status_file, "Residency: {}", residency)?;
 
     let mut outfile = OpenOptions::new()
         .create(true)
@@ -158,65 +184,120 @@ fn hammer_all_reachable_pages(
     let width = 12;
     writeln!(
         outfile,
-        "\t{:<width$}{:<width$}{:<width$}{:<width$}{:<width$}{:<7}{}",
-        "Page", "aPFN1", "aPFN2", "bPFN1", "bPFN2", "Flips", "Flipped bits"
+        "\t{:<width$}{:<width$}{:<width$}{:<width$}{:<width$}{:<7}{}\t{}\t{}",
+        "Page",
+        "aPFN1",
+        "aPFN2",
+        "bPFN1",
+        "bPFN2",
+        "Flips",
+        "Flipped bits",
+        "DRAM address",
+        "NUMA node"
     )?;
 
     // Shuffle the row indices so we hammer the rows in a random order
     let mut rng = rand::thread_rng();
-    let mut indices = (0..pages_by_row.len() - 2).collect::<Vec<_>>();
+    let mut indices = pages_by_row.rows().map(|row| row.index).collect::<Vec<_>>();
     indices.shuffle(&mut rng);
 
-    'main: for above_row_index in indices {
-        let target_row_index = above_row_index + 1;
-        let below_row_index = above_row_index + 2;
-
+    'main: for target_row_index in indices {
         if tested_rows.contains(&target_row_index) {
             println!("[!] Row {} already tested, skipping...", target_row_index);
             rows_skipped += 1;
             continue;
         }
 
-        let above_row = &pages_by_row[above_row_index];
-        let target_row = &pages_by_row[target_row_index];
-        let below_row = &pages_by_row[below_row_index];
+        if last_mapping_check.elapsed() >= MAPPING_CHECK_INTERVAL {
+            let residency = ensure_resident(mmap, backing)?;
+            writeln!(status_file, "Residency: {}", residency)?;
+            let moved = moved_pages_in(mmap, &pages_by_row)?;
+            if !moved.is_empty() {
+                warn_moved_pages(&mut status_file, &moved, "since the mapping was indexed")?;
+            }
+            // Pages faulted in again most likely got other page frames
+            if !moved.is_empty() || residency.faulted_in > 0 {
+                pages_by_row = collect_pages_by_row(mmap, mapping, backing)?;
+            }
+            last_mapping_check = Instant::now();
+        }
+
+        // The aggressors have to be in the DRAM rows physically around the target row, which
+        // depend on the rank if odd ranks mirror or invert their row address. The rows directly
+        // above and below are needed for the output even if the pattern doesn't hammer them.
+        let mut rows_by_rank = Vec::new();
+        let mut aggressors_by_rank = Vec::new();
+        for rank in 0..mapping.ranks() {
+            let Some(rows) = pages_by_row.adjacent_rows(mapping, target_row_index, rank) else {
+                rows_skipped += 1;
+                continue 'main;
+            };
+            let Some(aggressors) = hammering
+                .pattern
+                .distances()
+                .map(|d| pages_by_row.neighbour_row(mapping, target_row_index, rank, d))
+                .collect::<Option<Vec<_>>>()
+            else {
+                rows_skipped += 1;
+                continue 'main;
+            };
+            rows_by_rank.push(rows);
+            aggressors_by_rank.push(aggressors);
+        }
+        let target_row = rows_by_rank[0].1;
 
         // If any of the rows are not full we can't hammer them, so continue to the next iteration
-        for i in 0..3 {
-            if pages_by_row[above_row_index + i].len() != row_size as usize / utils::PAGE_SIZE {
+        for row in aggressors_by_rank
+            .iter()
+            .flatten()
+            .chain([&target_row])
+            .chain(
+                rows_by_rank
+                    .iter()
+                    .flat_map(|(above, _, below)| [above, below]),
+            )
+        {
+            if row.len() != row_size / utils::PAGE_SIZE {
                 rows_skipped += 1;
                 continue 'main;
             }
         }
 
-        // Initialize rows (above and below get aggressor pattern, i.e. 0b0101, and target row gets zeroed)
-        init_row(&above_row[..], PATTERN);
+        // Initialize rows (aggressors get aggressor pattern, i.e. 0b0101, and target row gets zeroed)
         init_row(&target_row[..], INIT_PATTERN);
-        init_row(&below_row[..], PATTERN);
-
-        // Collect a list of addresses that are in the same bank
-        let above_pages_by_bank = get_pages_by_bank(&above_row[..], bridge, dimms);
-        let below_pages_by_bank = get_pages_by_bank(&below_row[..], bridge, dimms);
+        for row in aggressors_by_rank.iter().flatten() {
+            init_row(&row[..], PATTERN);
+        }
 
         // RELEASE THE BEAST
         let before = Instant::now();
-        for (above, below) in above_pages_by_bank
-            .iter()
-            .map(|p| p.first())
-            .zip(below_pages_by_bank.iter().map(|p| p.first()))
-        {
-            // We only need to hammer one page in each bank as each row access hammers the entire row,
-            // so we pick the first one
-            match (above, below) {
-                (Some(a), Some(b)) => {
-                    match attack_method {
-                        AttackMethod::RowHammer => rowhammer(a.virt_addr, b.virt_addr, NO_OF_READS),
-                        AttackMethod::RowPress => {
-                            rowpress(a.virt_addr, b.virt_addr, 800_000, 3, 32)
-                        }
-                    };
+        for (rank, aggressors) in aggressors_by_rank.iter().enumerate() {
+            // Collect a list of cache lines that are in the same bank for every aggressor row
+            let lines_by_bank = aggressors
+                .iter()
+                .map(|row| get_lines_by_bank(&row[..], mapping))
+                .collect::<Vec<_>>();
+
+            for bank in 0..lines_by_bank.iter().map(Vec::len).min().unwrap_or(0) {
+                // We only need to hammer one cache line in each bank as each row access hammers
+                // the entire row, so we pick the first one. These rows are only around the target
+                // row in banks of this rank.
+                let Some(lines) = lines_by_bank
+                    .iter()
+                    .map(|lines| lines[bank].first())
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    continue;
+                };
+                let (page, line) = lines[0];
+                if page.line_dram_address(*line, mapping).rank as usize != rank {
+                    continue;
                 }
-                _ => continue,
+                let addrs = lines
+                    .iter()
+                    .map(|(page, line)| page.line_addr(*line) as *const u8)
+                    .collect::<Vec<_>>();
+                hammering.hammer(&addrs);
             }
         }
 
@@ -237,6 +318,25 @@ fn hammer_all_reachable_pages(
         //     continue 'main;
         // }
 
+        // Flips in pages which moved while hammering would be attributed to the wrong page frames
+        let moved = moved_pages(
+            rows_by_rank
+                .iter()
+                .flat_map(|&(above_row, target_row, below_row)| [above_row, target_row, below_row])
+                .chain(aggressors_by_rank.iter().flatten().copied())
+                .flatten(),
+        )?;
+        if !moved.is_empty() {
+            warn_moved_pages(
+                &mut status_file,
+                &moved,
+                &format!("while hammering row {}", target_row_index),
+            )?;
+            pages_by_row = collect_pages_by_row(mmap, mapping, backing)?;
+            rows_skipped += 1;
+            continue;
+        }
+
         tested_rows.insert(target_row_index);
 
         writeln!(
@@ -246,48 +346,48 @@ fn hammer_all_reachable_pages(
             before.elapsed(),
         )?;
 
-        // Count the number of flipped bits in the target row after each test and sets which are above and below pages
-        let mut target_row = target_row.clone();
-        for target_page in &mut target_row {
-            let above_pages = find_pfns_in_same_bank(target_page, &above_row[..], bridge, dimms);
-            let below_pages = find_pfns_in_same_bank(target_page, &below_row[..], bridge, dimms);
-
-            let (flips, flip_offsets) = count_flips_by_bit(target_page, INIT_PATTERN);
-            match target_page.data {
-                Some(ref mut data) => {
-                    for (old_flip, new_flip) in data.flips.iter_mut().zip(flips) {
-                        *old_flip += new_flip;
-                    }
+        // Count the number of flipped bits in the target row after each test, separately for
+        // every bank a target page is spread over, and write the results to the output file
+        for target_page in target_row {
+            for bank in target_page.banks(mapping) {
+                let (flips, flip_offsets) =
+                    count_flips_by_bit_in_lines(target_page, INIT_PATTERN, |line| {
+                        target_page.line_bank_index(line, mapping) == bank
+                    });
+                let flip_sum = flips.iter().sum::<u64>();
+                total_flips += flip_sum;
+                if flip_sum == 0 {
+                    continue;
                 }
-                None => {
-                    target_page.data = Some(PageData::new(
-                        (above_pages.0.pfn, above_pages.1.pfn),
-                        (below_pages.0.pfn, below_pages.1.pfn),
-                        flips,
-                        flip_offsets,
-                    ));
-                }
-            }
-            total_flips += flips.iter().sum::<u64>();
-        }
 
-        // Write the results to the output file
-        for page in &target_row {
-            let flips = page.data.as_ref().unwrap().flips;
-            let flip_sum = flips.iter().sum::<u64>();
-            if flip_sum > 0 {
-                let data = page.data.as_ref().unwrap();
+                let victim_line = target_page
+                    .lines_in_bank(bank, mapping)
+                    .next()
+                    .expect("Bank should contain at least one line of the page");
+                let dram_address = target_page.line_dram_address(victim_line, mapping);
+                let (above_row, _, below_row) = rows_by_rank[dram_address.rank as usize];
+                let above_lines = find_pfns_in_same_bank(bank, &above_row[..], mapping);
+                let below_lines = find_pfns_in_same_bank(bank, &below_row[..], mapping);
+                let data = PageData::new(
+                    (above_lines.0 .0.pfn, above_lines.1 .0.pfn),
+                    (below_lines.0 .0.pfn, below_lines.1 .0.pfn),
+                    flips,
+                    flip_offsets,
+                );
                 writeln!(
                     outfile,
-                    ">\t{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<7}{:?}",
-                    page.pfn,
+                    ">\t{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<#width$x}{:<7}{:?}\t{}\t{}",
+                    target_page.pfn,
                     data.above_pfns.0,
                     data.above_pfns.1,
                     data.below_pfns.0,
                     data.below_pfns.1,
                     flip_sum,
-                    flips,
+                    data.flips,
+                    dram_address,
+                    node_string(target_page.virt_addr),
                 )?;
+                *flips_by_bank.entry(dram_address.bank_string()).or_insert(0) += flip_sum;
             }
         }
 
@@ -300,11 +400,17 @@ fn hammer_all_reachable_pages(
             total_flips,
             pages_tested,
         )?;
+        if !flips_by_bank.is_empty() {
+            writeln!(status_file, "        Flips by bank:")?;
+            for (bank, flips) in &flips_by_bank {
+                writeln!(status_file, "            {}: {}", bank, flips)?;
+            }
+        }
         let rows_analyzed = tested_rows.len() + rows_skipped;
         writeln!(
             status_file,
             "        {:.2}% of allocated memory analyzed ({:.2}% tested, {:.2}% skipped)\n",
-            rows_analyzed as f64 * 100.0 / pages_by_row.len() as f64,
+            rows_analyzed as f64 * 100.0 / pages_by_row.row_count() as f64,
             tested_rows.len() as f64 / rows_analyzed as f64 * 100.0,
             rows_skipped as f64 / rows_analyzed as f64 * 100.0,
         )?;
@@ -316,12 +422,15 @@ fn hammer_all_reachable_pages(
 pub(crate) fn main(
     fraction_of_phys_memory: f64,
     cores: u8,
-    dimms: u8,
-    bridge: Bridge,
+    mapping: &dyn DramMapping,
     output: impl AsRef<Path>,
-    attack_method: AttackMethod,
+    hammering: &Hammering,
+    backing: Backing,
 ) {
     println!("Setting up memory mapping...");
-    let mut mmap = setup_mapping(fraction_of_phys_memory);
-    hammer_all_reachable_pages(&mut mmap, cores, dimms, bridge, output, attack_method).unwrap();
+    let mut mmap = setup_mapping(fraction_of_phys_memory, backing).unwrap_or_else(|e| {
+        eprintln!("[!] {}", e);
+        process::exit(1);
+    });
+    hammer_all_reachable_pages(&mut mmap, cores, mapping, output, hammering, backing).unwrap();
 }
@@ -0,0 +1,95 @@
+use std::{
+    arch::x86_64::{__rdtscp, _mm_clflush, _mm_lfence, _mm_mfence},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Fraction of the slowest measurements ignored when looking for the conflict threshold, as
+/// interrupts and TLB misses cause a few measurements to be far slower than a row conflict.
+const OUTLIER_FRACTION: f64 = 0.005;
+
+/// Measures how many cycles it takes to read `a` and `b` directly from DRAM.
+///
+/// Both addresses are flushed from the cache before every round, so if they are in the same bank
+/// but in different rows the second read has to wait for the row buffer to be closed and
+/// reopened, which makes the access measurably slower (a row buffer conflict).
+///
+/// # Returns
+/// The median of `rounds` measurements, in cycles.
+pub(crate) fn measure_access_time(a: *const u8, b: *const u8, rounds: usize) -> u64 {
+    let mut times = Vec::with_capacity(rounds);
+    let mut aux = 0;
+    for _ in 0..rounds {
+        unsafe {
+            _mm_clflush(a);
+            _mm_clflush(b);
+            _mm_mfence();
+            let start = __rdtscp(&mut aux);
+            _mm_lfence();
+            a.read_volatile();
+            b.read_volatile();
+            _mm_lfence();
+            let end = __rdtscp(&mut aux);
+            times.push(end.saturating_sub(start));
+        }
+    }
+    median(&mut times)
+}
+
+/// Reads `addr` directly from DRAM `samples` times in a row, recording when every read started.
+///
+/// # Returns
+/// A (timestamp, latency) pair per read, both in cycles.
+pub(crate) fn measure_latency_trace(addr: *const u8, samples: usize) -> Vec<(u64, u64)> {
+    let mut trace = Vec::with_capacity(samples);
+    let mut aux = 0;
+    for _ in 0..samples {
+        unsafe {
+            _mm_clflush(addr);
+            _mm_mfence();
+            let start = __rdtscp(&mut aux);
+            _mm_lfence();
+            addr.read_volatile();
+            _mm_lfence();
+            let end = __rdtscp(&mut aux);
+            trace.push((start, end.saturating_sub(start)));
+        }
+    }
+    trace
+}
+
+/// Estimates how many timestamp counter cycles pass per nanosecond.
+pub(crate) fn cycles_per_ns() -> f64 {
+    let mut aux = 0;
+    let start_time = Instant::now();
+    let start = unsafe { __rdtscp(&mut aux) };
+    thread::sleep(Duration::from_millis(100));
+    let end = unsafe { __rdtscp(&mut aux) };
+    (end - start) as f64 / start_time.elapsed().as_nanos() as f64
+}
+
+/// Finds the latency separating row buffer hits and different-bank accesses from row buffer
+/// conflicts.
+///
+/// The threshold is placed in the middle of the largest gap between two consecutive latencies,
+/// after ignoring the slowest outliers. Conflicts only happen within one bank, so they are always
+/// the minority and the gap is only searched for in the slower half of the latencies.
+pub(crate) fn find_conflict_threshold(latencies: &[u64]) -> Option<u64> {
+    let mut sorted = latencies.to_vec();
+    sorted.sort_unstable();
+    let keep = sorted.len() - (sorted.len() as f64 * OUTLIER_FRACTION) as usize;
+    sorted
+        .get(sorted.len() / 2..keep)?
+        .windows(2)
+        .max_by_key(|w| w[1] - w[0])
+        .filter(|w| w[1] > w[0])
+        .map(|w| w[0] + (w[1] - w[0]) / 2)
+}
+
+pub(crate) fn median(values: &mut [u64]) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    values.sort_unstable();
+    values[values.len() / 2]
+}
@@ -1,27 +1,58 @@
 use std::{
-    arch::x86_64::{_mm_clflush, _mm_lfence, _mm_mfence},
+    arch::x86_64::_mm_clflush,
     cell::RefCell,
+    fs::File,
+    io,
     mem::size_of_val,
     ops::{Range, RangeFull},
 };
 
-use memmap2::{MmapMut, MmapOptions};
+use memmap2::{Advice, MmapMut, MmapOptions};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use procfs::{
     process::{PageInfo, PageMap, Process},
     ProcResult,
 };
 use sysinfo::{System, SystemExt};
 
-use crate::Bridge;
+use crate::{
+    profiler::{
+        budget::MemoryBudget,
+        index::MemoryIndex,
+        mapping::{DramAddress, DramMapping},
+        pagemap::{pagemap_threads, scan_huge_pages, scan_pagemap},
+        pattern::{hammer_sequence, press_sequence},
+    },
+    Backing,
+};
 
 pub(crate) const MAX_BITS: usize = 16;
 pub(crate) const PAGE_SIZE: usize = 0x1000;
+/// Size of a 2 MiB huge page, from the hugetlb pool or transparent
+pub(crate) const HUGE_PAGE_SIZE: usize = 0x20_0000;
+/// Size of a 1 GiB huge page from the hugetlb pool
+pub(crate) const GIGANTIC_PAGE_SIZE: usize = 0x4000_0000;
+/// `memfd_create` flag for memory from the hugetlb pool, with log2 of the page size shifted
+/// left by `MFD_HUGE_SHIFT` (see memfd_create(2))
+const MFD_HUGETLB: u32 = 0x4;
+const MFD_HUGE_SHIFT: u32 = 26;
+pub(crate) const CACHE_LINE_SIZE: usize = 64;
+pub(crate) const LINES_PER_PAGE: usize = PAGE_SIZE / CACHE_LINE_SIZE;
 pub(crate) const NO_OF_READS: usize = 3_000_000;
+/// Iterations of `rowpress` used when there is no machine profile
+pub(crate) const ROWPRESS_ITERATIONS: usize = 800_000;
+/// Activations of each aggressor per `rowpress` iteration
+pub(crate) const ROWPRESS_ACTIVATIONS: usize = 3;
+/// Reads from each aggressor per `rowpress` activation
+pub(crate) const ROWPRESS_READS: usize = 32;
+/// Written to the aggressor pages of a victim page before hammering it
+pub(crate) const AGGRESSOR_PATTERN: u16 = 0x0100;
 
 #[derive(Clone, Debug)]
 pub(crate) struct Row {
     pages: Vec<Page>,
-    pub(crate) presumed_index: usize,
+    /// The DRAM row number of all pages in the row, according to the mapping
+    pub(crate) index: usize,
 }
 
 #[derive(Clone, Debug)]
@@ -41,10 +72,10 @@ pub(crate) struct PageData {
 }
 
 impl Row {
-    pub(crate) fn new(presumed_index: usize) -> Self {
+    pub(crate) fn new(index: usize) -> Self {
         Self {
             pages: Vec::new(),
-            presumed_index,
+            index,
         }
     }
 
@@ -57,6 +88,22 @@ impl Row {
     }
 }
 
+impl Backing {
+    /// The size of the physically contiguous chunks the memory comes in. For transparent huge
+    /// pages this is only the best case, the kernel may still give us 4 KiB pages.
+    pub(crate) fn page_size(self) -> usize {
+        match self {
+            Backing::Small => PAGE_SIZE,
+            Backing::Thp | Backing::Hugetlb2m => HUGE_PAGE_SIZE,
+            Backing::Hugetlb1g => GIGANTIC_PAGE_SIZE,
+        }
+    }
+
+    pub(crate) fn is_hugetlb(self) -> bool {
+        matches!(self, Backing::Hugetlb2m | Backing::Hugetlb1g)
+    }
+}
+
 impl std::ops::Index<usize> for Row {
     type Output = Page;
     fn index(&self, index: usize) -> &Self::Output {
@@ -108,38 +155,60 @@ impl Page {
         (self.pfn as usize * PAGE_SIZE) as *mut u8
     }
 
-    pub(crate) fn bank_index(&self, bridge: Bridge, dimms: u8) -> u8 {
+    pub(crate) fn bank_index(&self, mapping: &dyn DramMapping) -> u8 {
         let mut bank_index = self.bank_index.borrow_mut();
         match *bank_index {
             Some(b) => b,
             None => {
-                *bank_index = Some(self.calc_bank_index(bridge, dimms));
+                *bank_index = Some(mapping.bank_index(self.phys_addr() as u64));
                 bank_index.expect("Something went wrong when caching bank index")
             }
         }
     }
 
-    pub(crate) fn col(&self) -> u64 {
-        let phys_addr = self.phys_addr() as u64;
-        (phys_addr & ((0b1 << 7) - 0b1)) | ((phys_addr >> 8) & ((1 << 6) - 1) << 7)
+    pub(crate) fn dram_address(&self, mapping: &dyn DramMapping) -> DramAddress {
+        mapping.decode(self.phys_addr() as u64)
     }
 
-    fn calc_bank_index(&self, bridge: Bridge, dimms: u8) -> u8 {
-        let phys_addr = self.phys_addr() as usize;
-        let bank_bits = get_bank_bits(bridge);
-        let bank_bits = if dimms == 2 {
-            &bank_bits
-        } else {
-            &bank_bits[..bank_bits.len() - 1]
-        };
-        let mut out = 0u8;
-        for bits in bank_bits {
-            for bit in bits {
-                out ^= ((phys_addr >> bit) & 1) as u8;
-            }
-            out <<= 1;
+    /// The virtual address of cache line number `line` in the page.
+    pub(crate) fn line_addr(&self, line: usize) -> *mut u8 {
+        unsafe { self.virt_addr.add(line * CACHE_LINE_SIZE) }
+    }
+
+    fn line_phys_addr(&self, line: usize) -> u64 {
+        self.phys_addr() as u64 + (line * CACHE_LINE_SIZE) as u64
+    }
+
+    /// The bank index of cache line number `line`. Address functions using bits below bit 12
+    /// spread a page over several banks, so this can differ between lines in the same page.
+    pub(crate) fn line_bank_index(&self, line: usize, mapping: &dyn DramMapping) -> u8 {
+        match line {
+            0 => self.bank_index(mapping),
+            _ => mapping.bank_index(self.line_phys_addr(line)),
         }
-        out >> 1
+    }
+
+    pub(crate) fn line_dram_address(&self, line: usize, mapping: &dyn DramMapping) -> DramAddress {
+        mapping.decode(self.line_phys_addr(line))
+    }
+
+    /// Finds all cache lines in the page which are in bank `bank_index`.
+    pub(crate) fn lines_in_bank<'a>(
+        &'a self,
+        bank_index: u8,
+        mapping: &'a dyn DramMapping,
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..LINES_PER_PAGE).filter(move |line| self.line_bank_index(*line, mapping) == bank_index)
+    }
+
+    /// Finds every bank which at least one cache line in the page is in, in increasing order.
+    pub(crate) fn banks(&self, mapping: &dyn DramMapping) -> Vec<u8> {
+        let mut banks = (0..LINES_PER_PAGE)
+            .map(|line| self.line_bank_index(line, mapping))
+            .collect::<Vec<_>>();
+        banks.sort_unstable();
+        banks.dedup();
+        banks
     }
 }
 
@@ -159,43 +228,6 @@ impl PageData {
     }
 }
 
-pub(crate) fn get_bank_bits(bridge: Bridge) -> Vec<Vec<u8>> {
-    match bridge {
-        Bridge::Haswell => vec![
-            vec![14, 18],
-            vec![15, 19],
-            vec![16, 20],
-            vec![17, 21],
-            vec![7, 8, 9, 12, 13, 18, 19],
-        ],
-        Bridge::Sandy => vec![
-            vec![14, 18],
-            vec![15, 19],
-            vec![16, 20],
-            vec![17, 21],
-            vec![17, 21],
-            vec![6],
-        ],
-    }
-}
-
-pub(crate) fn get_block_by_order(order: u32) -> MmapMut {
-    let mem_size = PAGE_SIZE * 2_usize.pow(order);
-    let mut mmap = MmapOptions::new()
-        .len(mem_size)
-        .populate()
-        .map_anon()
-        .expect("Failed to setup memory map over block");
-
-    let ptr = mmap.as_mut_ptr();
-    for offset in (0..mmap.len()).step_by(PAGE_SIZE) {
-        unsafe {
-            *ptr.add(offset) = 1 + offset as u8;
-        }
-    }
-    mmap
-}
-
 pub(crate) fn get_phys_memory_size() -> u64 {
     let sys = System::new_all();
     sys.total_memory()
@@ -232,12 +264,25 @@ pub(crate) fn find_flips(page: &Page, initial_pattern: u16) -> Vec<(usize, usize
 pub(crate) fn count_flips_by_bit(
     page: &Page,
     initial_pattern: u16,
+) -> ([u64; MAX_BITS], Vec<usize>) {
+    count_flips_by_bit_in_lines(page, initial_pattern, |_| true)
+}
+
+/// Counts the number of flipped bits by bit like `count_flips_by_bit`, but only in the cache
+/// lines of `page` for which `include_line` returns true.
+pub(crate) fn count_flips_by_bit_in_lines(
+    page: &Page,
+    initial_pattern: u16,
+    include_line: impl Fn(usize) -> bool,
 ) -> ([u64; MAX_BITS], Vec<usize>) {
     let mut flips = [0; MAX_BITS];
     let base_ptr = page.virt_addr as *const u16;
     let mut flip_offsets = Vec::new();
 
     for i in 0..PAGE_SIZE / 2 {
+        if !include_line(i * 2 / CACHE_LINE_SIZE) {
+            continue;
+        }
         unsafe {
             let ptr = base_ptr.add(i);
             _mm_clflush(ptr as *const u8);
@@ -263,7 +308,7 @@ pub(crate) unsafe fn fill_memory(victim_va: *mut u8, above_va: *mut u8, below_va
     let above_va = above_va as *mut u16;
     let below_va = below_va as *mut u16;
 
-    let pattern = 0x0100;
+    let pattern = AGGRESSOR_PATTERN;
 
     for index in 0..PAGE_SIZE / 2 {
         unsafe {
@@ -276,15 +321,95 @@ pub(crate) unsafe fn fill_memory(victim_va: *mut u8, above_va: *mut u8, below_va
     }
 }
 
-/// Setup the memory mapping used for the memory profiling
-pub(crate) fn setup_mapping(fraction_of_phys_memory: f64) -> MmapMut {
-    let mut mmap = MmapOptions::new()
-        .len((get_phys_memory_size() as f64 * fraction_of_phys_memory) as usize)
-        .populate()
-        .map_anon()
-        .expect("failed to setup memory mapping");
+/// Setup the memory mapping used for the memory profiling, backed by `backing`. The size is
+/// rounded down to a whole number of (huge) pages.
+///
+/// # Returns
+/// The mapping, scaled down to leave some memory for the rest of the system if needed, or an
+/// error if not even a small mapping fits or there aren't enough huge pages reserved.
+pub(crate) fn setup_mapping(
+    fraction_of_phys_memory: f64,
+    backing: Backing,
+) -> Result<MmapMut, String> {
+    let page_size = backing.page_size();
+    let len =
+        (get_phys_memory_size() as f64 * fraction_of_phys_memory) as usize / page_size * page_size;
+
+    let budget = MemoryBudget::read(backing);
+    let reserve_hint = || {
+        format!(
+            "reserve more huge pages in /sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages",
+            page_size >> 10
+        )
+    };
+    let len = match budget.fit(len as u64) {
+        Ok(fitted) if fitted < len as u64 => {
+            let fitted = fitted as usize / page_size * page_size;
+            eprintln!(
+                "[!] Scaling the mapping down from {} MiB to {} MiB to stay within the available \
+                 memory",
+                len >> 20,
+                fitted >> 20
+            );
+            fitted
+        }
+        Ok(_) => len,
+        Err(e) if backing.is_hugetlb() => {
+            return Err(format!("Refusing to map memory: {}, {}", e, reserve_hint()))
+        }
+        Err(e) => return Err(format!("Refusing to map memory: {}", e)),
+    };
+    if !backing.is_hugetlb() && budget.memlock.is_some_and(|limit| len as u64 > limit) {
+        eprintln!(
+            "[!] RLIMIT_MEMLOCK is only {} KiB, so the mapping can't be locked. Raise it with \
+             `ulimit -l` or run as sudo",
+            budget.memlock.unwrap_or_default() >> 10
+        );
+    }
 
-    mmap.lock().expect("failed to lock mmap to RAM.");
+    let mut mmap = if backing.is_hugetlb() && len > 0 {
+        map_hugetlb(len, page_size).map_err(|e| {
+            format!(
+                "Couldn't map {} MiB of {} KiB huge pages: {}, {}",
+                len >> 20,
+                page_size >> 10,
+                e,
+                reserve_hint()
+            )
+        })?
+    } else if backing == Backing::Thp {
+        // Populating before the advice would fault in 4 KiB pages
+        let mmap = MmapOptions::new()
+            .len(len)
+            .map_anon()
+            .expect("failed to setup memory mapping");
+        mmap.advise(Advice::HugePage)
+            .expect("failed to advise transparent huge pages");
+        mmap
+    } else {
+        MmapOptions::new()
+            .len(len)
+            .populate()
+            .map_anon()
+            .expect("failed to setup memory mapping")
+    };
+
+    // Needs root or a high enough RLIMIT_MEMLOCK. Huge pages from the hugetlb pool can't be
+    // swapped out anyway and are never counted as locked
+    if backing.is_hugetlb() {
+        println!("Not locking the mapping, huge pages can't be swapped out");
+    } else {
+        match mmap.lock().map(|_| locked_bytes(&mmap)) {
+            Err(e) => eprintln!("[!] Couldn't lock the mapping to RAM: {}", e),
+            Ok(Some(locked)) if locked < mmap.len() as u64 => eprintln!(
+                "[!] Only {} of {} MiB of the mapping are locked to RAM",
+                locked >> 20,
+                mmap.len() >> 20
+            ),
+            Ok(Some(locked)) => println!("Locked all {} MiB of the mapping to RAM", locked >> 20),
+            Ok(None) => println!("[!] Couldn't check whether the mapping is locked to RAM"),
+        }
+    }
 
     let ptr = mmap.as_mut_ptr();
     for offset in (0..mmap.len()).step_by(PAGE_SIZE) {
@@ -293,9 +418,43 @@ pub(crate) fn setup_mapping(fraction_of_phys_memory: f64) -> MmapMut {
         }
     }
 
-    mmap
+    Ok(mmap)
+}
+
+/// Sums up the `Locked` sizes in `/proc/self/smaps` of the memory areas `mmap` is made of.
+///
+/// # Returns
+/// The number of locked bytes, or `None` if smaps couldn't be read.
+pub(crate) fn locked_bytes(mmap: &MmapMut) -> Option<u64> {
+    let start = mmap.as_ptr() as u64;
+    let end = start + mmap.len() as u64;
+    let maps = Process::myself().and_then(|process| process.smaps()).ok()?;
+    Some(
+        maps.iter()
+            .filter(|map| map.address.0 < end && map.address.1 > start)
+            .filter_map(|map| map.extension.map.get("Locked"))
+            .sum(),
+    )
 }
 
+/// Maps `len` bytes of `page_size` pages from the hugetlb pool, through a memfd since
+/// `MmapOptions` can't ask for huge anonymous pages.
+fn map_hugetlb(len: usize, page_size: usize) -> io::Result<MmapMut> {
+    let name = c"kyber-not-it";
+    let flags = MemFdCreateFlag::MFD_CLOEXEC
+        | MemFdCreateFlag::from_bits_retain(
+            MFD_HUGETLB | page_size.trailing_zeros() << MFD_HUGE_SHIFT,
+        );
+    let file = File::from(memfd_create(name, flags)?);
+    file.set_len(len as u64)?;
+    unsafe { MmapOptions::new().len(len).populate().map_mut(&file) }
+}
+
+/// Looks up the page frame number of `virtual_addr` in the pagemap.
+///
+/// # Returns
+/// The page frame number, or an error if the page isn't in memory or the process isn't allowed
+/// to see page frame numbers (they read as zero without root).
 pub(crate) fn get_page_frame_number(
     pagemap: &mut PageMap,
     virtual_addr: *const u8,
@@ -303,23 +462,21 @@ pub(crate) fn get_page_frame_number(
     match pagemap.get_info(virtual_addr as usize / PAGE_SIZE)? {
         PageInfo::MemoryPage(mempage) => {
             //println!("FLAGS: {:#?}", mempage);
-            Ok(mempage.get_page_frame_number().0)
+            match mempage.get_page_frame_number().0 {
+                0 => Err(procfs::ProcError::PermissionDenied(None)),
+                pfn => Ok(pfn),
+            }
         }
         PageInfo::SwapPage(_) => Err(procfs::ProcError::NotFound(None)),
     }
 }
 
+/// Double-sided hammering of the rows of `above_row` and `below_row`.
 pub(crate) fn rowhammer(above_row: *const u8, below_row: *const u8, iters: usize) {
-    for _ in 0..iters {
-        unsafe {
-            _mm_clflush(above_row);
-            above_row.read_volatile();
-            _mm_clflush(below_row);
-            below_row.read_volatile();
-        }
-    }
+    hammer_sequence(&[above_row, below_row], iters);
 }
 
+/// Double-sided RowPress on the rows of `above_row` and `below_row`.
 pub(crate) fn rowpress(
     above_row: *const u8,
     below_row: *const u8,
@@ -327,61 +484,64 @@ pub(crate) fn rowpress(
     aggressor_activations: usize,
     reads: usize,
 ) {
-    for _ in 0..iters {
-        unsafe {
-            _mm_lfence();
-        }
-
-        for _ in 0..aggressor_activations {
-            // Read both aggressor rows in sequence
-            for i in 0..reads {
-                unsafe {
-                    above_row.add(i).read_volatile();
-                }
-            }
-            for i in 0..reads {
-                unsafe {
-                    below_row.add(i).read_volatile();
-                }
-            }
-
-            for i in 0..reads {
-                unsafe {
-                    _mm_clflush(above_row.add(i));
-                    _mm_clflush(below_row.add(i));
-                }
-            }
-
-            unsafe {
-                _mm_mfence();
-            }
-        }
-    }
+    press_sequence(&[above_row, below_row], iters, aggressor_activations, reads);
 }
 
-pub(crate) fn collect_pages_by_row(mmap: &mut MmapMut, row_size: usize) -> ProcResult<Vec<Row>> {
+/// Indexes the pages of `mmap` by page frame number and row. Only the first page frame number of
+/// each huge page is looked up, the rest follow from the pages being physically contiguous. 4 KiB
+/// pages are looked up in large batches split over all cores, instead of one lookup per page.
+pub(crate) fn collect_pages_by_row(
+    mmap: &mut MmapMut,
+    mapping: &dyn DramMapping,
+    backing: Backing,
+) -> ProcResult<MemoryIndex> {
     let base_ptr = mmap.as_mut_ptr();
-    let mut rows = Vec::new();
-    let pagemap = &mut Process::myself()?.pagemap()?;
-
-    for offset in (0..mmap.len()).step_by(PAGE_SIZE) {
-        unsafe {
-            let virtual_addr = base_ptr.add(offset);
-            if let Ok(pfn) = get_page_frame_number(pagemap, virtual_addr) {
-                let physical_addr = pfn as usize * PAGE_SIZE;
-                let presumed_row_index = physical_addr as usize / row_size;
-                // If the row index is larger than the number of rows, we
-                // push new rows until we have enough.
-                if presumed_row_index >= rows.len() {
-                    for i in rows.len()..presumed_row_index + 1 {
-                        rows.push(Row::new(i));
-                    }
-                }
-                rows[presumed_row_index].push(Page::new(virtual_addr, pfn));
-            }
-        }
+    let mut index = MemoryIndex::new();
+    let scan = match backing {
+        Backing::Small => scan_pagemap(base_ptr, mmap.len(), pagemap_threads())?,
+        Backing::Thp | Backing::Hugetlb2m | Backing::Hugetlb1g => scan_huge_pages(
+            base_ptr,
+            mmap.len(),
+            backing.page_size(),
+            backing == Backing::Thp,
+        )?,
+    };
+    println!("{}", scan);
+
+    for (i, entry) in scan.entries.iter().enumerate() {
+        let Some(pfn) = entry.pfn() else {
+            continue;
+        };
+        let virtual_addr = unsafe { base_ptr.add(i * PAGE_SIZE) };
+        index.insert(Page::new(virtual_addr, pfn), mapping);
     }
-    Ok(rows)
+    Ok(index)
+}
+
+/// Finds the aggressor cache lines to hammer for `victim`, one set per bank the victim page is
+/// spread over. `aggressor_pages` holds the pages to pick from for every aggressor row of the
+/// hammer pattern, and a line is picked from each row which shares the bank (and on DDR4 the bank
+/// group) with the victim lines. Banks missing from any aggressor row are left out.
+pub(crate) fn aggressor_lines(
+    victim: &Page,
+    aggressor_pages: &[Vec<&Page>],
+    mapping: &dyn DramMapping,
+) -> Vec<Vec<*const u8>> {
+    victim
+        .banks(mapping)
+        .into_iter()
+        .filter_map(|bank| {
+            aggressor_pages
+                .iter()
+                .map(|pages| {
+                    pages.iter().find_map(|page| {
+                        let line = page.lines_in_bank(bank, mapping).next()?;
+                        Some(page.line_addr(line) as *const u8)
+                    })
+                })
+                .collect()
+        })
+        .collect()
 }
 
 pub(crate) fn get_phys_addr(pagemap: &mut PageMap, virtual_addr: *const u8) -> ProcResult<u64> {
@@ -389,3 +549,72 @@ pub(crate) fn get_phys_addr(pagemap: &mut PageMap, virtual_addr: *const u8) -> P
     // Physical address of frame is page_frame_number * page_size + offset
     Ok((pfn * PAGE_SIZE as u64) | (virtual_addr as usize & (0x1000 - 1)) as u64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::mapping::XorMapping;
+
+    /// Bank bit 0 is the XOR of line bit 6 and page bit 12, so every page is spread over two banks
+    /// and alternates between them with every cache line. Bank bit 1 is bit 13.
+    const SPLIT_PAGES: &str = "bank = 0x1040\nbank = 0x2000\ncolumn = 0x1fbf\nrow_size = 0x4000";
+
+    #[test]
+    fn finds_lines_in_bank() {
+        let mapping = SPLIT_PAGES.parse::<XorMapping>().unwrap();
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        let virt_addr = buffer.as_mut_ptr();
+        let even = (0..LINES_PER_PAGE).step_by(2).collect::<Vec<_>>();
+        let odd = (1..LINES_PER_PAGE).step_by(2).collect::<Vec<_>>();
+
+        // page frame, bank, lines in that bank
+        let cases = [
+            (0, 0, &even),
+            (0, 1, &odd),
+            (1, 0, &odd),
+            (1, 1, &even),
+            (2, 2, &even),
+            (2, 0, &vec![]),
+        ];
+        for (pfn, bank, lines) in cases {
+            let page = Page::new(virt_addr, pfn);
+            assert_eq!(
+                page.lines_in_bank(bank, &mapping).collect::<Vec<_>>(),
+                *lines,
+                "page frame {}, bank {}",
+                pfn,
+                bank
+            );
+        }
+        assert_eq!(Page::new(virt_addr, 0).banks(&mapping), [0, 1]);
+        assert_eq!(Page::new(virt_addr, 3).banks(&mapping), [2, 3]);
+    }
+
+    #[test]
+    fn picks_aggressor_lines_per_victim_bank() {
+        let mapping = SPLIT_PAGES.parse::<XorMapping>().unwrap();
+        let mut buffer = vec![0u8; 4 * PAGE_SIZE];
+        let base = buffer.as_mut_ptr();
+        let page = |i: usize, pfn| Page::new(unsafe { base.add(i * PAGE_SIZE) }, pfn);
+        // Row 0, in banks 0 and 1
+        let victim = page(0, 0x0);
+        // Row 1, in banks 0 and 1
+        let above = page(1, 0x4);
+        // Row 2, the first page in banks 2 and 3 and the second one in banks 1 and 0
+        let below = [page(2, 0xa), page(3, 0x9)];
+        let line = |page: &Page, line| page.line_addr(line) as *const u8;
+
+        let lines = aggressor_lines(&victim, &[vec![&above], below.iter().collect()], &mapping);
+        assert_eq!(
+            lines,
+            [
+                vec![line(&above, 0), line(&below[1], 1)],
+                vec![line(&above, 1), line(&below[1], 0)],
+            ]
+        );
+
+        // No line of the first page below shares a bank with the victim
+        let lines = aggressor_lines(&victim, &[vec![&above], vec![&below[0]]], &mapping);
+        assert!(lines.is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+use std::process;
+
+use rand::seq::SliceRandom;
+
+use crate::profiler::{
+    mapping::{mask_to_bits, xor_function, DramMapping, XorMapping},
+    timing::{find_conflict_threshold, measure_access_time},
+    utils::{collect_pages_by_row, setup_mapping, Page},
+};
+use crate::Backing;
+
+/// Rounds of timing measurements per page pair
+const ROUNDS: usize = 100;
+/// How many random pairs we try per wanted sample before giving up
+const ATTEMPTS_PER_SAMPLE: usize = 10_000;
+/// Agreement below this rate is reported as a problem with the mapping
+const AGREEMENT_THRESHOLD: f64 = 0.9;
+
+/// Picks up to `n` random pairs of pages for which `predicate` holds.
+pub(crate) fn sample_pairs<'a>(
+    pages: &[&'a Page],
+    n: usize,
+    predicate: impl Fn(&Page, &Page) -> bool,
+) -> Vec<(&'a Page, &'a Page)> {
+    let mut rng = rand::thread_rng();
+    let mut pairs = Vec::with_capacity(n);
+    for _ in 0..n * ATTEMPTS_PER_SAMPLE {
+        let pair = pages.choose_multiple(&mut rng, 2).collect::<Vec<_>>();
+        if predicate(pair[0], pair[1]) {
+            pairs.push((*pair[0], *pair[1]));
+            if pairs.len() >= n {
+                break;
+            }
+        }
+    }
+    pairs
+}
+
+/// Measures the access time of every pair in `pairs`, in cycles.
+pub(crate) fn measure_pairs(pairs: &[(&Page, &Page)]) -> Vec<u64> {
+    pairs
+        .iter()
+        .map(|(a, b)| measure_access_time(a.virt_addr, b.virt_addr, ROUNDS))
+        .collect()
+}
+
+pub(crate) fn different_rows(mapping: &dyn DramMapping, a: &Page, b: &Page) -> bool {
+    mapping.row(a.phys_addr() as u64) != mapping.row(b.phys_addr() as u64)
+}
+
+/// Checks whether function number `i` of `functions` is the only one which differs between
+/// the pages `a` and `b`.
+fn separated_only_by(functions: &[(&str, u64)], i: usize, a: &Page, b: &Page) -> bool {
+    let (a, b) = (a.phys_addr() as u64, b.phys_addr() as u64);
+    functions
+        .iter()
+        .enumerate()
+        .all(|(j, (_, mask))| (xor_function(*mask, a) != xor_function(*mask, b)) == (i == j))
+}
+
+/// Prints how many of `latencies` agree with the expectation that they are (or aren't) row
+/// conflicts.
+///
+/// # Returns
+/// The rate of agreeing measurements.
+fn report_agreement(description: &str, latencies: &[u64], threshold: u64, conflict: bool) -> f64 {
+    let agreeing = latencies
+        .iter()
+        .filter(|l| (**l >= threshold) == conflict)
+        .count();
+    let rate = agreeing as f64 / latencies.len().max(1) as f64;
+    println!(
+        "{}: {}/{} agree ({:.1}%)",
+        description,
+        agreeing,
+        latencies.len(),
+        rate * 100.0
+    );
+    rate
+}
+
+/// Checks `mapping` by timing pairs of pages it places in the same bank but in different rows,
+/// which should all be row buffer conflicts, against pairs it places in different banks, which
+/// never should be.
+pub(crate) fn main(fraction_of_phys_memory: f64, mapping: &XorMapping, samples: usize) {
+    println!("Setting up memory mapping...");
+    let mut mmap = setup_mapping(fraction_of_phys_memory, Backing::Small).unwrap_or_else(|e| {
+        eprintln!("[!] {}", e);
+        process::exit(1);
+    });
+
+    println!("Collecting all pages in all rows...");
+    let index =
+        collect_pages_by_row(&mut mmap, mapping, Backing::Small).expect("Couldn't collect pages");
+    let pages = index.pages().collect::<Vec<_>>();
+    if pages.len() < 2 {
+        eprintln!(
+            "[!] Can't verify mapping - only got {} pages. Make sure you're running as sudo!",
+            pages.len()
+        );
+        return;
+    }
+
+    println!(
+        "Measuring {} random page pairs to find the threshold...",
+        samples
+    );
+    let calibration = measure_pairs(&sample_pairs(&pages, samples, |_, _| true));
+    let Some(threshold) = find_conflict_threshold(&calibration) else {
+        eprintln!("[!] Couldn't find a row conflict threshold");
+        return;
+    };
+    println!("Row conflict threshold: {} cycles\n", threshold);
+
+    let same_bank = sample_pairs(&pages, samples, |a, b| {
+        a.bank_index(mapping) == b.bank_index(mapping) && different_rows(mapping, a, b)
+    });
+    let different_bank = sample_pairs(&pages, samples, |a, b| {
+        a.bank_index(mapping) != b.bank_index(mapping)
+    });
+    let same_bank_latencies = measure_pairs(&same_bank);
+    let different_bank_latencies = measure_pairs(&different_bank);
+
+    let same_bank_rate = report_agreement(
+        "Same bank, different rows (expecting conflicts)",
+        &same_bank_latencies,
+        threshold,
+        true,
+    );
+    let different_bank_rate = report_agreement(
+        "Different banks (expecting no conflicts)",
+        &different_bank_latencies,
+        threshold,
+        false,
+    );
+    let total = same_bank_latencies.len() + different_bank_latencies.len();
+    println!(
+        "Overall agreement: {:.1}%\n",
+        (same_bank_rate * same_bank_latencies.len() as f64
+            + different_bank_rate * different_bank_latencies.len() as f64)
+            * 100.0
+            / total.max(1) as f64
+    );
+
+    // Pairs only separated by one function should never conflict. If they do, that function
+    // doesn't actually select the bank.
+    println!("Checking each XOR function:");
+    let functions = mapping.functions();
+    let mut wrong_functions = Vec::new();
+    for (i, (kind, mask)) in functions.iter().enumerate() {
+        let pairs = sample_pairs(&pages, samples, |a, b| {
+            separated_only_by(&functions, i, a, b) && different_rows(mapping, a, b)
+        });
+        let description = format!("\t{} {:#x} (bits {:?})", kind, mask, mask_to_bits(*mask));
+        if pairs.is_empty() {
+            println!("{}: no pairs found", description);
+            continue;
+        }
+        let rate = report_agreement(&description, &measure_pairs(&pairs), threshold, false);
+        if rate < AGREEMENT_THRESHOLD {
+            wrong_functions.push((kind, mask));
+        }
+    }
+    println!();
+
+    for (kind, mask) in &wrong_functions {
+        println!(
+            "[!] The {} function {:#x} looks wrong: pages only separated by it still conflict",
+            kind, mask
+        );
+    }
+    if same_bank_rate < AGREEMENT_THRESHOLD && wrong_functions.is_empty() {
+        println!(
+            "[!] Pages in the same bank often don't conflict, the mapping is probably missing a \
+             function or has the wrong row bits. Try running `reverse-map`."
+        );
+    }
+    if same_bank_rate >= AGREEMENT_THRESHOLD
+        && different_bank_rate >= AGREEMENT_THRESHOLD
+        && wrong_functions.is_empty()
+    {
+        println!("The mapping looks correct for this machine.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Haswell with one DIMM
+    const HASWELL_1: &str = "\
+        bank = 0x22000
+        bank = 0x44000
+        bank = 0x110000
+        rank = 0x88000
+        column = 0x1fff
+        row_size = 0x20000
+    ";
+
+    #[test]
+    fn separates_pages_by_one_function() {
+        let mapping = HASWELL_1.parse::<XorMapping>().unwrap();
+        let functions = mapping.functions();
+        assert_eq!(
+            functions,
+            [
+                ("bank", 0x22000),
+                ("bank", 0x44000),
+                ("bank", 0x110000),
+                ("rank", 0x88000)
+            ]
+        );
+        let page = |pfn| Page::new(std::ptr::null_mut(), pfn);
+
+        // page frames, function, whether it is the only one separating the pages
+        let cases = [
+            ((0x0, 0x2), 0, true),
+            ((0x0, 0x2), 1, false),
+            ((0x0, 0x8), 3, true),
+            // Bits 13 and 15 are used by two different functions
+            ((0x0, 0xa), 0, false),
+            // Bits 13 and 17 cancel out in the same function
+            ((0x0, 0x22), 0, false),
+            ((0x0, 0x0), 0, false),
+        ];
+        for ((a, b), function, separated) in cases {
+            assert_eq!(
+                separated_only_by(&functions, function, &page(a), &page(b)),
+                separated,
+                "page frames {:#x} and {:#x}, function {}",
+                a,
+                b,
+                function
+            );
+        }
+
+        assert!(different_rows(&mapping, &page(0x0), &page(0x22)));
+        assert!(!different_rows(&mapping, &page(0x0), &page(0x2)));
+    }
+
+    #[test]
+    fn rates_agreement_with_threshold() {
+        let latencies = [180, 200, 350, 400];
+        assert_eq!(report_agreement("conflicts", &latencies, 300, true), 0.5);
+        assert_eq!(report_agreement("conflicts", &latencies, 200, true), 0.75);
+        assert_eq!(
+            report_agreement("no conflicts", &latencies, 200, false),
+            0.25
+        );
+        assert_eq!(
+            report_agreement("no conflicts", &latencies, 500, false),
+            1.0
+        );
+        assert_eq!(report_agreement("nothing", &[], 300, true), 0.0);
+    }
+
+    #[test]
+    fn samples_pairs_matching_predicate() {
+        let pages = (0..8)
+            .map(|pfn| Page::new(std::ptr::null_mut(), pfn))
+            .collect::<Vec<_>>();
+        let pages = pages.iter().collect::<Vec<_>>();
+
+        let pairs = sample_pairs(&pages, 20, |a, b| a.pfn % 2 == 0 && b.pfn % 2 == 1);
+        assert_eq!(pairs.len(), 20);
+        assert!(pairs.iter().all(|(a, b)| a.pfn % 2 == 0 && b.pfn % 2 == 1));
+        assert!(sample_pairs(&pages, 3, |_, _| false).is_empty());
+    }
+}
@@ -1,6 +1,12 @@
 use std::io::{self, Write};
 
-use crate::{attack, attack_tester, profiler, AttackArgs, AttackMethod, Bridge, ProfilerArgs};
+use clap::ValueEnum;
+
+use crate::{
+    attack, attack_tester,
+    profiler::{self, calibrate::load_hammer_counts, pattern::Hammering},
+    AttackArgs, AttackMethod, Bridge, ProfilerArgs,
+};
 
 fn read_line() -> String {
     let mut input = String::new();
@@ -15,6 +21,7 @@ pub(crate) fn select_command() {
 
     println!("Welcome to KyberKracker!\n");
     println!("This is a tool for profiling and attacking DRAM with RowHammer, with the goal of cracking the Kyber cipher.\n");
+    println!("Detected hardware:\n{}", profiler::detect::machine());
 
     println!("Available commands:");
     println!("\t1. Profile");
@@ -71,9 +78,9 @@ fn run_profiler() {
     println!(
         "\t1. Run with default settings (-p {} -c {} -d {} -b {:?} -o {} -a {:?})",
         opts.fraction_of_phys_memory,
-        opts.cores,
-        opts.dimms,
-        opts.bridge,
+        opts.cores(),
+        opts.dram.dimms(),
+        opts.dram.bridge(),
         opts.output,
         opts.attack_method
     );
@@ -88,11 +95,15 @@ fn run_profiler() {
             "1" => {
                 profiler::rowhammer::main(
                     opts.fraction_of_phys_memory,
-                    opts.cores,
-                    opts.dimms,
-                    opts.bridge,
+                    opts.cores(),
+                    &opts.dram.dram_mapping(),
                     opts.output,
-                    opts.attack_method,
+                    &Hammering {
+                        pattern: opts.pattern.clone(),
+                        method: opts.attack_method,
+                        counts: load_hammer_counts(),
+                    },
+                    opts.backing,
                 );
                 break;
             }
@@ -117,45 +128,50 @@ fn run_profiler() {
                         eprintln!("Input must be a valid float number");
                     }
                 };
-                opts.cores = loop {
-                    print!("Number of cores on target machine ({}): ", opts.cores);
+                opts.cores = Some(loop {
+                    print!("Number of cores on target machine ({}): ", opts.cores());
                     stdout.flush().unwrap();
                     let input = read_line();
                     if input.trim().is_empty() {
-                        break opts.cores;
+                        break opts.cores();
                     }
                     if let Ok(c) = input.trim().parse() {
                         break c;
                     } else {
                         eprintln!("Input must be an integer");
                     }
-                };
-                opts.dimms = loop {
-                    print!("Number of RAM sticks on target machine ({}): ", opts.dimms);
+                });
+                opts.dram.dimms = Some(loop {
+                    print!(
+                        "Number of RAM sticks on target machine ({}): ",
+                        opts.dram.dimms()
+                    );
                     stdout.flush().unwrap();
                     let input = read_line();
                     if input.trim().is_empty() {
-                        break opts.dimms;
+                        break opts.dram.dimms();
                     }
                     if let Ok(d) = input.trim().parse() {
                         break d;
                     } else {
                         eprintln!("Input must be an integer");
                     }
-                };
-                opts.bridge = loop {
-                    print!("Northbridge type ({:?}): ", opts.bridge);
+                });
+                opts.dram.bridge = Some(loop {
+                    print!("CPU generation ({:?}): ", opts.dram.bridge());
                     stdout.flush().unwrap();
                     let input = read_line();
                     if input.trim().is_empty() {
-                        break opts.bridge;
+                        break opts.dram.bridge();
                     }
-                    match input.trim().to_lowercase().as_str() {
-                        "haswell" => break Bridge::Haswell,
-                        "sandy" => break Bridge::Sandy,
-                        _ => eprintln!("Input must be either 'haswell' or 'sandy'"),
+                    match Bridge::from_str(input.trim(), true) {
+                        Ok(bridge) => break bridge,
+                        Err(_) => eprintln!(
+                            "Input must be one of 'sandy', 'ivy', 'haswell', 'skylake', \
+                             'coffee-lake' or 'zen'"
+                        ),
                     }
-                };
+                });
                 opts.output = loop {
                     print!("Output file ({}): ", opts.output);
                     stdout.flush().unwrap();
@@ -182,19 +198,23 @@ fn run_profiler() {
                 println!(
                     "Selected settings: -p {} -c {} -d {} -b {:?} -o {} -a {:?}",
                     opts.fraction_of_phys_memory,
-                    opts.cores,
-                    opts.dimms,
-                    opts.bridge,
+                    opts.cores(),
+                    opts.dram.dimms(),
+                    opts.dram.bridge(),
                     opts.output,
                     opts.attack_method
                 );
                 profiler::rowhammer::main(
                     opts.fraction_of_phys_memory,
-                    opts.cores,
-                    opts.dimms,
-                    opts.bridge,
+                    opts.cores(),
+                    &opts.dram.dram_mapping(),
                     opts.output,
-                    opts.attack_method,
+                    &Hammering {
+                        pattern: opts.pattern.clone(),
+                        method: opts.attack_method,
+                        counts: load_hammer_counts(),
+                    },
+                    opts.backing,
                 );
                 break;
             }
@@ -210,7 +230,7 @@ fn run_evaluation() {
     let mut stdout = io::stdout();
 
     let mut opts = ProfilerArgs::default();
-    println!("\t1. Run with default settings (-d {})", opts.dimms);
+    println!("\t1. Run with default settings (-d {})", opts.dram.dimms());
     println!("\t2. Run with custom settings\n");
 
     print!("Select command (1-2): ");
@@ -220,25 +240,35 @@ fn run_evaluation() {
         let input = read_line();
         match input.trim() {
             "1" => {
-                profiler::pagefinder::main(opts.dimms);
+                profiler::pagefinder::main(
+                    &opts.dram.dram_mapping(),
+                    opts.backing,
+                    &opts.pattern,
+                    load_hammer_counts().rowhammer,
+                );
                 break;
             }
             "2" => {
-                opts.dimms = loop {
+                opts.dram.dimms = Some(loop {
                     print!("Number of RAM sticks on target machine: ");
                     stdout.flush().unwrap();
                     let input = read_line();
                     if input.trim().is_empty() {
-                        break opts.dimms;
+                        break opts.dram.dimms();
                     }
                     if let Ok(d) = input.trim().parse() {
                         break d;
                     } else {
                         eprintln!("Input must be an integer");
                     }
-                };
-                println!("Selected settings: -d {}", opts.dimms);
-                profiler::pagefinder::main(opts.dimms);
+                });
+                println!("Selected settings: -d {}", opts.dram.dimms());
+                profiler::pagefinder::main(
+                    &opts.dram.dram_mapping(),
+                    opts.backing,
+                    &opts.pattern,
+                    load_hammer_counts().rowhammer,
+                );
                 break;
             }
             _ => {
@@ -256,7 +286,10 @@ fn run_attack() {
 
     println!(
         "\t1. Run with default settings (-p {:.1} -d {} -t {} -n {})",
-        opts.fraction_of_phys_memory, opts.dimms, opts.testing, opts.number_of_dummy_pages
+        opts.fraction_of_phys_memory,
+        opts.dram.dimms(),
+        opts.testing,
+        opts.number_of_dummy_pages
     );
     println!("\t2. Run with custom settings\n");
 
@@ -269,9 +302,12 @@ fn run_attack() {
             "1" => {
                 attack::attack::main(
                     opts.fraction_of_phys_memory,
-                    opts.dimms,
+                    &opts.dram.dram_mapping(),
                     opts.testing,
                     opts.number_of_dummy_pages,
+                    opts.backing,
+                    &opts.pattern,
+                    load_hammer_counts().rowhammer,
                 );
                 break;
             }
@@ -297,19 +333,22 @@ fn run_attack() {
                     }
                 };
 
-                opts.dimms = loop {
-                    print!("Number of RAM sticks on target machine ({}): ", opts.dimms);
+                opts.dram.dimms = Some(loop {
+                    print!(
+                        "Number of RAM sticks on target machine ({}): ",
+                        opts.dram.dimms()
+                    );
                     stdout.flush().unwrap();
                     let input = read_line();
                     if input.trim().is_empty() {
-                        break opts.dimms;
+                        break opts.dram.dimms();
                     }
                     if let Ok(d) = input.trim().parse() {
                         break d;
                     } else {
                         eprintln!("Input must be an integer");
                     }
-                };
+                });
 
                 opts.testing = loop {
                     print!("Testing mode ({}): ", opts.testing);
@@ -344,9 +383,12 @@ fn run_attack() {
 
                 attack::attack::main(
                     opts.fraction_of_phys_memory,
-                    opts.dimms,
+                    &opts.dram.dram_mapping(),
                     opts.testing,
                     opts.number_of_dummy_pages,
+                    opts.backing,
+                    &opts.pattern,
+                    load_hammer_counts().rowhammer,
                 );
                 break;
             }